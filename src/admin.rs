@@ -1,11 +1,18 @@
-use crate::apikey::ApiKey;
-use crate::backend::MySqlBackend;
+use crate::apikey::{ApiKey, ApiKeyError};
+use crate::attachments;
+use crate::autograde::{AnswerKey, KeyMatch, ALL_MATCHES};
+use crate::backend::{MySqlBackend, Value};
 use crate::config::Config;
-use crate::questions::{LectureQuestion, LectureQuestionsContext};
+use crate::csrf::{CsrfForm, CsrfToken};
+use crate::deadline::{self, Deadline};
+use crate::qtype::{split_options, QuestionSpec, QuestionType, ALL_TYPES};
+use crate::questions::LectureQuestion;
+use crate::ratelimit::{RateLimiter, ThrottledClient};
+use crate::visibility::{LectureState, Visibility, ALL_VISIBILITIES};
+use chrono::naive::NaiveDateTime;
 use mysql::from_value;
-use rocket::form::Form;
-use rocket::http::Status;
-use rocket::outcome::IntoOutcome;
+use rocket::http::{RawStr, Status};
+use rocket::outcome::{IntoOutcome, Outcome};
 use rocket::request::{self, FromRequest, Request};
 use rocket::response::Redirect;
 use rocket::State;
@@ -17,7 +24,11 @@ pub(crate) struct Admin;
 
 #[derive(Debug)]
 pub(crate) enum AdminError {
-    Unauthorized,
+    /// The `ApiKey` guard failed (not logged in, or not permitted to make this request at all);
+    /// the failure keeps that guard's status. Only read through `Debug`, when Rocket logs it.
+    ApiKey(#[allow(dead_code)] ApiKeyError),
+    /// Logged in, but not an admin
+    Forbidden,
 }
 
 #[rocket::async_trait]
@@ -25,16 +36,24 @@ impl<'r> FromRequest<'r> for Admin {
     type Error = AdminError;
 
     async fn from_request(request: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
-        let apikey = request.guard::<ApiKey>().await.unwrap();
+        let apikey = match request.guard::<ApiKey>().await {
+            Outcome::Success(apikey) => apikey,
+            Outcome::Failure((status, e)) => {
+                return Outcome::Failure((status, AdminError::ApiKey(e)))
+            }
+            Outcome::Forward(()) => return Outcome::Forward(()),
+        };
         let cfg = request.guard::<&State<Config>>().await.unwrap();
 
-        let res = if cfg.admins.contains(&apikey.user) {
+        // an admin viewing the site as a student keeps (read-only) access to staff pages
+        let user = apikey.impersonator.as_ref().unwrap_or(&apikey.user);
+        let res = if cfg.admins.contains(user) {
             Some(Admin)
         } else {
             None
         };
 
-        res.into_outcome((Status::Unauthorized, AdminError::Unauthorized))
+        res.into_outcome((Status::Forbidden, AdminError::Forbidden))
     }
 }
 
@@ -42,36 +61,306 @@ impl<'r> FromRequest<'r> for Admin {
 pub(crate) struct AddLectureQuestionForm {
     q_id: u64,
     q_prompt: String,
+    q_type: String,
+    /// One option per line, for choice questions
+    q_options: String,
+    q_tolerance: Option<f64>,
+    q_language: String,
+    /// Expected answer; empty if the question is graded by hand
+    q_key: String,
+    /// How answers are compared to the key (see `KeyMatch`)
+    q_match: String,
+    q_points: Option<u64>,
+    /// Students can attach a file to their answer
+    q_attachments: bool,
+    /// Render the prompt and answers as Markdown
+    q_markdown: bool,
+}
+
+impl AddLectureQuestionForm {
+    fn spec(&self) -> Result<QuestionSpec, Status> {
+        let spec = QuestionSpec {
+            qtype: QuestionType::parse(&self.q_type).ok_or(Status::UnprocessableEntity)?,
+            options: split_options(&self.q_options),
+            tolerance: self.q_tolerance,
+            language: Some(self.q_language.trim().to_string()).filter(|l| !l.is_empty()),
+        };
+        spec.check().map_err(|_| Status::UnprocessableEntity)?;
+        Ok(spec)
+    }
+
+    fn answer_key(&self, spec: &QuestionSpec) -> Result<Option<AnswerKey>, Status> {
+        if self.q_key.trim().is_empty() || self.q_match.is_empty() {
+            return Ok(None);
+        }
+        let key = AnswerKey {
+            key: self.q_key.clone(),
+            matching: KeyMatch::parse(&self.q_match).ok_or(Status::UnprocessableEntity)?,
+            points: self.q_points.unwrap_or(1),
+        };
+        key.check(spec).map_err(|_| Status::UnprocessableEntity)?;
+        Ok(Some(key))
+    }
+}
+
+#[derive(Serialize)]
+struct QuestionTypeOption {
+    value: &'static str,
+    label: &'static str,
+    selected: bool,
+}
+
+fn qtype_options(selected: QuestionType) -> Vec<QuestionTypeOption> {
+    ALL_TYPES
+        .iter()
+        .map(|t| QuestionTypeOption {
+            value: t.as_str(),
+            label: t.label(),
+            selected: *t == selected,
+        })
+        .collect()
+}
+
+/// Choices for how answers are compared to the key; the empty value means no key.
+fn key_match_options(selected: Option<KeyMatch>) -> Vec<QuestionTypeOption> {
+    let mut opts = vec![QuestionTypeOption {
+        value: "",
+        label: "none (graded by hand)",
+        selected: selected.is_none(),
+    }];
+    opts.extend(ALL_MATCHES.iter().map(|m| QuestionTypeOption {
+        value: m.as_str(),
+        label: m.as_str(),
+        selected: Some(*m) == selected,
+    }));
+    opts
+}
+
+#[derive(Serialize)]
+struct AdminLectureContext {
+    lec_id: u64,
+    questions: Vec<LectureQuestion>,
+    qtypes: Vec<QuestionTypeOption>,
+    key_matches: Vec<QuestionTypeOption>,
+    answer_key: String,
+    points: u64,
+    /// Deadlines as `datetime-local` values
+    due_at: String,
+    lock_at: String,
+    timezone: &'static str,
+    visibilities: Vec<QuestionTypeOption>,
+    opens_at: String,
+    grades_released: bool,
+    csrf_token: String,
+    parent: &'static str,
+}
+
+#[derive(Serialize)]
+struct QuestionEditContext {
+    lec_id: u64,
+    question: LectureQuestion,
+    qtypes: Vec<QuestionTypeOption>,
+    /// Options as edited, one per line
+    options_text: String,
+    key_matches: Vec<QuestionTypeOption>,
+    answer_key: String,
+    points: u64,
+    csrf_token: String,
+    parent: &'static str,
 }
 
 #[derive(Debug, FromForm)]
 pub(crate) struct AdminLecAdd {
-    lec_id: u8,
+    lec_id: u64,
     lec_label: String,
+    due_at: String,
+    lock_at: String,
+    visibility: String,
+    opens_at: String,
+}
+
+#[derive(Debug, FromForm)]
+pub(crate) struct ReorderForm {
+    /// Question IDs in their new order, comma-separated
+    order: String,
+}
+
+#[derive(Debug, FromForm)]
+pub(crate) struct DeleteForm {
+    /// Keep the answers (for grades and exports) instead of deleting them
+    keep_answers: bool,
+}
+
+#[derive(Serialize)]
+struct DeleteConfirmContext {
+    what: String,
+    action: String,
+    back: String,
+    num_questions: Option<u64>,
+    num_answers: u64,
+    csrf_token: String,
+    parent: &'static str,
+}
+
+#[derive(Debug, FromForm)]
+pub(crate) struct ReleaseForm {
+    /// Show students their grades for the lecture
+    released: bool,
+}
+
+#[derive(Debug, FromForm)]
+pub(crate) struct VisibilityForm {
+    visibility: String,
+    /// When a scheduled lecture opens
+    opens_at: String,
+}
+
+/// Parses visibility inputs; scheduled lectures need an opening time.
+fn parse_visibility(
+    config: &Config,
+    visibility: &str,
+    opens_at: &str,
+) -> Result<LectureState, Status> {
+    let state = LectureState {
+        visibility: Visibility::parse(visibility).ok_or(Status::UnprocessableEntity)?,
+        opens_at: deadline::parse_input(config, opens_at).map_err(|_| Status::UnprocessableEntity)?,
+    };
+    if state.visibility == Visibility::Scheduled && state.opens_at.is_none() {
+        return Err(Status::UnprocessableEntity);
+    }
+    Ok(state)
+}
+
+fn visibility_options(selected: Visibility) -> Vec<QuestionTypeOption> {
+    ALL_VISIBILITIES
+        .iter()
+        .map(|v| QuestionTypeOption {
+            value: v.as_str(),
+            label: v.as_str(),
+            selected: *v == selected,
+        })
+        .collect()
+}
+
+#[derive(Debug, FromForm)]
+pub(crate) struct DeadlineForm {
+    /// Empty for no deadline
+    due_at: String,
+    /// Empty to accept submissions indefinitely
+    lock_at: String,
+}
+
+/// Parses deadline inputs; a lock before the due date makes no sense.
+fn parse_deadline(config: &Config, due_at: &str, lock_at: &str) -> Result<Deadline, Status> {
+    let invalid = |_| Status::UnprocessableEntity;
+    let d = Deadline {
+        due_at: deadline::parse_input(config, due_at).map_err(invalid)?,
+        lock_at: deadline::parse_input(config, lock_at).map_err(invalid)?,
+        extended: false,
+    };
+    if let (Some(due), Some(lock)) = (d.due_at, d.lock_at) {
+        if lock < due {
+            return Err(Status::UnprocessableEntity);
+        }
+    }
+    Ok(d)
+}
+
+/// Users shown per page of the admin user list.
+const USERS_PER_PAGE: usize = 50;
+
+/// Sortable columns of the user list: (name in URLs, heading, column in `users`). Roles come
+/// from the config rather than the database, so they have no column and are sorted in memory.
+const USER_SORT_COLUMNS: &[(&str, &str, Option<&str>)] = &[
+    ("email", "Email", Some("email")),
+    ("role", "Role", None),
+    ("pseudonym", "Pseudonym", Some("pseudonym")),
+    ("gender", "Gender", Some("gender")),
+    ("age", "Age", Some("age")),
+    ("ethnicity", "Ethnicity", Some("ethnicity")),
+    ("remote", "Remote?", Some("is_remote")),
+    ("education", "Education", Some("education")),
+];
+
+#[derive(Debug, FromForm)]
+pub(crate) struct UserQuery {
+    /// Substring of email or pseudonym
+    q: Option<String>,
+    /// "admin", "manager" or "student"
+    role: Option<String>,
+    remote: Option<bool>,
+    sort: Option<String>,
+    desc: Option<bool>,
+    page: Option<usize>,
 }
 
 #[derive(Debug, Serialize)]
 pub(crate) struct User {
     email: String,
-    apikey: String,
     pseudonym: String,
     gender: String,
-    age: u32,
+    age: Option<u32>,
     ethnicity: String,
     is_remote: u8,
     education: String,
     is_admin: u8,
+    is_manager: u8,
+}
+
+#[derive(Serialize)]
+struct SortLink {
+    label: &'static str,
+    url: String,
+    active: bool,
+    desc: bool,
 }
 
 #[derive(Serialize)]
 struct UserContext {
     users: Vec<User>,
+    columns: Vec<SortLink>,
+    q: String,
+    role: String,
+    remote: Option<bool>,
+    total: usize,
+    page: usize,
+    num_pages: usize,
+    prev_url: Option<String>,
+    next_url: Option<String>,
+    csrf_token: String,
+    parent: &'static str,
+}
+
+#[derive(Serialize)]
+struct UserAnswer {
+    lec_id: u64,
+    lec_label: String,
+    q_id: u64,
+    time: String,
+    grade: u64,
+}
+
+#[derive(Serialize)]
+struct UserDetailContext {
+    user: User,
+    roster_name: Option<String>,
+    roster_section: Option<String>,
+    answers: Vec<UserAnswer>,
+    csrf_token: String,
+    parent: &'static str,
+}
+
+#[derive(Serialize)]
+struct ThrottledContext {
+    clients: Vec<ThrottledClient>,
     parent: &'static str,
 }
 
 #[get("/")]
-pub(crate) fn lec_add(_adm: Admin) -> Template {
+pub(crate) fn lec_add(_adm: Admin, csrf: CsrfToken, config: &State<Config>) -> Template {
     let mut ctx = HashMap::new();
+    ctx.insert("timezone", deadline::zone_name(config).to_string());
+    ctx.insert("csrf_token", csrf.0);
     ctx.insert("parent", String::from("layout"));
     Template::render("admin/lecadd", &ctx)
 }
@@ -79,152 +368,801 @@ pub(crate) fn lec_add(_adm: Admin) -> Template {
 #[post("/", data = "<data>")]
 pub(crate) fn lec_add_submit(
     _adm: Admin,
-    data: Form<AdminLecAdd>,
+    data: CsrfForm<AdminLecAdd>,
     backend: &State<Arc<Mutex<MySqlBackend>>>,
-) -> Redirect {
+    config: &State<Config>,
+) -> Result<Redirect, Status> {
+    let d = parse_deadline(config, &data.due_at, &data.lock_at)?;
+    let state = parse_visibility(config, &data.visibility, &data.opens_at)?;
     // insert into MySql if not exists
     let mut bg = backend.lock().unwrap();
+    if lecture_id_taken(&mut bg, data.lec_id) {
+        return Err(Status::UnprocessableEntity);
+    }
     bg.insert(
         "lectures",
         vec![
-            (data.lec_id as u64).into(),
+            data.lec_id.into(),
             data.lec_label.to_string().into(),
+            d.due_at.into(),
+            d.lock_at.into(),
+            state.visibility.as_str().into(),
+            state.opens_at.into(),
+            false.into(),
         ],
     );
     drop(bg);
 
-    Redirect::to("/leclist")
+    Ok(Redirect::to("/leclist"))
 }
 
 #[get("/<num>")]
-pub(crate) fn lec(_adm: Admin, num: u8, backend: &State<Arc<Mutex<MySqlBackend>>>) -> Template {
+pub(crate) fn lec(
+    _adm: Admin,
+    num: u64,
+    csrf: CsrfToken,
+    backend: &State<Arc<Mutex<MySqlBackend>>>,
+    config: &State<Config>,
+) -> Template {
     let mut bg = backend.lock().unwrap();
     let res = bg.prep_exec(
-        "SELECT * FROM questions WHERE lec = ?",
-        vec![(num as u64).into()],
+        "SELECT * FROM questions WHERE lec = ? ORDER BY position, q",
+        vec![num.into()],
+    );
+    let d = Deadline::load(&mut bg, num);
+    let state = LectureState::load(&mut bg, num).unwrap_or(LectureState {
+        visibility: Visibility::Open,
+        opens_at: None,
+    });
+    let released = bg.prep_exec(
+        "SELECT grades_released FROM lectures WHERE id = ?",
+        vec![num.into()],
     );
     drop(bg);
-    let mut qs: Vec<_> = res
+    let qs: Vec<_> = res
         .into_iter()
         .map(|r| {
             let id: u64 = from_value(r[1].clone());
-            LectureQuestion {
-                id: id,
-                prompt: from_value(r[2].clone()),
-                answer: None,
-            }
+            let spec = QuestionSpec::from_row(&r);
+            let mut q = LectureQuestion::new(id, from_value(r[2].clone()), &spec, None);
+            q.read_settings(&r);
+            q
         })
         .collect();
-    qs.sort_by(|a, b| a.id.cmp(&b.id));
 
-    let ctx = LectureQuestionsContext {
+    let ctx = AdminLectureContext {
         lec_id: num,
         questions: qs,
+        qtypes: qtype_options(QuestionType::Text),
+        key_matches: key_match_options(None),
+        answer_key: String::new(),
+        points: 1,
+        due_at: deadline::to_input(config, d.due_at),
+        lock_at: deadline::to_input(config, d.lock_at),
+        timezone: deadline::zone_name(config),
+        visibilities: visibility_options(state.visibility),
+        opens_at: deadline::to_input(config, state.opens_at),
+        grades_released: released
+            .first()
+            .and_then(|r| from_value::<Option<bool>>(r[0].clone()))
+            .unwrap_or(false),
+        csrf_token: csrf.0,
         parent: "layout",
     };
     Template::render("admin/lec", &ctx)
 }
 
-#[post("/<num>", data = "<data>")]
-pub(crate) fn addq(
+#[post("/deadline/<num>", data = "<data>")]
+pub(crate) fn set_deadline(
     _adm: Admin,
-    num: u8,
-    data: Form<AddLectureQuestionForm>,
+    num: u64,
+    data: CsrfForm<DeadlineForm>,
     backend: &State<Arc<Mutex<MySqlBackend>>>,
-) -> Redirect {
+    config: &State<Config>,
+) -> Result<Redirect, Status> {
+    let d = parse_deadline(config, &data.due_at, &data.lock_at)?;
     let mut bg = backend.lock().unwrap();
-    bg.insert(
-        "questions",
+    bg.prep_exec(
+        "UPDATE lectures SET due_at = ?, lock_at = ? WHERE id = ?",
+        vec![d.due_at.into(), d.lock_at.into(), num.into()],
+    );
+    drop(bg);
+
+    Ok(Redirect::to(format!("/admin/lec/{}", num)))
+}
+
+#[post("/visibility/<num>", data = "<data>")]
+pub(crate) fn set_visibility(
+    _adm: Admin,
+    num: u64,
+    data: CsrfForm<VisibilityForm>,
+    backend: &State<Arc<Mutex<MySqlBackend>>>,
+    config: &State<Config>,
+) -> Result<Redirect, Status> {
+    let state = parse_visibility(config, &data.visibility, &data.opens_at)?;
+    let mut bg = backend.lock().unwrap();
+    bg.prep_exec(
+        "UPDATE lectures SET visibility = ?, opens_at = ? WHERE id = ?",
         vec![
-            (num as u64).into(),
-            (data.q_id as u64).into(),
-            data.q_prompt.to_string().into(),
+            state.visibility.as_str().into(),
+            state.opens_at.into(),
+            num.into(),
         ],
     );
     drop(bg);
 
+    Ok(Redirect::to(format!("/admin/lec/{}", num)))
+}
+
+#[post("/release/<num>", data = "<data>")]
+pub(crate) fn set_release(
+    _adm: Admin,
+    num: u64,
+    data: CsrfForm<ReleaseForm>,
+    backend: &State<Arc<Mutex<MySqlBackend>>>,
+) -> Redirect {
+    let mut bg = backend.lock().unwrap();
+    bg.prep_exec(
+        "UPDATE lectures SET grades_released = ? WHERE id = ?",
+        vec![data.released.into(), num.into()],
+    );
+    drop(bg);
+
     Redirect::to(format!("/admin/lec/{}", num))
 }
 
+#[post("/<num>", data = "<data>")]
+pub(crate) fn addq(
+    _adm: Admin,
+    num: u64,
+    data: CsrfForm<AddLectureQuestionForm>,
+    backend: &State<Arc<Mutex<MySqlBackend>>>,
+) -> Result<Redirect, Status> {
+    let spec = data.spec()?;
+    let key = data.answer_key(&spec)?;
+    let mut bg = backend.lock().unwrap();
+    if question_id_taken(&mut bg, num, data.q_id) {
+        return Err(Status::UnprocessableEntity);
+    }
+    // new questions go last
+    let position = count(
+        &mut bg,
+        "SELECT COALESCE(MAX(position), 0) + 1 FROM questions WHERE lec = ?",
+        vec![num.into()],
+    );
+    let mut row: Vec<Value> = vec![
+        num.into(),
+        data.q_id.into(),
+        data.q_prompt.to_string().into(),
+    ];
+    row.extend(spec.to_values());
+    row.extend(AnswerKey::to_values(&key));
+    row.push(position.into());
+    row.push(data.q_attachments.into());
+    row.push(data.q_markdown.into());
+    bg.insert("questions", row);
+    drop(bg);
+
+    Ok(Redirect::to(format!("/admin/lec/{}", num)))
+}
+
 #[get("/<num>/<qnum>")]
 pub(crate) fn editq(
     _adm: Admin,
-    num: u8,
-    qnum: u8,
+    num: u64,
+    qnum: u64,
+    csrf: CsrfToken,
     backend: &State<Arc<Mutex<MySqlBackend>>>,
-) -> Template {
+) -> Result<Template, Status> {
     let mut bg = backend.lock().unwrap();
     let res = bg.prep_exec(
-        "SELECT * FROM questions WHERE lec = ?",
-        vec![(num as u64).into()],
+        "SELECT * FROM questions WHERE lec = ? AND q = ?",
+        vec![num.into(), qnum.into()],
     );
     drop(bg);
 
-    let mut ctx = HashMap::new();
-    for r in res {
-        if r[1] == (qnum as u64).into() {
-            ctx.insert("lec_qprompt", from_value(r[2].clone()));
-        }
-    }
-    ctx.insert("lec_id", format!("{}", num));
-    ctx.insert("lec_qnum", format!("{}", qnum));
-    ctx.insert("parent", String::from("layout"));
-    Template::render("admin/lecedit", &ctx)
+    let r = res.first().ok_or(Status::NotFound)?;
+    let spec = QuestionSpec::from_row(r);
+    let key = AnswerKey::from_row(r);
+    let mut question = LectureQuestion::new(qnum, from_value(r[2].clone()), &spec, None);
+    question.read_settings(r);
+    let ctx = QuestionEditContext {
+        lec_id: num,
+        question,
+        qtypes: qtype_options(spec.qtype),
+        options_text: spec.options.join("\n"),
+        key_matches: key_match_options(key.as_ref().map(|k| k.matching)),
+        answer_key: key.as_ref().map(|k| k.key.clone()).unwrap_or_default(),
+        points: key.as_ref().map(|k| k.points).unwrap_or(1),
+        csrf_token: csrf.0,
+        parent: "layout",
+    };
+    Ok(Template::render("admin/lecedit", &ctx))
 }
 
 #[post("/editq/<num>", data = "<data>")]
 pub(crate) fn editq_submit(
     _adm: Admin,
-    num: u8,
-    data: Form<AddLectureQuestionForm>,
+    num: u64,
+    data: CsrfForm<AddLectureQuestionForm>,
     backend: &State<Arc<Mutex<MySqlBackend>>>,
-) -> Redirect {
+) -> Result<Redirect, Status> {
+    let spec = data.spec()?;
+    let key = data.answer_key(&spec)?;
+    let mut params: Vec<Value> = vec![data.q_prompt.to_string().into()];
+    params.extend(spec.to_values());
+    params.extend(AnswerKey::to_values(&key));
+    params.push(data.q_attachments.into());
+    params.push(data.q_markdown.into());
+    params.push(num.into());
+    params.push(data.q_id.into());
+
     let mut bg = backend.lock().unwrap();
     bg.prep_exec(
-        "UPDATE questions SET question = ? WHERE lec = ? AND q = ?",
-        vec![
-            data.q_prompt.to_string().into(),
-            (num as u64).into(),
-            (data.q_id as u64).into(),
-        ],
+        "UPDATE questions SET question = ?, qtype = ?, options = ?, tolerance = ?, language = ?, \
+         answer_key = ?, key_match = ?, points = ?, attachments = ?, markdown = ? \
+         WHERE lec = ? AND q = ?",
+        params,
     );
     drop(bg);
 
+    Ok(Redirect::to(format!("/admin/lec/{}", num)))
+}
+
+#[post("/reorder/<num>", data = "<data>")]
+pub(crate) fn reorder(
+    _adm: Admin,
+    num: u64,
+    data: CsrfForm<ReorderForm>,
+    backend: &State<Arc<Mutex<MySqlBackend>>>,
+) -> Result<Redirect, Status> {
+    let mut bg = backend.lock().unwrap();
+    let ids: Vec<u64> = bg
+        .prep_exec("SELECT q FROM questions WHERE lec = ?", vec![num.into()])
+        .into_iter()
+        .map(|r| from_value(r[0].clone()))
+        .collect();
+    let order = parse_order(&data.order, &ids)?;
+    // only positions change; question IDs, which answers refer to, stay the same
+    for (i, q) in order.into_iter().enumerate() {
+        bg.prep_exec(
+            "UPDATE questions SET position = ? WHERE lec = ? AND q = ?",
+            vec![(i as u64 + 1).into(), num.into(), q.into()],
+        );
+    }
+    drop(bg);
+
+    Ok(Redirect::to(format!("/admin/lec/{}", num)))
+}
+
+/// Parses a submitted question order, which must list each of the lecture's questions (`ids`)
+/// exactly once, so that no two end up in the same position. An empty order changes nothing.
+fn parse_order(order: &str, ids: &[u64]) -> Result<Vec<u64>, Status> {
+    if order.trim().is_empty() {
+        return Ok(vec![]);
+    }
+    let order = order
+        .split(',')
+        .map(|q| q.trim().parse::<u64>())
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|_| Status::UnprocessableEntity)?;
+    let (mut sorted, mut expected) = (order.clone(), ids.to_vec());
+    sorted.sort_unstable();
+    expected.sort_unstable();
+    if sorted != expected {
+        // e.g., from a page that was stale after questions were added or deleted
+        return Err(Status::UnprocessableEntity);
+    }
+    Ok(order)
+}
+
+fn count(bg: &mut MySqlBackend, sql: &str, params: Vec<Value>) -> u64 {
+    let res = bg.prep_exec(sql, params);
+    from_value(res[0][0].clone())
+}
+
+/// Whether a new lecture cannot have this ID: as for questions, the ID of a deleted lecture whose
+/// answers were kept cannot be reused.
+fn lecture_id_taken(bg: &mut MySqlBackend, lec: u64) -> bool {
+    let params: Vec<Value> = vec![lec.into()];
+    count(bg, "SELECT COUNT(*) FROM lectures WHERE id = ?", params.clone())
+        + count(bg, "SELECT COUNT(*) FROM answer_versions WHERE lec = ?", params)
+        > 0
+}
+
+/// Whether a new question cannot have this ID: answers kept when a question is deleted still
+/// refer to its ID, so a new question must not take it over (or the ID of an existing one).
+fn question_id_taken(bg: &mut MySqlBackend, lec: u64, q: u64) -> bool {
+    let params: Vec<Value> = vec![lec.into(), q.into()];
+    count(
+        bg,
+        "SELECT COUNT(*) FROM questions WHERE lec = ? AND q = ?",
+        params.clone(),
+    ) + count(
+        bg,
+        "SELECT COUNT(*) FROM answer_versions WHERE lec = ? AND q = ?",
+        params,
+    ) > 0
+}
+
+/// Removes answers (with all their versions and attachments) matching `filter`, e.g.
+/// "lec = ? AND q = ?". Drafts are always removed, since they can no longer be submitted.
+fn delete_answers(
+    bg: &mut MySqlBackend,
+    config: &Config,
+    filter: &str,
+    params: Vec<Value>,
+    keep_answers: bool,
+) {
+    let mut tables = vec!["drafts"];
+    if !keep_answers {
+        tables.extend(["answers", "answer_versions"]);
+        attachments::delete_where(bg, config, filter, params.clone());
+    }
+    for t in tables {
+        bg.prep_exec(&format!("DELETE FROM {} WHERE {}", t, filter), params.clone());
+    }
+}
+
+#[get("/<num>/<qnum>/delete")]
+pub(crate) fn delete_question_confirm(
+    _adm: Admin,
+    num: u64,
+    qnum: u64,
+    csrf: CsrfToken,
+    backend: &State<Arc<Mutex<MySqlBackend>>>,
+) -> Result<Template, Status> {
+    let mut bg = backend.lock().unwrap();
+    let params: Vec<Value> = vec![num.into(), qnum.into()];
+    let sql = "SELECT COUNT(*) FROM questions WHERE lec = ? AND q = ?";
+    if count(&mut bg, sql, params.clone()) == 0 {
+        return Err(Status::NotFound);
+    }
+    let num_answers = count(
+        &mut bg,
+        "SELECT COUNT(*) FROM answers WHERE lec = ? AND q = ?",
+        params,
+    );
+    drop(bg);
+
+    let ctx = DeleteConfirmContext {
+        what: format!("question {} of lecture {}", qnum, num),
+        action: format!("/admin/lec/{}/{}/delete", num, qnum),
+        back: format!("/admin/lec/{}", num),
+        num_questions: None,
+        num_answers,
+        csrf_token: csrf.0,
+        parent: "layout",
+    };
+    Ok(Template::render("admin/delete", &ctx))
+}
+
+#[post("/<num>/<qnum>/delete", data = "<data>")]
+pub(crate) fn delete_question(
+    _adm: Admin,
+    num: u64,
+    qnum: u64,
+    data: CsrfForm<DeleteForm>,
+    backend: &State<Arc<Mutex<MySqlBackend>>>,
+    config: &State<Config>,
+) -> Redirect {
+    let mut bg = backend.lock().unwrap();
+    let params: Vec<Value> = vec![num.into(), qnum.into()];
+    delete_answers(&mut bg, config, "lec = ? AND q = ?", params.clone(), data.keep_answers);
+    bg.prep_exec("DELETE FROM questions WHERE lec = ? AND q = ?", params);
+    drop(bg);
+
     Redirect::to(format!("/admin/lec/{}", num))
 }
 
-#[get("/")]
+#[get("/<num>/delete")]
+pub(crate) fn delete_lecture_confirm(
+    _adm: Admin,
+    num: u64,
+    csrf: CsrfToken,
+    backend: &State<Arc<Mutex<MySqlBackend>>>,
+) -> Result<Template, Status> {
+    let mut bg = backend.lock().unwrap();
+    let res = bg.prep_exec("SELECT label FROM lectures WHERE id = ?", vec![num.into()]);
+    let label: String = from_value(res.first().ok_or(Status::NotFound)?[0].clone());
+    let num_questions = count(
+        &mut bg,
+        "SELECT COUNT(*) FROM questions WHERE lec = ?",
+        vec![num.into()],
+    );
+    let num_answers = count(
+        &mut bg,
+        "SELECT COUNT(*) FROM answers WHERE lec = ?",
+        vec![num.into()],
+    );
+    drop(bg);
+
+    let ctx = DeleteConfirmContext {
+        what: format!("lecture {} ({})", num, label),
+        action: format!("/admin/lec/{}/delete", num),
+        back: format!("/admin/lec/{}", num),
+        num_questions: Some(num_questions),
+        num_answers,
+        csrf_token: csrf.0,
+        parent: "layout",
+    };
+    Ok(Template::render("admin/delete", &ctx))
+}
+
+#[post("/<num>/delete", data = "<data>")]
+pub(crate) fn delete_lecture(
+    _adm: Admin,
+    num: u64,
+    data: CsrfForm<DeleteForm>,
+    backend: &State<Arc<Mutex<MySqlBackend>>>,
+    config: &State<Config>,
+) -> Redirect {
+    let mut bg = backend.lock().unwrap();
+    delete_answers(&mut bg, config, "lec = ?", vec![num.into()], data.keep_answers);
+    bg.prep_exec("DELETE FROM extensions WHERE lec = ?", vec![num.into()]);
+    bg.prep_exec("DELETE FROM questions WHERE lec = ?", vec![num.into()]);
+    bg.prep_exec("DELETE FROM lectures WHERE id = ?", vec![num.into()]);
+    drop(bg);
+
+    Redirect::to("/leclist")
+}
+
+const USER_COLUMNS: &str = "email, pseudonym, gender, age, ethnicity, is_remote, education";
+
+fn user_from_row(r: &[Value], config: &Config) -> User {
+    let email: String = from_value(r[0].clone());
+    User {
+        // roles are shown as configured, which is what the guards check
+        is_admin: if config.admins.contains(&email) { 1 } else { 0 },
+        is_manager: if config.managers.contains(&email) { 1 } else { 0 },
+        email,
+        pseudonym: from_value(r[1].clone()),
+        gender: from_value(r[2].clone()),
+        age: from_value(r[3].clone()),
+        ethnicity: from_value(r[4].clone()),
+        is_remote: from_value(r[5].clone()),
+        education: from_value(r[6].clone()),
+    }
+}
+
+/// SQL condition on users whose email is (or, if `negate`, is not) one of `emails`.
+fn email_in(emails: &[String], negate: bool, params: &mut Vec<Value>) -> String {
+    if emails.is_empty() {
+        return String::from(if negate { "TRUE" } else { "FALSE" });
+    }
+    params.extend(emails.iter().map(|e| e.as_str().into()));
+    format!(
+        "email {}IN ({})",
+        if negate { "NOT " } else { "" },
+        vec!["?"; emails.len()].join(", ")
+    )
+}
+
+/// Orders users in memory, for columns that are encrypted at rest.
+fn sort_users(users: &mut [User], sort: &str) {
+    match sort {
+        "gender" => users.sort_by(|a, b| a.gender.cmp(&b.gender)),
+        "ethnicity" => users.sort_by(|a, b| a.ethnicity.cmp(&b.ethnicity)),
+        "education" => users.sort_by(|a, b| a.education.cmp(&b.education)),
+        "age" => users.sort_by_key(|u| u.age),
+        "pseudonym" => users.sort_by(|a, b| a.pseudonym.cmp(&b.pseudonym)),
+        "role" => users.sort_by_key(|u| (u.is_admin, u.is_manager)),
+        "remote" => users.sort_by_key(|u| u.is_remote),
+        _ => users.sort_by(|a, b| a.email.cmp(&b.email)),
+    }
+}
+
+fn user_list_url(
+    q: &str,
+    role: &str,
+    remote: Option<bool>,
+    sort: &str,
+    desc: bool,
+    page: usize,
+) -> String {
+    let mut url = format!(
+        "/admin/users?q={}&role={}&sort={}&desc={}&page={}",
+        RawStr::new(q).percent_encode(),
+        RawStr::new(role).percent_encode(),
+        sort,
+        desc,
+        page
+    );
+    if let Some(r) = remote {
+        url.push_str(&format!("&remote={}", r));
+    }
+    url
+}
+
+#[get("/?<query..>")]
 pub(crate) fn get_registered_users(
     _adm: Admin,
+    query: UserQuery,
+    csrf: CsrfToken,
     backend: &State<Arc<Mutex<MySqlBackend>>>,
     config: &State<Config>,
 ) -> Template {
+    let q = query.q.clone().unwrap_or_default();
+    let role = query.role.clone().unwrap_or_default();
+    let desc = query.desc.unwrap_or(false);
+    let (sort, _, sort_column) = *USER_SORT_COLUMNS
+        .iter()
+        .find(|(name, _, _)| Some(*name) == query.sort.as_deref())
+        .unwrap_or(&USER_SORT_COLUMNS[0]);
+
+    let mut conds: Vec<String> = vec![];
+    let mut params: Vec<Value> = vec![];
+    if !q.trim().is_empty() {
+        let pattern = format!(
+            "%{}%",
+            q.trim()
+                .replace('\\', "\\\\")
+                .replace('%', "\\%")
+                .replace('_', "\\_")
+        );
+        conds.push("(email LIKE ? OR pseudonym LIKE ?)".into());
+        params.push(pattern.as_str().into());
+        params.push(pattern.into());
+    }
+    // the same roles as shown in the list: admin before manager, student if neither
+    match role.as_str() {
+        "admin" => conds.push(email_in(&config.admins, false, &mut params)),
+        "manager" => {
+            conds.push(email_in(&config.managers, false, &mut params));
+            conds.push(email_in(&config.admins, true, &mut params));
+        }
+        "student" => {
+            conds.push(email_in(&config.admins, true, &mut params));
+            conds.push(email_in(&config.managers, true, &mut params));
+        }
+        _ => (),
+    }
+    if let Some(remote) = query.remote {
+        conds.push("is_remote = ?".into());
+        params.push(remote.into());
+    }
+    let filter = if conds.is_empty() {
+        String::new()
+    } else {
+        format!(" WHERE {}", conds.join(" AND "))
+    };
+
     let mut bg = backend.lock().unwrap();
-    let res = bg.prep_exec("SELECT email, is_admin, apikey, pseudonym, gender, age, ethnicity, is_remote, education FROM users", vec![]);
+    let res = bg.prep_exec(
+        &format!("SELECT COUNT(*) FROM users{}", filter),
+        params.clone(),
+    );
+    let total = from_value::<u64>(res[0][0].clone()) as usize;
+    let num_pages = std::cmp::max(1, total.div_ceil(USERS_PER_PAGE));
+    let page = query.page.unwrap_or(1).max(1).min(num_pages);
+    let offset = (page - 1) * USERS_PER_PAGE;
+
+    let sql_column = sort_column.filter(|c| !bg.is_encrypted("users", c));
+    let users: Vec<User> = if let Some(sort_column) = sql_column {
+        params.push((USERS_PER_PAGE as u64).into());
+        params.push((offset as u64).into());
+        let res = bg.prep_exec(
+            &format!(
+                "SELECT {} FROM users{} ORDER BY {} {}, email LIMIT ? OFFSET ?",
+                USER_COLUMNS,
+                filter,
+                sort_column,
+                if desc { "DESC" } else { "ASC" }
+            ),
+            params,
+        );
+        res.iter().map(|r| user_from_row(r, config)).collect()
+    } else {
+        // ciphertexts do not sort like their plaintexts (and roles are not stored), so sort
+        // after decryption; ordering by email first keeps ties in a stable order
+        let res = bg.prep_exec(
+            &format!("SELECT {} FROM users{} ORDER BY email", USER_COLUMNS, filter),
+            params,
+        );
+        let mut users: Vec<User> = res.iter().map(|r| user_from_row(r, config)).collect();
+        sort_users(&mut users, sort);
+        if desc {
+            users.reverse();
+        }
+        users.into_iter().skip(offset).take(USERS_PER_PAGE).collect()
+    };
     drop(bg);
 
-    let users: Vec<_> = res
-        .into_iter()
-        .map(|r| User {
-            email: from_value(r[0].clone()),
-            apikey: from_value(r[2].clone()),
-            is_admin: if config.admins.contains(&from_value(r[0].clone())) {
-                1
-            } else {
-                0
-            }, // r[1].clone().into(), this type conversion does not work
-            pseudonym: from_value(r[3].clone()),
-            gender: from_value(r[4].clone()),
-            age: from_value(r[5].clone()),
-            ethnicity: from_value(r[6].clone()),
-            is_remote: from_value(r[7].clone()),
-            education: from_value(r[8].clone()),
+    let columns = USER_SORT_COLUMNS
+        .iter()
+        .map(|(name, label, _)| SortLink {
+            label,
+            // clicking the active column again reverses the order
+            url: user_list_url(&q, &role, query.remote, name, *name == sort && !desc, 1),
+            active: *name == sort,
+            desc: *name == sort && desc,
         })
         .collect();
 
     let ctx = UserContext {
         users: users,
+        columns,
+        prev_url: if page > 1 {
+            Some(user_list_url(&q, &role, query.remote, sort, desc, page - 1))
+        } else {
+            None
+        },
+        next_url: if page < num_pages {
+            Some(user_list_url(&q, &role, query.remote, sort, desc, page + 1))
+        } else {
+            None
+        },
+        q,
+        role,
+        remote: query.remote,
+        total,
+        page,
+        num_pages,
+        csrf_token: csrf.0,
         parent: "layout",
     };
     Template::render("admin/users", &ctx)
 }
+
+#[get("/<email>")]
+pub(crate) fn user_detail(
+    _adm: Admin,
+    email: String,
+    csrf: CsrfToken,
+    backend: &State<Arc<Mutex<MySqlBackend>>>,
+    config: &State<Config>,
+) -> Result<Template, Status> {
+    let mut bg = backend.lock().unwrap();
+    let res = bg.prep_exec(
+        &format!("SELECT {} FROM users WHERE email = ?", USER_COLUMNS),
+        vec![email.as_str().into()],
+    );
+    let user = user_from_row(res.first().ok_or(Status::NotFound)?, config);
+    let roster = bg.prep_exec(
+        "SELECT name, section FROM enrollment WHERE LOWER(email) = ?",
+        vec![email.to_lowercase().into()],
+    );
+    let answers = bg
+        .prep_exec(
+            "SELECT answers.lec, lectures.label, answers.q, answers.submitted_at, answers.grade \
+             FROM answers JOIN lectures ON answers.lec = lectures.id \
+             WHERE answers.email = ? ORDER BY answers.lec, answers.q",
+            vec![email.as_str().into()],
+        )
+        .into_iter()
+        .map(|r| UserAnswer {
+            lec_id: from_value(r[0].clone()),
+            lec_label: from_value(r[1].clone()),
+            q_id: from_value(r[2].clone()),
+            time: from_value::<NaiveDateTime>(r[3].clone())
+                .format("%Y-%m-%d %H:%M:%S")
+                .to_string(),
+            grade: from_value(r[4].clone()),
+        })
+        .collect();
+    drop(bg);
+
+    let ctx = UserDetailContext {
+        user,
+        roster_name: roster.first().map(|r| from_value(r[0].clone())),
+        roster_section: roster.first().map(|r| from_value(r[1].clone())),
+        answers,
+        csrf_token: csrf.0,
+        parent: "layout",
+    };
+    Ok(Template::render("admin/user", &ctx))
+}
+
+#[get("/")]
+pub(crate) fn throttled(_adm: Admin, limiter: &State<Arc<RateLimiter>>) -> Template {
+    let ctx = ThrottledContext {
+        clients: limiter.throttled(),
+        parent: "layout",
+    };
+    Template::render("admin/throttled", &ctx)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config;
+    use chrono::Local;
+
+    #[test]
+    fn order_must_list_every_question_once() {
+        assert_eq!(parse_order("3, 1,2", &[1, 2, 3]), Ok(vec![3, 1, 2]));
+        // nothing to reorder
+        assert_eq!(parse_order("", &[]), Ok(vec![]));
+        assert_eq!(parse_order(" ", &[1, 2]), Ok(vec![]));
+        for bad in &["1,2", "1,2,3,4", "1,1,2", "1,2,x", "1,,2,3"] {
+            assert_eq!(
+                parse_order(bad, &[1, 2, 3]),
+                Err(Status::UnprocessableEntity),
+                "{}",
+                bad
+            );
+        }
+    }
+
+    fn rows(bg: &mut MySqlBackend, table: &str) -> u64 {
+        count(
+            bg,
+            &format!("SELECT COUNT(*) FROM {} WHERE lec = 1 AND q = 1", table),
+            vec![],
+        )
+    }
+
+    #[test]
+    #[ignore = "needs a MySQL server"]
+    fn deleted_questions_keep_or_remove_answers() {
+        let config = config::parse("sample-config.toml").unwrap();
+        let mut bg = MySqlBackend::new(
+            &config.db_user,
+            &config.db_password,
+            "websubmit_test_admin",
+            None,
+            true,
+            None,
+        )
+        .unwrap();
+        let now = Local::now().naive_local();
+        let answer = |bg: &mut MySqlBackend| {
+            bg.replace(
+                "answers",
+                vec![
+                    "student@brown.edu".into(),
+                    1.into(),
+                    1.into(),
+                    "42".into(),
+                    now.into(),
+                    0.into(),
+                    false.into(),
+                    1.into(),
+                    false.into(),
+                    Value::NULL,
+                ],
+            );
+            bg.replace(
+                "answer_versions",
+                vec![
+                    "student@brown.edu".into(),
+                    1.into(),
+                    1.into(),
+                    1.into(),
+                    "42".into(),
+                    now.into(),
+                ],
+            );
+            bg.replace(
+                "drafts",
+                vec![
+                    "student@brown.edu".into(),
+                    1.into(),
+                    1.into(),
+                    "43".into(),
+                    now.into(),
+                ],
+            );
+        };
+        let params = || -> Vec<Value> { vec![1.into(), 1.into()] };
+
+        answer(&mut bg);
+        delete_answers(&mut bg, &config, "lec = ? AND q = ?", params(), true);
+        assert_eq!(rows(&mut bg, "answers"), 1);
+        assert_eq!(rows(&mut bg, "answer_versions"), 1);
+        assert_eq!(rows(&mut bg, "drafts"), 0);
+        // the kept answers still claim the IDs
+        assert!(question_id_taken(&mut bg, 1, 1));
+        assert!(lecture_id_taken(&mut bg, 1));
+        assert!(!question_id_taken(&mut bg, 1, 2));
+        assert!(!lecture_id_taken(&mut bg, 2));
+
+        answer(&mut bg);
+        delete_answers(&mut bg, &config, "lec = ? AND q = ?", params(), false);
+        assert_eq!(rows(&mut bg, "answers"), 0);
+        assert_eq!(rows(&mut bg, "answer_versions"), 0);
+        assert_eq!(rows(&mut bg, "drafts"), 0);
+        assert!(!question_id_taken(&mut bg, 1, 1));
+        assert!(!lecture_id_taken(&mut bg, 1));
+    }
+}
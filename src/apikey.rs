@@ -1,27 +1,36 @@
 use crate::backend::MySqlBackend;
 use crate::config::Config;
+use crate::csrf::{CsrfForm, CsrfToken};
 use crate::email;
+use crate::impersonate;
+use crate::login::{render_login, safe_next};
+use crate::profile::{self, Demographics};
+use crate::pseudonym;
+use crate::roster;
+use crate::ratelimit::RateLimiter;
+use crate::tokens;
 use crypto::digest::Digest;
 use crypto::sha2::Sha256;
 use mysql::from_value;
-use rocket::form::Form;
-use rocket::http::Status;
+use rocket::http::{Method, Status};
 use rocket::http::{Cookie, CookieJar};
-use rocket::outcome::IntoOutcome;
+use rocket::outcome::Outcome;
 use rocket::request::{self, FromRequest, Request};
 use rocket::response::Redirect;
 use rocket::State;
 use rocket_dyn_templates::Template;
 use std::collections::HashMap;
+use std::net::IpAddr;
 use std::sync::{Arc, Mutex};
-use rand::{Rng, thread_rng};
-use rand::distributions::Alphanumeric;
 use crate::bbox::BBox;
 
-/// (username, apikey)
+/// (username, apikey, impersonating admin). A bearer token's scope is checked when the guard
+/// runs, so handlers never need to look at it.
 pub(crate) struct ApiKey {
     pub user: String,
     pub key: String,
+    /// Admin viewing the site as `user`, if impersonating
+    pub impersonator: Option<String>,
 }
 
 pub(crate) struct BBoxApiKey {
@@ -39,7 +48,7 @@ impl BBoxApiKey {
 pub(crate) struct ApiKeyRequest {
     email: String,
     gender: String,
-    age: u32,
+    age: String,
     ethnicity: String,
     is_remote: bool,
     education: String,
@@ -48,6 +57,7 @@ pub(crate) struct ApiKeyRequest {
 #[derive(Debug, FromForm)]
 pub(crate) struct ApiKeySubmit {
     key: String,
+    next: Option<String>,
 }
 
 #[derive(Debug)]
@@ -55,6 +65,8 @@ pub(crate) enum ApiKeyError {
     Ambiguous,
     Missing,
     BackendFailure,
+    InsufficientScope,
+    Impersonating,
 }
 
 #[rocket::async_trait]
@@ -66,61 +78,155 @@ impl<'r> FromRequest<'r> for ApiKey {
             .guard::<&State<Arc<Mutex<MySqlBackend>>>>()
             .await
             .unwrap();
-        request
+
+        // programmatic clients authenticate with a bearer token; if one is presented, the
+        // cookie is ignored entirely (which is also what makes skipping CSRF checks safe)
+        if let Some(auth) = request.headers().get_one("Authorization") {
+            let cfg = request.guard::<&State<Config>>().await.unwrap();
+            let token = match auth.strip_prefix("Bearer ") {
+                Some(t) => t.trim(),
+                None => return Outcome::Failure((Status::Unauthorized, ApiKeyError::Missing)),
+            };
+            return match tokens::check_bearer_token(be, cfg, token) {
+                Ok((user, scope)) if scope.permits(request) => Outcome::Success(ApiKey {
+                    user,
+                    key: token.to_string(),
+                    impersonator: None,
+                }),
+                Ok(_) => Outcome::Failure((Status::Forbidden, ApiKeyError::InsufficientScope)),
+                Err(e) => Outcome::Failure((Status::Unauthorized, e)),
+            };
+        }
+
+        let apikey = request
             .cookies()
             .get("apikey")
             .and_then(|cookie| cookie.value().parse().ok())
             .and_then(|key: String| match check_api_key(&be, &key) {
-                Ok(user) => Some(ApiKey { user, key }),
+                Ok(user) => Some(ApiKey {
+                    user,
+                    key,
+                    impersonator: None,
+                }),
                 Err(_) => None,
-            })
-            .into_outcome((Status::Unauthorized, ApiKeyError::Missing))
+            });
+        let apikey = match apikey {
+            Some(apikey) => apikey,
+            None => return Outcome::Failure((Status::Unauthorized, ApiKeyError::Missing)),
+        };
+
+        // admins viewing the site as a student see it read-only: GETs are served as the student,
+        // and every write other than ending the impersonation is refused
+        if let Some(student) = request.cookies().get_private(impersonate::COOKIE) {
+            let cfg = request.guard::<&State<Config>>().await.unwrap();
+            let student = student.value().to_string();
+            // only impersonations that were started (and audited) and not yet stopped count
+            let open = cfg.admins.contains(&apikey.user)
+                && !impersonate::is_privileged(cfg, &student)
+                && impersonate::is_open(&mut be.lock().unwrap(), &apikey.user, &student);
+            if !open {
+                request
+                    .cookies()
+                    .remove_private(Cookie::named(impersonate::COOKIE));
+            } else if request.method() == Method::Get {
+                return Outcome::Success(ApiKey {
+                    user: student,
+                    key: apikey.key,
+                    impersonator: Some(apikey.user),
+                });
+            } else if request.uri().path().as_str() != impersonate::STOP_PATH {
+                return Outcome::Failure((Status::Forbidden, ApiKeyError::Impersonating));
+            }
+        }
+
+        Outcome::Success(apikey)
     }
 }
 
 #[post("/", data = "<data>")]
 pub(crate) fn generate(
-    data: Form<ApiKeyRequest>,
+    data: CsrfForm<ApiKeyRequest>,
+    csrf: CsrfToken,
     backend: &State<Arc<Mutex<MySqlBackend>>>,
     config: &State<Config>,
-) -> Template {
-    // generate an API key from email address
-    let mut hasher = Sha256::new();
-    hasher.input_str(&data.email);
-    // add a secret to make API keys unforgeable without access to the server
-    hasher.input_str(&config.secret);
-    let hash = hasher.result_str();
-
-    let pseudonym: String = thread_rng()
-        .sample_iter(&Alphanumeric)
-        .take(16)
-        .map(char::from)
-        .collect();
+    limiter: &State<Arc<RateLimiter>>,
+) -> Result<Template, Status> {
+    // limit registrations (and hence emails) per address, not just per client
+    if !limiter.take("/apikey/generate", &data.email.to_lowercase()) {
+        return Err(Status::TooManyRequests);
+    }
 
-    let is_admin = if config.admins.contains(&data.email) {
-        1.into()
-    } else {
-        0.into()
+    let age = match profile::validate(
+        config,
+        &data.gender,
+        &data.age,
+        &data.ethnicity,
+        &data.education,
+    ) {
+        Ok(age) => age,
+        Err(errors) => {
+            // show the form again with what was entered, so it can be corrected
+            let demographics = profile::fields(
+                config,
+                &data.gender,
+                &data.age,
+                &data.ethnicity,
+                data.is_remote,
+                &data.education,
+            );
+            return Ok(render_login(
+                config,
+                csrf,
+                &None,
+                &data.email,
+                demographics,
+                errors,
+            ));
+        }
     };
 
-    let is_manager = if config.managers.contains(&data.email) {
-        1.into()
+    let mut bg = backend.lock().unwrap();
+    let email_error = if !roster::valid_email(&data.email) {
+        Some("Enter a valid email address.")
+    } else if !roster::may_register(&mut bg, config, &data.email) {
+        Some("This email address is not on the class roster.")
     } else {
-        0.into()
+        None
     };
-
-    // insert into MySql if not exists
-    let mut bg = backend.lock().unwrap();
-    bg.insert(
-        "users",
-        vec![data.email.as_str().into(), hash.as_str().into(), is_admin, is_manager,
-             pseudonym.into(), data.gender.as_str().into(), data.age.into(),
-             data.ethnicity.as_str().into(), data.is_remote.into(), data.education.as_str().into()],
-    );
+    if let Some(email_error) = email_error {
+        drop(bg);
+        let demographics = profile::fields(
+            config,
+            &data.gender,
+            &data.age,
+            &data.ethnicity,
+            data.is_remote,
+            &data.education,
+        );
+        let mut errors = HashMap::new();
+        errors.insert("email", String::from(email_error));
+        return Ok(render_login(
+            config,
+            csrf,
+            &None,
+            &data.email,
+            demographics,
+            errors,
+        ));
+    }
+    let demographics = Demographics {
+        gender: &data.gender,
+        age,
+        ethnicity: &data.ethnicity,
+        is_remote: data.is_remote,
+        education: &data.education,
+    };
+    let hash = create_user(&mut bg, config, &data.email, &demographics);
 
     if config.send_emails {
         email::send(
             bg.log.clone(),
+            config,
             "no-reply@csci2390-submit.cs.brown.edu".into(),
             vec![data.email.clone()],
             format!("{} API key", config.class),
@@ -133,8 +239,49 @@ pub(crate) fn generate(
     // return to user
     let mut ctx = HashMap::new();
     ctx.insert("apikey_email", data.email.clone());
+    ctx.insert("csrf_token", csrf.0);
     ctx.insert("parent", "layout".into());
-    Template::render("apikey/generate", &ctx)
+    Ok(Template::render("apikey/generate", &ctx))
+}
+
+/// Registers a new user and returns their API key.
+pub(crate) fn create_user(
+    bg: &mut MySqlBackend,
+    config: &Config,
+    email: &str,
+    demographics: &Demographics,
+) -> String {
+    // generate an API key from email address
+    let mut hasher = Sha256::new();
+    hasher.input_str(email);
+    // add a secret to make API keys unforgeable without access to the server
+    hasher.input_str(&config.secret);
+    let hash = hasher.result_str();
+
+    let pseudonym = pseudonym::generate(bg, config);
+
+    let is_admin = if config.admins.iter().any(|a| a == email) {
+        1.into()
+    } else {
+        0.into()
+    };
+
+    let is_manager = if config.managers.iter().any(|m| m == email) {
+        1.into()
+    } else {
+        0.into()
+    };
+
+    // insert into MySql if not exists
+    bg.insert(
+        "users",
+        vec![email.into(), hash.as_str().into(), is_admin, is_manager,
+             pseudonym.into(), demographics.gender.into(), demographics.age.into(),
+             demographics.ethnicity.into(), demographics.is_remote.into(),
+             demographics.education.into()],
+    );
+
+    hash
 }
 
 pub(crate) fn check_api_key(
@@ -158,9 +305,11 @@ pub(crate) fn check_api_key(
 
 #[post("/", data = "<data>")]
 pub(crate) fn check(
-    data: Form<ApiKeySubmit>,
+    data: CsrfForm<ApiKeySubmit>,
+    ip: IpAddr,
     cookies: &CookieJar<'_>,
     backend: &State<Arc<Mutex<MySqlBackend>>>,
+    limiter: &State<Arc<RateLimiter>>,
 ) -> Redirect {
     // check that the API key exists and set cookie
     let res = check_api_key(&*backend, &data.key);
@@ -174,14 +323,19 @@ pub(crate) fn check(
         Err(ApiKeyError::Ambiguous) => {
             eprintln!("Ambiguous API key: {}", data.key);
         }
+        Err(ApiKeyError::InsufficientScope) | Err(ApiKeyError::Impersonating) => {
+            eprintln!("API key not permitted: {}", data.key);
+        }
         Ok(_) => (),
     }
 
     if res.is_err() {
+        limiter.record_failure(ip);
         Redirect::to("/")
     } else {
+        limiter.record_success(&ip);
         let cookie = Cookie::build("apikey", data.key.clone()).path("/").finish();
         cookies.add(cookie);
-        Redirect::to("/leclist")
+        Redirect::to(safe_next(&data.next))
     }
 }
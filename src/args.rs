@@ -5,12 +5,14 @@ use clap::{App, Arg};
 const WEBSUBMIT_USAGE: &'static str = "\
 EXAMPLES:
   websubmit -i csci2390
-  websubmit -i csci2390 -c csci2390-f19.toml";
+  websubmit -i csci2390 -c csci2390-f19.toml
+  websubmit -i csci2390 -c csci2390-f19.toml --reencrypt";
 
 #[derive(Clone, Debug)]
 pub struct Args {
     pub class: String,
     pub config: config::Config,
+    pub reencrypt: bool,
 }
 
 pub fn parse_args() -> Args {
@@ -35,6 +37,11 @@ pub fn parse_args() -> Args {
                 .required(true)
                 .help("Short textual identifier for the class hosted (used as Noria deployment name)."),
         )
+        .arg(
+            Arg::with_name("reencrypt")
+                .long("reencrypt")
+                .help("Encrypt existing data in encrypted columns with the current key, then exit."),
+        )
         .after_help(WEBSUBMIT_USAGE)
         .get_matches();
 
@@ -42,5 +49,6 @@ pub fn parse_args() -> Args {
         class: String::from(args.value_of("class").unwrap()),
         config: config::parse(args.value_of("config").expect("Failed to parse config!"))
             .expect("failed to parse config"),
+        reencrypt: args.is_present("reencrypt"),
     }
 }
@@ -0,0 +1,397 @@
+use crate::apikey::ApiKey;
+use crate::backend::{MySqlBackend, Value};
+use crate::config::Config;
+use crate::csrf::{CsrfForm, NoFields};
+use crate::deadline::{self, Deadline};
+use crate::export::Download;
+use crate::pseudonym::Pseudonyms;
+use crate::visibility::LectureState;
+use crypto::digest::Digest;
+use crypto::sha2::Sha256;
+use mysql::from_value;
+use rand::distributions::Alphanumeric;
+use rand::{thread_rng, Rng};
+use rocket::fs::TempFile;
+use rocket::http::{ContentType, Status};
+use rocket::response::Redirect;
+use rocket::State;
+use std::collections::HashMap;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+#[derive(FromForm)]
+pub(crate) struct AttachmentUpload<'r> {
+    file: TempFile<'r>,
+}
+
+/// A student's file for one question. The contents are stored in `attachment_dir` under their
+/// SHA-256 hash, so identical uploads share one file.
+#[derive(Debug, Clone, Serialize)]
+pub(crate) struct Attachment {
+    pub filename: String,
+    pub mime: String,
+    pub size: u64,
+    #[serde(skip)]
+    pub hash: String,
+}
+
+impl Attachment {
+    /// Reads `filename, mime, size, hash` starting at column `i`.
+    fn from_values(r: &[Value], i: usize) -> Attachment {
+        Attachment {
+            filename: from_value(r[i].clone()),
+            mime: from_value(r[i + 1].clone()),
+            size: from_value(r[i + 2].clone()),
+            hash: from_value(r[i + 3].clone()),
+        }
+    }
+
+    /// The stored contents.
+    pub(crate) fn read(&self, config: &Config) -> io::Result<Vec<u8>> {
+        std::fs::read(file_path(config, &self.hash))
+    }
+}
+
+/// Whether a question (a full `questions` row) accepts attachments.
+pub(crate) fn accepted(r: &[Value]) -> bool {
+    from_value::<Option<bool>>(r[11].clone()).unwrap_or(false)
+}
+
+fn file_path(config: &Config, hash: &str) -> PathBuf {
+    Path::new(&config.attachment_dir).join(hash)
+}
+
+/// A student's attachments for a lecture, by question.
+pub(crate) fn for_student(
+    bg: &mut MySqlBackend,
+    email: &str,
+    lec: u64,
+) -> HashMap<u64, Attachment> {
+    bg.prep_exec(
+        "SELECT q, filename, mime, size, hash FROM attachments WHERE email = ? AND lec = ?",
+        vec![email.into(), lec.into()],
+    )
+    .into_iter()
+    .map(|r| (from_value(r[0].clone()), Attachment::from_values(&r, 1)))
+    .collect()
+}
+
+/// All attachments for a lecture, by student and question.
+pub(crate) fn for_lecture(
+    bg: &mut MySqlBackend,
+    lec: u64,
+) -> HashMap<(String, u64), Attachment> {
+    bg.prep_exec(
+        "SELECT email, q, filename, mime, size, hash FROM attachments WHERE lec = ?",
+        vec![lec.into()],
+    )
+    .into_iter()
+    .map(|r| {
+        let key = (from_value(r[0].clone()), from_value(r[1].clone()));
+        (key, Attachment::from_values(&r, 2))
+    })
+    .collect()
+}
+
+/// All of a student's attachments, as `(lec, q, attachment)`.
+pub(crate) fn for_user(bg: &mut MySqlBackend, email: &str) -> Vec<(u64, u64, Attachment)> {
+    bg.prep_exec(
+        "SELECT lec, q, filename, mime, size, hash FROM attachments WHERE email = ? \
+         ORDER BY lec, q",
+        vec![email.into()],
+    )
+    .into_iter()
+    .map(|r| {
+        (
+            from_value(r[0].clone()),
+            from_value(r[1].clone()),
+            Attachment::from_values(&r, 2),
+        )
+    })
+    .collect()
+}
+
+/// Removes stored files that no attachment refers to any more.
+fn remove_unreferenced(bg: &mut MySqlBackend, config: &Config, hashes: Vec<String>) {
+    for hash in hashes {
+        let res = bg.prep_exec(
+            "SELECT COUNT(*) FROM attachments WHERE hash = ?",
+            vec![hash.as_str().into()],
+        );
+        if from_value::<u64>(res[0][0].clone()) > 0 {
+            continue;
+        }
+        match std::fs::remove_file(file_path(config, &hash)) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => {
+                eprintln!("failed to remove attachment {}: {}", hash, e)
+            }
+            _ => (),
+        }
+    }
+}
+
+/// Deletes the attachments matching `filter` (e.g., "email = ?") and their files.
+pub(crate) fn delete_where(
+    bg: &mut MySqlBackend,
+    config: &Config,
+    filter: &str,
+    params: Vec<Value>,
+) -> u64 {
+    let hashes: Vec<String> = bg
+        .prep_exec(
+            &format!("SELECT hash FROM attachments WHERE {}", filter),
+            params.clone(),
+        )
+        .into_iter()
+        .map(|r| from_value(r[0].clone()))
+        .collect();
+    bg.prep_exec(&format!("DELETE FROM attachments WHERE {}", filter), params);
+    let removed = hashes.len() as u64;
+    remove_unreferenced(bg, config, hashes);
+    removed
+}
+
+/// Students may only change attachments while they can submit answers to the question.
+fn check_writable(bg: &mut MySqlBackend, email: &str, lec: u64, q: u64) -> Result<(), Status> {
+    let now = deadline::now();
+    match LectureState::load(bg, lec) {
+        Some(s) if s.accepts_answers(now) => (),
+        _ => return Err(Status::Forbidden),
+    }
+    if Deadline::for_student(bg, lec, email).is_locked(now) {
+        return Err(Status::Forbidden);
+    }
+    let res = bg.prep_exec(
+        "SELECT * FROM questions WHERE lec = ? AND q = ?",
+        vec![lec.into(), q.into()],
+    );
+    match res.first() {
+        Some(r) if accepted(r) => Ok(()),
+        Some(_) => Err(Status::Forbidden),
+        None => Err(Status::NotFound),
+    }
+}
+
+/// Checks an upload's size and type against the configured limits. Returns its MIME type.
+fn check_upload(config: &Config, len: u64, ct: Option<&ContentType>) -> Result<String, Status> {
+    if len == 0 {
+        return Err(Status::UnprocessableEntity);
+    }
+    if len > config.attachment_max_kb * 1024 {
+        return Err(Status::PayloadTooLarge);
+    }
+    // the type is the one the browser sent; it is only ever served back as a download
+    let ct = ct.ok_or(Status::UnsupportedMediaType)?;
+    let mime = format!("{}/{}", ct.top(), ct.sub()).to_lowercase();
+    if !config.attachment_types.contains(&mime) {
+        return Err(Status::UnsupportedMediaType);
+    }
+    Ok(mime)
+}
+
+/// Moves an upload into `attachment_dir`, named by its hash. Returns the hash and size.
+async fn store(config: &Config, file: &mut TempFile<'_>) -> io::Result<(String, u64)> {
+    let dir = Path::new(&config.attachment_dir);
+    std::fs::create_dir_all(dir)?;
+    let suffix: String = thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(16)
+        .map(char::from)
+        .collect();
+    let tmp = dir.join(format!(".upload-{}", suffix));
+    file.move_copy_to(&tmp).await?;
+
+    let data = std::fs::read(&tmp)?;
+    let mut hasher = Sha256::new();
+    hasher.input(&data);
+    let hash = hasher.result_str();
+    let dest = dir.join(&hash);
+    if dest.exists() {
+        std::fs::remove_file(&tmp)?;
+    } else {
+        std::fs::rename(&tmp, &dest)?;
+    }
+    Ok((hash, data.len() as u64))
+}
+
+#[post("/<lec>/<q>", data = "<data>")]
+pub(crate) async fn upload(
+    apikey: ApiKey,
+    lec: u64,
+    q: u64,
+    mut data: CsrfForm<AttachmentUpload<'_>>,
+    backend: &State<Arc<Mutex<MySqlBackend>>>,
+    config: &State<Config>,
+) -> Result<Redirect, Status> {
+    {
+        let mut bg = backend.lock().unwrap();
+        check_writable(&mut bg, &apikey.user, lec, q)?;
+    }
+
+    let file = &mut data.file;
+    let mime = check_upload(config, file.len(), file.content_type())?;
+    let ct = file.content_type().unwrap(); // checked above
+    let filename = match (file.name(), ct.extension()) {
+        (Some(name), Some(ext)) => format!("{}.{}", name, ext),
+        (Some(name), None) => name.to_string(),
+        (None, _) => String::from("attachment"),
+    };
+
+    let (hash, size) = store(config, file).await.map_err(|e| {
+        eprintln!("failed to store attachment: {}", e);
+        Status::InternalServerError
+    })?;
+
+    let mut bg = backend.lock().unwrap();
+    let old: Vec<String> = bg
+        .prep_exec(
+            "SELECT hash FROM attachments WHERE email = ? AND lec = ? AND q = ?",
+            vec![apikey.user.as_str().into(), lec.into(), q.into()],
+        )
+        .into_iter()
+        .map(|r| from_value(r[0].clone()))
+        .collect();
+    bg.replace(
+        "attachments",
+        vec![
+            apikey.user.as_str().into(),
+            lec.into(),
+            q.into(),
+            filename.into(),
+            mime.into(),
+            size.into(),
+            hash.into(),
+            deadline::now().into(),
+        ],
+    );
+    remove_unreferenced(&mut bg, config, old);
+    drop(bg);
+
+    Ok(Redirect::to(format!("/questions/{}", lec)))
+}
+
+#[post("/<lec>/<q>/remove", data = "<_data>")]
+pub(crate) fn remove(
+    apikey: ApiKey,
+    lec: u64,
+    q: u64,
+    _data: CsrfForm<NoFields>,
+    backend: &State<Arc<Mutex<MySqlBackend>>>,
+    config: &State<Config>,
+) -> Result<Redirect, Status> {
+    let mut bg = backend.lock().unwrap();
+    check_writable(&mut bg, &apikey.user, lec, q)?;
+    delete_where(
+        &mut bg,
+        config,
+        "email = ? AND lec = ? AND q = ?",
+        vec![apikey.user.as_str().into(), lec.into(), q.into()],
+    );
+    drop(bg);
+
+    Ok(Redirect::to(format!("/questions/{}", lec)))
+}
+
+fn send(
+    bg: &mut MySqlBackend,
+    config: &Config,
+    email: &str,
+    lec: u64,
+    q: u64,
+) -> Result<Download, Status> {
+    let attachment = for_student(bg, email, lec)
+        .remove(&q)
+        .ok_or(Status::NotFound)?;
+    let data = attachment.read(config).map_err(|e| {
+        eprintln!("failed to read attachment {}: {}", attachment.hash, e);
+        Status::NotFound
+    })?;
+    // always served as a download, never displayed inline
+    let ct = ContentType::parse_flexible(&attachment.mime).unwrap_or(ContentType::Binary);
+    Ok(Download::new(ct, &attachment.filename, data))
+}
+
+/// Whether `user` may download other students' attachments; everyone else only gets their own.
+fn may_view_all(config: &Config, user: &str) -> bool {
+    config.admins.iter().any(|a| a == user) || config.staff.iter().any(|s| s == user)
+}
+
+/// A student's own attachment.
+#[get("/<lec>/<q>")]
+pub(crate) fn download_own(
+    apikey: ApiKey,
+    lec: u64,
+    q: u64,
+    backend: &State<Arc<Mutex<MySqlBackend>>>,
+    config: &State<Config>,
+) -> Result<Download, Status> {
+    let mut bg = backend.lock().unwrap();
+    send(&mut bg, config, &apikey.user, lec, q)
+}
+
+/// Any student's attachment, for admins and staff.
+#[get("/<lec>/<q>/<user>")]
+pub(crate) fn download(
+    apikey: ApiKey,
+    lec: u64,
+    q: u64,
+    user: String,
+    backend: &State<Arc<Mutex<MySqlBackend>>>,
+    config: &State<Config>,
+) -> Result<Download, Status> {
+    if !may_view_all(config, &apikey.user) {
+        return Err(Status::Forbidden);
+    }
+    let mut bg = backend.lock().unwrap();
+    // in anonymous view, `user` is a pseudonym
+    let email = Pseudonyms::load(&mut bg).resolve(&user);
+    send(&mut bg, config, &email, lec, q)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config;
+
+    fn test_config() -> Config {
+        let mut config = config::parse("sample-config.toml").unwrap();
+        config.attachment_max_kb = 4;
+        config.attachment_types = vec!["application/pdf".into(), "image/png".into()];
+        config
+    }
+
+    #[test]
+    fn uploads_are_limited_by_size() {
+        let config = test_config();
+        let pdf = ContentType::PDF;
+        assert_eq!(check_upload(&config, 0, Some(&pdf)), Err(Status::UnprocessableEntity));
+        assert_eq!(check_upload(&config, 4096, Some(&pdf)), Ok("application/pdf".into()));
+        assert_eq!(check_upload(&config, 4097, Some(&pdf)), Err(Status::PayloadTooLarge));
+    }
+
+    #[test]
+    fn uploads_are_limited_by_type() {
+        let config = test_config();
+        assert_eq!(check_upload(&config, 10, Some(&ContentType::PNG)), Ok("image/png".into()));
+        // parameters and case do not matter
+        let ct = ContentType::parse_flexible("Application/PDF; charset=binary").unwrap();
+        assert_eq!(check_upload(&config, 10, Some(&ct)), Ok("application/pdf".into()));
+        assert_eq!(
+            check_upload(&config, 10, Some(&ContentType::HTML)),
+            Err(Status::UnsupportedMediaType)
+        );
+        assert_eq!(check_upload(&config, 10, None), Err(Status::UnsupportedMediaType));
+    }
+
+    #[test]
+    fn only_admins_and_staff_see_others_attachments() {
+        let mut config = test_config();
+        config.admins = vec!["admin@brown.edu".into()];
+        config.staff = vec!["ta@brown.edu".into()];
+        assert!(may_view_all(&config, "admin@brown.edu"));
+        assert!(may_view_all(&config, "ta@brown.edu"));
+        assert!(!may_view_all(&config, "student@brown.edu"));
+        assert!(!may_view_all(&config, ""));
+    }
+}
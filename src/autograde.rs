@@ -0,0 +1,260 @@
+use crate::backend::Value;
+use crate::qtype::{split_options, QuestionSpec, QuestionType};
+use mysql::from_value;
+use regex::{Regex, RegexBuilder};
+
+/// Bound on the compiled size of a regular expression key, so that a key cannot make grading
+/// slow or exhaust memory.
+const REGEX_SIZE_LIMIT: usize = 1 << 20;
+
+/// How an answer is compared to the answer key.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum KeyMatch {
+    /// Same text, ignoring leading and trailing whitespace
+    Exact,
+    /// Same text, also ignoring case
+    CaseInsensitive,
+    /// The whole answer matches the key as a regular expression
+    Regex,
+    /// Within the question's tolerance of the key
+    Numeric,
+    /// For single choice, one of the key's options (one per line); for multiple choice, exactly
+    /// the key's options
+    Choices,
+}
+
+pub(crate) const ALL_MATCHES: &[KeyMatch] = &[
+    KeyMatch::Exact,
+    KeyMatch::CaseInsensitive,
+    KeyMatch::Regex,
+    KeyMatch::Numeric,
+    KeyMatch::Choices,
+];
+
+impl KeyMatch {
+    pub(crate) fn as_str(&self) -> &'static str {
+        match self {
+            KeyMatch::Exact => "exact",
+            KeyMatch::CaseInsensitive => "case-insensitive",
+            KeyMatch::Regex => "regex",
+            KeyMatch::Numeric => "numeric",
+            KeyMatch::Choices => "choices",
+        }
+    }
+
+    pub(crate) fn parse(s: &str) -> Option<KeyMatch> {
+        ALL_MATCHES.iter().find(|m| m.as_str() == s).copied()
+    }
+
+    /// Which comparisons make sense for a question type.
+    fn applies_to(&self, qtype: QuestionType) -> bool {
+        match qtype {
+            QuestionType::SingleChoice | QuestionType::MultipleChoice => *self == KeyMatch::Choices,
+            QuestionType::Numeric => *self == KeyMatch::Numeric,
+            QuestionType::Text | QuestionType::ShortText | QuestionType::Code => {
+                *self == KeyMatch::Exact
+                    || *self == KeyMatch::CaseInsensitive
+                    || *self == KeyMatch::Regex
+            }
+        }
+    }
+}
+
+/// A question's answer key. Questions without one are graded by hand.
+#[derive(Debug, Clone)]
+pub(crate) struct AnswerKey {
+    pub key: String,
+    pub matching: KeyMatch,
+    /// Grade for a correct answer (incorrect answers get 0)
+    pub points: u64,
+}
+
+impl AnswerKey {
+    /// Reads the key from a `questions` row (`SELECT *`), if it has one.
+    pub(crate) fn from_row(r: &[Value]) -> Option<AnswerKey> {
+        let key: Option<String> = from_value(r[7].clone());
+        let matching = from_value::<Option<String>>(r[8].clone()).and_then(|m| KeyMatch::parse(&m));
+        match (key, matching) {
+            (Some(key), Some(matching)) => Some(AnswerKey {
+                key,
+                matching,
+                points: from_value::<Option<u64>>(r[9].clone()).unwrap_or(1),
+            }),
+            _ => None,
+        }
+    }
+
+    /// Checks that the key can be used for the question.
+    pub(crate) fn check(&self, spec: &QuestionSpec) -> Result<(), String> {
+        if !self.matching.applies_to(spec.qtype) {
+            return Err(format!(
+                "\"{}\" matching cannot be used for {} questions.",
+                self.matching.as_str(),
+                spec.qtype.label()
+            ));
+        }
+        match self.matching {
+            KeyMatch::Regex => self
+                .regex()
+                .map(|_| ())
+                .map_err(|e| format!("Invalid regular expression: {}", e)),
+            KeyMatch::Numeric => match self.key.trim().parse::<f64>() {
+                Ok(n) if n.is_finite() => Ok(()),
+                _ => Err(String::from("The answer key must be a number.")),
+            },
+            KeyMatch::Choices => {
+                let keys = split_options(&self.key);
+                if keys.is_empty() || !keys.iter().all(|k| spec.options.contains(k)) {
+                    Err(String::from("The answer key must list options of the question."))
+                } else {
+                    Ok(())
+                }
+            }
+            KeyMatch::Exact | KeyMatch::CaseInsensitive => Ok(()),
+        }
+    }
+
+    /// The values to store in the `answer_key`, `key_match` and `points` columns.
+    pub(crate) fn to_values(key: &Option<AnswerKey>) -> Vec<Value> {
+        match key {
+            Some(k) => vec![
+                k.key.as_str().into(),
+                k.matching.as_str().into(),
+                k.points.into(),
+            ],
+            None => vec![Value::NULL, Value::NULL, Value::NULL],
+        }
+    }
+
+    /// The key as a regular expression, anchored so that it has to match the whole answer.
+    fn regex(&self) -> Result<Regex, regex::Error> {
+        RegexBuilder::new(&format!("^(?:{})$", self.key))
+            .size_limit(REGEX_SIZE_LIMIT)
+            .build()
+    }
+
+    fn is_correct(&self, spec: &QuestionSpec, answer: &str) -> bool {
+        let answer = answer.trim();
+        match self.matching {
+            KeyMatch::Exact => answer == self.key.trim(),
+            KeyMatch::CaseInsensitive => answer.to_lowercase() == self.key.trim().to_lowercase(),
+            KeyMatch::Regex => self
+                .regex()
+                .map(|re| re.is_match(answer))
+                .unwrap_or(false),
+            KeyMatch::Numeric => match (answer.parse::<f64>(), self.key.trim().parse::<f64>()) {
+                (Ok(a), Ok(k)) => (a - k).abs() <= spec.tolerance.unwrap_or(0.0),
+                _ => false,
+            },
+            KeyMatch::Choices => {
+                let keys = split_options(&self.key);
+                let mut selected = split_options(answer);
+                if spec.qtype == QuestionType::SingleChoice {
+                    selected.len() == 1 && keys.contains(&selected[0])
+                } else {
+                    let mut keys = keys;
+                    keys.sort();
+                    selected.sort();
+                    keys == selected
+                }
+            }
+        }
+    }
+
+    /// Grades an answer (as stored, i.e., after validation).
+    pub(crate) fn grade(&self, spec: &QuestionSpec, answer: &str) -> u64 {
+        if self.is_correct(spec, answer) {
+            self.points
+        } else {
+            0
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn spec(qtype: QuestionType, options: &[&str], tolerance: Option<f64>) -> QuestionSpec {
+        QuestionSpec {
+            qtype,
+            options: options.iter().map(|o| o.to_string()).collect(),
+            tolerance,
+            language: None,
+        }
+    }
+
+    fn key(key: &str, matching: KeyMatch) -> AnswerKey {
+        AnswerKey {
+            key: key.to_string(),
+            matching,
+            points: 3,
+        }
+    }
+
+    #[test]
+    fn grades_exact_and_case_insensitive() {
+        let s = spec(QuestionType::ShortText, &[], None);
+        let exact = key(" Paxos ", KeyMatch::Exact);
+        assert_eq!(exact.grade(&s, "Paxos\n"), 3);
+        assert_eq!(exact.grade(&s, "paxos"), 0);
+        let ci = key("Paxos", KeyMatch::CaseInsensitive);
+        assert_eq!(ci.grade(&s, " pAXOS "), 3);
+        assert_eq!(ci.grade(&s, "Raft"), 0);
+    }
+
+    #[test]
+    fn regex_keys_match_whole_answers() {
+        let s = spec(QuestionType::Text, &[], None);
+        let k = key("colou?r|hue", KeyMatch::Regex);
+        assert_eq!(k.grade(&s, "color"), 3);
+        assert_eq!(k.grade(&s, " hue "), 3);
+        assert_eq!(k.grade(&s, "a colour"), 0);
+        assert_eq!(k.grade(&s, "hues"), 0);
+        assert!(k.check(&s).is_ok());
+        assert!(key("(", KeyMatch::Regex).check(&s).is_err());
+        // keys that compile to huge programs are refused rather than graded
+        let huge = key("(?:a{1000}){1000}", KeyMatch::Regex);
+        assert!(huge.check(&s).is_err());
+        assert_eq!(huge.grade(&s, "a"), 0);
+    }
+
+    #[test]
+    fn numeric_keys_use_the_tolerance() {
+        let k = key("3.14", KeyMatch::Numeric);
+        let s = spec(QuestionType::Numeric, &[], Some(0.01));
+        assert_eq!(k.grade(&s, "3.141"), 3);
+        assert_eq!(k.grade(&s, "3.15"), 3);
+        assert_eq!(k.grade(&s, "3.2"), 0);
+        assert_eq!(k.grade(&s, "pi"), 0);
+        // without a tolerance, only the exact value
+        let s = spec(QuestionType::Numeric, &[], None);
+        assert_eq!(k.grade(&s, "3.140"), 3);
+        assert_eq!(k.grade(&s, "3.141"), 0);
+    }
+
+    #[test]
+    fn choice_keys() {
+        let single = spec(QuestionType::SingleChoice, &["a", "b", "c"], None);
+        // any one of the key's options
+        let k = key("a\nb", KeyMatch::Choices);
+        assert_eq!(k.grade(&single, "b"), 3);
+        assert_eq!(k.grade(&single, "c"), 0);
+        assert_eq!(k.grade(&single, "a\nb"), 0);
+
+        // exactly the key's options, in any order
+        let multiple = spec(QuestionType::MultipleChoice, &["a", "b", "c"], None);
+        assert_eq!(k.grade(&multiple, "b\na"), 3);
+        assert_eq!(k.grade(&multiple, "a"), 0);
+        assert_eq!(k.grade(&multiple, "a\nb\nc"), 0);
+        assert!(key("d", KeyMatch::Choices).check(&multiple).is_err());
+    }
+
+    #[test]
+    fn keys_must_fit_the_question_type() {
+        let numeric = spec(QuestionType::Numeric, &[], None);
+        assert!(key("42", KeyMatch::Exact).check(&numeric).is_err());
+        assert!(key("forty-two", KeyMatch::Numeric).check(&numeric).is_err());
+        assert!(key("42", KeyMatch::Numeric).check(&numeric).is_ok());
+    }
+}
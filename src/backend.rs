@@ -1,3 +1,4 @@
+use crate::encryption::Keyring;
 use mysql::prelude::*;
 use mysql::Opts;
 pub use mysql::Value;
@@ -12,6 +13,10 @@ pub struct MySqlBackend {
     db_user: String,
     db_password: String,
     db_name: String,
+    /// Keys for columns encrypted at rest, if enabled
+    pub keyring: Option<Keyring>,
+    /// Column names of tables inserted into, for encrypting positional values
+    table_columns: HashMap<String, Vec<String>>,
 }
 
 impl MySqlBackend {
@@ -21,6 +26,7 @@ impl MySqlBackend {
         dbname: &str,
         log: Option<slog::Logger>,
         prime: bool,
+        keyring: Option<Keyring>,
     ) -> Result<Self> {
         let log = match log {
             None => slog::Logger::root(slog::Discard, o!()),
@@ -73,6 +79,8 @@ impl MySqlBackend {
             db_user: String::from(user),
             db_password: String::from(password),
             db_name: String::from(dbname),
+            keyring,
+            table_columns: HashMap::new(),
         })
     }
 
@@ -87,7 +95,17 @@ impl MySqlBackend {
         .unwrap();
     }
 
+    /// Runs a query; values read from encrypted columns are returned decrypted.
     pub fn prep_exec(&mut self, sql: &str, params: Vec<Value>) -> Vec<Vec<Value>> {
+        self.do_exec(sql, params, true)
+    }
+
+    /// Runs a query, returning values of encrypted columns as stored (for re-encryption).
+    pub fn prep_exec_raw(&mut self, sql: &str, params: Vec<Value>) -> Vec<Vec<Value>> {
+        self.do_exec(sql, params, false)
+    }
+
+    fn do_exec(&mut self, sql: &str, params: Vec<Value>, decrypt: bool) -> Vec<Vec<Value>> {
         if !self.prep_stmts.contains_key(sql) {
             let stmt = self
                 .handle
@@ -109,8 +127,38 @@ impl MySqlBackend {
                 Ok(res) => {
                     let mut rows = vec![];
                     for row in res {
-                        let rowvals = row.unwrap().unwrap();
-                        let vals: Vec<Value> = rowvals.iter().map(|v| v.clone().into()).collect();
+                        let row = row.unwrap();
+                        // where each value comes from, to find those of encrypted columns
+                        let origins: Vec<(String, String)> = row
+                            .columns_ref()
+                            .iter()
+                            .map(|c| {
+                                (
+                                    c.org_table_str().into_owned(),
+                                    c.org_name_str().into_owned(),
+                                )
+                            })
+                            .collect();
+                        let rowvals = row.unwrap();
+                        let mut vals: Vec<Value> = rowvals.to_vec();
+                        if let (true, Some(keyring)) = (decrypt, &self.keyring) {
+                            for (v, (table, column)) in vals.iter_mut().zip(origins.iter()) {
+                                if !keyring.is_encrypted(table, column) {
+                                    continue;
+                                }
+                                let plaintext = match v {
+                                    Value::Bytes(b) => keyring.decrypt(table, column, b),
+                                    _ => Ok(None),
+                                };
+                                match plaintext {
+                                    Ok(Some(p)) => *v = Value::Bytes(p),
+                                    Ok(None) => (),
+                                    // one bad value should not take down every request; it is
+                                    // left sealed
+                                    Err(e) => error!(self.log, "{}", e),
+                                }
+                            }
+                        }
                         rows.push(vals);
                     }
                     debug!(self.log, "executed query {}, got {} rows", sql, rows.len());
@@ -121,7 +169,51 @@ impl MySqlBackend {
         }
     }
 
+    /// Whether `table.column` is encrypted at rest (and hence cannot be compared or ordered by
+    /// in SQL).
+    pub fn is_encrypted(&self, table: &str, column: &str) -> bool {
+        self.keyring
+            .as_ref()
+            .map(|k| k.is_encrypted(table, column))
+            .unwrap_or(false)
+    }
+
+    /// Encrypts a value for writing to `table.column` in an UPDATE, if that column is encrypted.
+    /// (`insert` and `replace` do this automatically.)
+    pub fn seal(&self, table: &str, column: &str, value: Value) -> Value {
+        match &self.keyring {
+            Some(keyring) => keyring.seal(table, column, value),
+            None => value,
+        }
+    }
+
+    fn seal_row(&mut self, table: &str, vals: Vec<Value>) -> Vec<Value> {
+        if self.keyring.is_none() {
+            return vals;
+        }
+        if !self.table_columns.contains_key(table) {
+            let columns = self
+                .prep_exec_raw(
+                    "SELECT column_name FROM information_schema.columns \
+                     WHERE table_schema = DATABASE() AND table_name = ? \
+                     ORDER BY ordinal_position",
+                    vec![table.into()],
+                )
+                .into_iter()
+                .map(|r| from_value(r[0].clone()))
+                .collect();
+            self.table_columns.insert(table.to_owned(), columns);
+        }
+        let columns = &self.table_columns[table];
+        let keyring = self.keyring.as_ref().unwrap();
+        vals.into_iter()
+            .zip(columns.iter())
+            .map(|(v, c)| keyring.seal(table, c, v))
+            .collect()
+    }
+
     fn do_insert(&mut self, table: &str, vals: Vec<Value>, replace: bool) {
+        let vals = self.seal_row(table, vals);
         let op = if replace { "REPLACE" } else { "INSERT" };
         let q = format!(
             "{} INTO {} VALUES ({})",
@@ -1,7 +1,72 @@
+use chrono_tz::Tz;
 use std::fs;
 use std::io::{Error, ErrorKind, Read};
 use toml;
 
+#[derive(Debug, Clone)]
+pub struct OidcConfig {
+    /// Issuer URL (discovery document is fetched from here)
+    pub issuer: String,
+    /// OAuth client ID
+    pub client_id: String,
+    /// OAuth client secret
+    pub client_secret: String,
+    /// Email domains allowed to log in (empty means any)
+    pub allowed_domains: Vec<String>,
+}
+
+#[derive(Debug, Clone)]
+pub struct RateLimit {
+    /// Path of the (POST) route this limit applies to
+    pub route: String,
+    /// Maximum number of requests in a burst
+    pub burst: u32,
+    /// Sustained requests per minute
+    pub per_minute: u32,
+}
+
+#[derive(Debug, Clone)]
+pub struct LatePenalty {
+    /// Applies to submissions at least this many hours late
+    pub hours: u64,
+    /// Percentage taken off the grade
+    pub percent: u64,
+}
+
+#[derive(Debug, Clone)]
+pub struct DemographicsConfig {
+    /// Gender choices offered
+    pub genders: Vec<String>,
+    /// Ethnicity choices offered
+    pub ethnicities: Vec<String>,
+    /// Education choices offered
+    pub educations: Vec<String>,
+    /// Youngest accepted age
+    pub min_age: u32,
+    /// Oldest accepted age
+    pub max_age: u32,
+}
+
+/// How new pseudonyms look
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PseudonymStyle {
+    /// 16 random alphanumeric characters
+    Random,
+    /// e.g., "calm-heron-17"
+    AdjectiveAnimal,
+}
+
+/// Which of a student's submitted versions of an answer is graded
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum GradedVersion {
+    /// The most recent one
+    Latest,
+    /// The first one; resubmissions are kept but not graded
+    First,
+    /// The most recent one submitted by the (effective) due date, if any
+    LastOnTime,
+}
+
 #[derive(Debug, Clone)]
 pub struct Config {
     /// Textual identifier for class
@@ -26,6 +91,106 @@ pub struct Config {
     pub send_emails: bool,
     /// Whether to reset and prime db 
     pub prime: bool,
+    /// Externally visible base URL (for links in emails)
+    pub base_url: String,
+    /// Directory to write emails to instead of invoking sendmail
+    pub email_dir: Option<String>,
+    /// Lifetime of email login links, in minutes
+    pub login_link_ttl: u64,
+    /// OpenID Connect single sign-on (disabled if absent)
+    pub oidc: Option<OidcConfig>,
+    /// Per-route request limits (applied per client IP and, where known, per email)
+    pub rate_limits: Vec<RateLimit>,
+    /// Failed API key checks from one IP before it is locked out
+    pub lockout_threshold: u32,
+    /// Duration of a lockout, in minutes
+    pub lockout_minutes: u64,
+    /// Keep deleted users' answers (under an anonymous ID) for aggregates
+    pub anonymize_deleted_answers: bool,
+    /// Choices for demographic fields
+    pub demographics: DemographicsConfig,
+    /// File holding the keys for encryption at rest (disabled if absent)
+    pub encryption_key_file: Option<String>,
+    /// Columns encrypted at rest, as `table.column`
+    pub encrypted_columns: Vec<String>,
+    /// Style of newly assigned pseudonyms
+    pub pseudonym_style: PseudonymStyle,
+    /// Allow anyone to register, not just rostered students
+    pub open_registration: bool,
+    /// Email domains whose users may register without being on the roster
+    pub enrollment_domains: Vec<String>,
+    /// Timezone deadlines are entered and shown in (server local time if absent)
+    pub timezone: Option<Tz>,
+    /// Grade deductions for late answers (the largest applicable one is used)
+    pub late_penalties: Vec<LatePenalty>,
+    /// Which version of a resubmitted answer is graded
+    pub graded_version: GradedVersion,
+    /// Directory attachment files are stored in
+    pub attachment_dir: String,
+    /// Largest accepted attachment, in KiB
+    pub attachment_max_kb: u64,
+    /// Accepted attachment MIME types
+    pub attachment_types: Vec<String>,
+}
+
+fn default_rate_limits() -> Vec<RateLimit> {
+    vec![
+        RateLimit {
+            route: "/apikey/check".into(),
+            burst: 10,
+            per_minute: 5,
+        },
+        RateLimit {
+            route: "/apikey/generate".into(),
+            burst: 3,
+            per_minute: 1,
+        },
+        RateLimit {
+            route: "/login/link".into(),
+            burst: 3,
+            per_minute: 1,
+        },
+    ]
+}
+
+fn default_encrypted_columns() -> Vec<String> {
+    vec![
+        "users.gender".into(),
+        "users.ethnicity".into(),
+        "users.education".into(),
+        "answers.answer".into(),
+        "answer_versions.answer".into(),
+        "drafts.answer".into(),
+    ]
+}
+
+fn default_attachment_types() -> Vec<String> {
+    vec![
+        "application/pdf".into(),
+        "image/png".into(),
+        "image/jpeg".into(),
+        "text/plain".into(),
+    ]
+}
+
+fn default_demographics() -> DemographicsConfig {
+    let strings = |v: &[&str]| -> Vec<String> { v.iter().map(|s| String::from(*s)).collect() };
+    DemographicsConfig {
+        genders: strings(&["female", "male", "non-binary", "other"]),
+        ethnicities: strings(&[
+            "American Indian or Alaska Native",
+            "Asian",
+            "Black or African American",
+            "Hispanic or Latino",
+            "Native Hawaiian or Other Pacific Islander",
+            "White",
+            "multiple",
+            "other",
+        ]),
+        educations: strings(&["high school", "bachelors", "masters", "PhD"]),
+        min_age: 13,
+        max_age: 120,
+    }
 }
 
 pub(crate) fn parse(path: &str) -> Result<Config, Error> {
@@ -76,5 +241,169 @@ pub(crate) fn parse(path: &str) -> Result<Config, Error> {
         secret: value.get("secret").unwrap().as_str().unwrap().into(),
         send_emails: value.get("send_emails").unwrap().as_bool().unwrap().into(),
         prime: value.get("prime").unwrap().as_bool().unwrap().into(),
+        base_url: value
+            .get("base_url")
+            .map(|v| v.as_str().unwrap().into())
+            .unwrap_or(String::from("http://localhost:8000")),
+        email_dir: value.get("email_dir").map(|v| v.as_str().unwrap().into()),
+        login_link_ttl: value
+            .get("login_link_ttl")
+            .map(|v| v.as_integer().unwrap() as u64)
+            .unwrap_or(15),
+        oidc: value.get("oidc").map(|v| {
+            let t = v.as_table().unwrap();
+            OidcConfig {
+                issuer: t.get("issuer").unwrap().as_str().unwrap().into(),
+                client_id: t.get("client_id").unwrap().as_str().unwrap().into(),
+                client_secret: t.get("client_secret").unwrap().as_str().unwrap().into(),
+                allowed_domains: t
+                    .get("allowed_domains")
+                    .map(|d| {
+                        d.as_slice()
+                            .unwrap()
+                            .iter()
+                            .map(|v| v.as_str().unwrap().into())
+                            .collect()
+                    })
+                    .unwrap_or(vec![]),
+            }
+        }),
+        rate_limits: match value.get("rate_limit") {
+            Some(v) => v
+                .as_slice()
+                .unwrap()
+                .iter()
+                .map(|l| {
+                    let t = l.as_table().unwrap();
+                    RateLimit {
+                        route: t.get("route").unwrap().as_str().unwrap().into(),
+                        burst: t.get("burst").unwrap().as_integer().unwrap() as u32,
+                        per_minute: t.get("per_minute").unwrap().as_integer().unwrap() as u32,
+                    }
+                })
+                .collect(),
+            None => default_rate_limits(),
+        },
+        lockout_threshold: value
+            .get("lockout_threshold")
+            .map(|v| v.as_integer().unwrap() as u32)
+            .unwrap_or(10),
+        lockout_minutes: value
+            .get("lockout_minutes")
+            .map(|v| v.as_integer().unwrap() as u64)
+            .unwrap_or(15),
+        anonymize_deleted_answers: value
+            .get("anonymize_deleted_answers")
+            .map(|v| v.as_bool().unwrap())
+            .unwrap_or(false),
+        demographics: match value.get("demographics") {
+            Some(v) => {
+                let t = v.as_table().unwrap();
+                let strings = |key: &str| -> Vec<String> {
+                    t.get(key)
+                        .unwrap()
+                        .as_slice()
+                        .unwrap()
+                        .iter()
+                        .map(|v| v.as_str().unwrap().into())
+                        .collect()
+                };
+                DemographicsConfig {
+                    genders: strings("genders"),
+                    ethnicities: strings("ethnicities"),
+                    educations: strings("educations"),
+                    min_age: t.get("min_age").unwrap().as_integer().unwrap() as u32,
+                    max_age: t.get("max_age").unwrap().as_integer().unwrap() as u32,
+                }
+            }
+            None => default_demographics(),
+        },
+        encryption_key_file: value
+            .get("encryption_key_file")
+            .map(|v| v.as_str().unwrap().into()),
+        encrypted_columns: value
+            .get("encrypted_columns")
+            .map(|v| {
+                v.as_slice()
+                    .unwrap()
+                    .iter()
+                    .map(|c| c.as_str().unwrap().into())
+                    .collect()
+            })
+            .unwrap_or(default_encrypted_columns()),
+        pseudonym_style: match value.get("pseudonym_style").map(|v| v.as_str().unwrap()) {
+            None | Some("random") => PseudonymStyle::Random,
+            Some("adjective-animal") => PseudonymStyle::AdjectiveAnimal,
+            Some(s) => {
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("unknown pseudonym_style \"{}\"", s),
+                ));
+            }
+        },
+        open_registration: value
+            .get("open_registration")
+            .map(|v| v.as_bool().unwrap())
+            .unwrap_or(false),
+        enrollment_domains: value
+            .get("enrollment_domains")
+            .map(|v| {
+                v.as_slice()
+                    .unwrap()
+                    .iter()
+                    .map(|d| d.as_str().unwrap().into())
+                    .collect()
+            })
+            .unwrap_or(vec![]),
+        timezone: match value.get("timezone").map(|v| v.as_str().unwrap()) {
+            None => None,
+            Some(s) => Some(s.parse::<Tz>().map_err(|_| {
+                Error::new(ErrorKind::InvalidInput, format!("unknown timezone \"{}\"", s))
+            })?),
+        },
+        late_penalties: match value.get("late_penalty") {
+            Some(v) => v
+                .as_slice()
+                .unwrap()
+                .iter()
+                .map(|p| {
+                    let t = p.as_table().unwrap();
+                    LatePenalty {
+                        hours: t.get("hours").unwrap().as_integer().unwrap() as u64,
+                        percent: t.get("percent").unwrap().as_integer().unwrap() as u64,
+                    }
+                })
+                .collect(),
+            None => vec![],
+        },
+        graded_version: match value.get("graded_version").map(|v| v.as_str().unwrap()) {
+            None | Some("latest") => GradedVersion::Latest,
+            Some("first") => GradedVersion::First,
+            Some("last-on-time") => GradedVersion::LastOnTime,
+            Some(s) => {
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("unknown graded_version \"{}\"", s),
+                ));
+            }
+        },
+        attachment_dir: value
+            .get("attachment_dir")
+            .map(|v| v.as_str().unwrap().into())
+            .unwrap_or(String::from("attachments")),
+        attachment_max_kb: value
+            .get("attachment_max_kb")
+            .map(|v| v.as_integer().unwrap() as u64)
+            .unwrap_or(5120),
+        attachment_types: value
+            .get("attachment_types")
+            .map(|v| {
+                v.as_slice()
+                    .unwrap()
+                    .iter()
+                    .map(|t| t.as_str().unwrap().to_lowercase())
+                    .collect()
+            })
+            .unwrap_or(default_attachment_types()),
     })
 }
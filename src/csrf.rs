@@ -0,0 +1,251 @@
+use crypto::util::fixed_time_eq;
+use rand::distributions::Alphanumeric;
+use rand::{thread_rng, Rng};
+use rocket::data::{self, Data, FromData};
+use rocket::form::{self, DataField, Form, FromForm, Options, ValueField};
+use rocket::http::{Cookie, Status};
+use rocket::outcome::Outcome;
+use rocket::request::{self, FromRequest, Request};
+use std::ops::{Deref, DerefMut};
+
+const CSRF_COOKIE: &str = "csrf_token";
+const CSRF_FIELD: &str = "csrf_token";
+
+/// Token for the current request, cached so repeated guard invocations agree.
+struct CachedToken(String);
+
+/// Per-session anti-forgery token. Handlers that render a form take this guard and pass the
+/// token into the template context as `csrf_token`.
+pub(crate) struct CsrfToken(pub String);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for CsrfToken {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
+        let token = request.local_cache(|| {
+            CachedToken(match request.cookies().get(CSRF_COOKIE) {
+                Some(cookie) => cookie.value().to_string(),
+                None => {
+                    let token: String = thread_rng()
+                        .sample_iter(&Alphanumeric)
+                        .take(32)
+                        .map(char::from)
+                        .collect();
+                    let cookie = Cookie::build(CSRF_COOKIE, token.clone())
+                        .path("/")
+                        .http_only(true)
+                        .finish();
+                    request.cookies().add(cookie);
+                    token
+                }
+            })
+        });
+        Outcome::Success(CsrfToken(token.0.clone()))
+    }
+}
+
+/// Form `T` plus the `csrf_token` field, which `T` itself never sees.
+struct CsrfFields<T> {
+    token: Option<String>,
+    inner: T,
+}
+
+struct CsrfFieldsContext<'v, T: FromForm<'v>> {
+    token: Option<String>,
+    inner: T::Context,
+}
+
+#[rocket::async_trait]
+impl<'v, T: FromForm<'v>> FromForm<'v> for CsrfFields<T> {
+    type Context = CsrfFieldsContext<'v, T>;
+
+    fn init(opts: Options) -> Self::Context {
+        CsrfFieldsContext {
+            token: None,
+            inner: T::init(opts),
+        }
+    }
+
+    fn push_value(ctxt: &mut Self::Context, field: ValueField<'v>) {
+        if field.name == CSRF_FIELD {
+            ctxt.token = Some(field.value.to_string());
+        } else {
+            T::push_value(&mut ctxt.inner, field);
+        }
+    }
+
+    async fn push_data(ctxt: &mut Self::Context, field: DataField<'v, '_>) {
+        T::push_data(&mut ctxt.inner, field).await
+    }
+
+    fn push_error(ctxt: &mut Self::Context, error: form::Error<'v>) {
+        T::push_error(&mut ctxt.inner, error)
+    }
+
+    fn finalize(ctxt: Self::Context) -> form::Result<'v, Self> {
+        Ok(CsrfFields {
+            token: ctxt.token,
+            inner: T::finalize(ctxt.inner)?,
+        })
+    }
+}
+
+/// A form without fields of its own, for POSTs that only need the CSRF check.
+pub(crate) struct NoFields;
+
+#[rocket::async_trait]
+impl<'v> FromForm<'v> for NoFields {
+    type Context = ();
+
+    fn init(_opts: Options) -> Self::Context {}
+
+    fn push_value(_ctxt: &mut Self::Context, _field: ValueField<'v>) {}
+
+    async fn push_data(_ctxt: &mut Self::Context, _field: DataField<'v, '_>) {}
+
+    fn finalize(_ctxt: Self::Context) -> form::Result<'v, Self> {
+        Ok(NoFields)
+    }
+}
+
+/// Drop-in replacement for `Form<T>` on state-changing routes: the submitted `csrf_token` field
+/// must match the session's token cookie, otherwise the request fails with 403 Forbidden.
+pub(crate) struct CsrfForm<T>(T);
+
+impl<T> Deref for CsrfForm<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.0
+    }
+}
+
+impl<T> DerefMut for CsrfForm<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.0
+    }
+}
+
+#[rocket::async_trait]
+impl<'r, T: FromForm<'r>> FromData<'r> for CsrfForm<T> {
+    type Error = form::Errors<'r>;
+
+    async fn from_data(req: &'r Request<'_>, data: Data<'r>) -> data::Outcome<'r, Self> {
+        let expected = req
+            .cookies()
+            .get(CSRF_COOKIE)
+            .map(|cookie| cookie.value().to_string());
+        // bearer-authenticated requests never rely on cookies, so they cannot be forged
+        let bearer = req
+            .headers()
+            .get_one("Authorization")
+            .map(|a| a.starts_with("Bearer "))
+            .unwrap_or(false);
+
+        match Form::<CsrfFields<T>>::from_data(req, data).await {
+            Outcome::Success(form) => {
+                let fields = form.into_inner();
+                match (expected, fields.token) {
+                    _ if bearer => Outcome::Success(CsrfForm(fields.inner)),
+                    // compare in constant time, so the token cannot be guessed byte by byte
+                    (Some(ref e), Some(ref t))
+                        if !e.is_empty() && fixed_time_eq(e.as_bytes(), t.as_bytes()) =>
+                    {
+                        Outcome::Success(CsrfForm(fields.inner))
+                    }
+                    _ => Outcome::Failure((
+                        Status::Forbidden,
+                        form::Error::validation("invalid CSRF token").into(),
+                    )),
+                }
+            }
+            Outcome::Failure(f) => Outcome::Failure(f),
+            Outcome::Forward(d) => Outcome::Forward(d),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rocket::http::ContentType;
+    use rocket::local::blocking::Client;
+
+    #[derive(FromForm)]
+    struct Note {
+        text: String,
+    }
+
+    #[get("/form")]
+    fn form(csrf: CsrfToken) -> String {
+        csrf.0
+    }
+
+    #[post("/note", data = "<data>")]
+    fn note(data: CsrfForm<Note>) -> String {
+        data.text.clone()
+    }
+
+    fn client() -> Client {
+        Client::tracked(rocket::build().mount("/", routes![form, note])).unwrap()
+    }
+
+    fn post(client: &Client, body: &str) -> Status {
+        client
+            .post("/note")
+            .header(ContentType::Form)
+            .body(body)
+            .dispatch()
+            .status()
+    }
+
+    #[test]
+    fn rejects_post_without_token() {
+        let client = client();
+        assert_eq!(post(&client, "text=hi"), Status::Forbidden);
+        // having a session token does not help if the form does not carry it
+        let _token = client.get("/form").dispatch().into_string().unwrap();
+        assert_eq!(post(&client, "text=hi"), Status::Forbidden);
+    }
+
+    #[test]
+    fn rejects_mismatched_token() {
+        let client = client();
+        let token = client.get("/form").dispatch().into_string().unwrap();
+        let other = format!("{}x", &token[1..]);
+        assert_eq!(
+            post(&client, &format!("text=hi&csrf_token={}", other)),
+            Status::Forbidden
+        );
+        assert_eq!(post(&client, "text=hi&csrf_token="), Status::Forbidden);
+    }
+
+    #[test]
+    fn rejects_token_of_another_session() {
+        let client = client();
+        let token = client.get("/form").dispatch().into_string().unwrap();
+        let response = client
+            .post("/note")
+            .header(ContentType::Form)
+            .body(format!("text=hi&csrf_token={}", token))
+            .cookie(Cookie::new(CSRF_COOKIE, "something else"))
+            .dispatch();
+        assert_eq!(response.status(), Status::Forbidden);
+    }
+
+    #[test]
+    fn accepts_matching_token() {
+        let client = client();
+        let token = client.get("/form").dispatch().into_string().unwrap();
+        // the same token is issued for the rest of the session
+        assert_eq!(client.get("/form").dispatch().into_string().unwrap(), token);
+        let response = client
+            .post("/note")
+            .header(ContentType::Form)
+            .body(format!("text=hi&csrf_token={}", token))
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(response.into_string().unwrap(), "hi");
+    }
+}
@@ -0,0 +1,183 @@
+use crate::backend::MySqlBackend;
+use crate::config::Config;
+use crate::extensions;
+use chrono::naive::NaiveDateTime;
+use chrono::{Duration, Local, TimeZone};
+use mysql::from_value;
+
+/// Format of `<input type="datetime-local">` values.
+const INPUT_FORMAT: &str = "%Y-%m-%dT%H:%M";
+
+/// A lecture's deadlines. Like `answers.submitted_at`, they are stored in the server's local
+/// time; they are entered and shown in the configured course timezone.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Deadline {
+    /// Answers submitted after this are late
+    pub due_at: Option<NaiveDateTime>,
+    /// No submissions are accepted after this
+    pub lock_at: Option<NaiveDateTime>,
+    /// Whether `due_at` is a per-student extension
+    pub extended: bool,
+}
+
+impl Deadline {
+    pub(crate) fn load(bg: &mut MySqlBackend, lec: u64) -> Deadline {
+        let res = bg.prep_exec(
+            "SELECT due_at, lock_at FROM lectures WHERE id = ?",
+            vec![lec.into()],
+        );
+        match res.first() {
+            Some(r) => Deadline {
+                due_at: from_value(r[0].clone()),
+                lock_at: from_value(r[1].clone()),
+                extended: false,
+            },
+            None => Deadline::default(),
+        }
+    }
+
+    /// The deadline that applies to `email`, taking their extension into account.
+    pub(crate) fn for_student(bg: &mut MySqlBackend, lec: u64, email: &str) -> Deadline {
+        let extension = extensions::lookup(bg, email, lec);
+        Deadline::load(bg, lec).extend(extension)
+    }
+
+    /// Applies a per-student extension: its deadline replaces the due date, and a lock before it
+    /// moves to it, so the student can still submit until their own deadline.
+    pub(crate) fn extend(&self, extension: Option<NaiveDateTime>) -> Deadline {
+        match extension {
+            Some(due) => Deadline {
+                due_at: Some(due),
+                lock_at: self.lock_at.map(|l| l.max(due)),
+                extended: true,
+            },
+            None => self.clone(),
+        }
+    }
+
+    pub(crate) fn is_late(&self, submitted_at: NaiveDateTime) -> bool {
+        self.due_at.map(|d| submitted_at > d).unwrap_or(false)
+    }
+
+    pub(crate) fn is_locked(&self, now: NaiveDateTime) -> bool {
+        self.lock_at.map(|l| now > l).unwrap_or(false)
+    }
+
+    /// Percentage taken off a grade for an answer submitted at `submitted_at`: that of the
+    /// strictest configured rule the lateness reaches.
+    pub(crate) fn late_penalty(&self, config: &Config, submitted_at: NaiveDateTime) -> u64 {
+        match self.due_at {
+            Some(due) if submitted_at > due => {
+                let hours_late = (submitted_at - due).num_hours() as u64;
+                config
+                    .late_penalties
+                    .iter()
+                    .filter(|p| hours_late >= p.hours)
+                    .map(|p| p.percent)
+                    .max()
+                    .unwrap_or(0)
+                    .min(100)
+            }
+            _ => 0,
+        }
+    }
+}
+
+/// A lecture's deadline as shown to students.
+#[derive(Serialize)]
+pub(crate) struct DeadlineStatus {
+    due: Option<String>,
+    extended: bool,
+    countdown: Option<String>,
+    /// Past the due date; answers are still accepted but flagged as late
+    late: bool,
+    locks: Option<String>,
+    locked: bool,
+}
+
+impl Deadline {
+    pub(crate) fn status(&self, config: &Config, now: NaiveDateTime) -> DeadlineStatus {
+        DeadlineStatus {
+            due: self.due_at.map(|d| display(config, d)),
+            extended: self.extended,
+            countdown: self.due_at.and_then(|d| countdown(now, d)),
+            late: self.is_late(now),
+            locks: self.lock_at.map(|l| display(config, l)),
+            locked: self.is_locked(now),
+        }
+    }
+}
+
+pub(crate) fn now() -> NaiveDateTime {
+    Local::now().naive_local()
+}
+
+pub(crate) fn apply_penalty(grade: u64, percent: u64) -> u64 {
+    grade * (100 - percent.min(100)) / 100
+}
+
+/// Parses a `datetime-local` input in the course timezone. Empty input means no deadline.
+pub(crate) fn parse_input(config: &Config, input: &str) -> Result<Option<NaiveDateTime>, ()> {
+    let input = input.trim();
+    if input.is_empty() {
+        return Ok(None);
+    }
+    let t = NaiveDateTime::parse_from_str(input, INPUT_FORMAT).map_err(|_| ())?;
+    match config.timezone {
+        // times skipped by a DST change do not exist; ambiguous ones take the earlier instant
+        Some(tz) => match tz.from_local_datetime(&t).earliest() {
+            Some(t) => Ok(Some(t.with_timezone(&Local).naive_local())),
+            None => Err(()),
+        },
+        None => Ok(Some(t)),
+    }
+}
+
+/// The value of a `datetime-local` input showing `t` in the course timezone.
+pub(crate) fn to_input(config: &Config, t: Option<NaiveDateTime>) -> String {
+    match (t, config.timezone) {
+        (None, _) => String::new(),
+        (Some(t), Some(tz)) => match Local.from_local_datetime(&t).earliest() {
+            Some(l) => l.with_timezone(&tz).format(INPUT_FORMAT).to_string(),
+            None => t.format(INPUT_FORMAT).to_string(),
+        },
+        (Some(t), None) => t.format(INPUT_FORMAT).to_string(),
+    }
+}
+
+/// Shows `t` in the course timezone, with the zone.
+pub(crate) fn display(config: &Config, t: NaiveDateTime) -> String {
+    let local = match Local.from_local_datetime(&t).earliest() {
+        Some(l) => l,
+        None => return t.format("%Y-%m-%d %H:%M").to_string(),
+    };
+    match config.timezone {
+        Some(tz) => local
+            .with_timezone(&tz)
+            .format("%Y-%m-%d %H:%M %Z")
+            .to_string(),
+        None => local.format("%Y-%m-%d %H:%M (UTC%:z)").to_string(),
+    }
+}
+
+/// Time remaining until `until`, e.g., "2 days, 3 hours left"; `None` once it has passed.
+pub(crate) fn countdown(now: NaiveDateTime, until: NaiveDateTime) -> Option<String> {
+    let left = until - now;
+    if left <= Duration::zero() {
+        return None;
+    }
+    let plural = |n: i64, unit: &str| format!("{} {}{}", n, unit, if n == 1 { "" } else { "s" });
+    let (days, hours, minutes) = (left.num_days(), left.num_hours() % 24, left.num_minutes() % 60);
+    Some(if days > 0 {
+        format!("{}, {} left", plural(days, "day"), plural(hours, "hour"))
+    } else if hours > 0 {
+        format!("{}, {} left", plural(hours, "hour"), plural(minutes, "minute"))
+    } else {
+        format!("{} left", plural(minutes.max(1), "minute"))
+    })
+}
+
+/// Name of the timezone deadlines are entered in, for forms.
+pub(crate) fn zone_name(config: &Config) -> &'static str {
+    config.timezone.map(|tz| tz.name()).unwrap_or("server local time")
+}
@@ -0,0 +1,261 @@
+use crate::admin::Admin;
+use crate::apikey::ApiKey;
+use crate::attachments;
+use crate::backend::MySqlBackend;
+use crate::config::Config;
+use crate::csrf::{CsrfForm, CsrfToken, NoFields};
+use crate::email;
+use crate::impersonate;
+use crate::login::hash_token;
+use crate::questions::MODEL_PATH;
+use chrono::Local;
+use mysql::from_value;
+use rand::distributions::Alphanumeric;
+use rand::{thread_rng, Rng};
+use rocket::http::{Cookie, CookieJar, Status};
+use rocket::State;
+use rocket_dyn_templates::Template;
+use std::collections::HashMap;
+use std::io::ErrorKind;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+#[derive(Debug, FromForm)]
+pub(crate) struct DeleteUserForm {
+    email: String,
+}
+
+#[derive(Serialize)]
+pub(crate) struct DeletionReceipt {
+    receipt_id: String,
+    deleted_at: String,
+    answers_removed: u64,
+    answers_anonymized: u64,
+    tokens_revoked: u64,
+    model_purged: bool,
+}
+
+#[derive(Serialize)]
+struct DeletionReceiptContext {
+    receipt: DeletionReceipt,
+    self_service: bool,
+    parent: &'static str,
+}
+
+fn random_id(len: usize) -> String {
+    thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(len)
+        .map(char::from)
+        .collect()
+}
+
+fn count(bg: &mut MySqlBackend, sql: &str, email: &str) -> u64 {
+    let res = bg.prep_exec(sql, vec![email.into()]);
+    from_value(res[0][0].clone())
+}
+
+/// Removes a user and everything tied to them. Answers are either deleted or, if configured,
+/// kept under a fresh anonymous ID so that aggregates stay stable. Fails with `NotFound` if there
+/// is no such user.
+pub(crate) fn delete_user(
+    bg: &mut MySqlBackend,
+    config: &Config,
+    email: &str,
+    requested_by: &str,
+) -> Result<DeletionReceipt, Status> {
+    if count(bg, "SELECT COUNT(*) FROM users WHERE email = ?", email) == 0 {
+        return Err(Status::NotFound);
+    }
+
+    let num_answers = count(bg, "SELECT COUNT(*) FROM answers WHERE email = ?", email);
+    // the grade prediction model was trained on this user's answers; it is retrained on demand.
+    // It goes first, so that nothing is deleted if it cannot be.
+    let model_purged = num_answers > 0
+        && match std::fs::remove_file(Path::new(MODEL_PATH)) {
+            Ok(()) => true,
+            Err(e) if e.kind() == ErrorKind::NotFound => false,
+            Err(e) => {
+                error!(bg.log, "failed to remove cached model: {}", e);
+                return Err(Status::InternalServerError);
+            }
+        };
+
+    let (answers_removed, answers_anonymized) = if config.anonymize_deleted_answers {
+        let anon_id = format!("deleted-{}", random_id(12));
+        bg.prep_exec(
+            "UPDATE answers SET email = ? WHERE email = ?",
+            vec![anon_id.as_str().into(), email.into()],
+        );
+        bg.prep_exec(
+            "UPDATE answer_versions SET email = ? WHERE email = ?",
+            vec![anon_id.into(), email.into()],
+        );
+        (0, num_answers)
+    } else {
+        bg.prep_exec("DELETE FROM answers WHERE email = ?", vec![email.into()]);
+        bg.prep_exec("DELETE FROM answer_versions WHERE email = ?", vec![email.into()]);
+        (num_answers, 0)
+    };
+
+    // invalidate every way of acting as this user
+    let tokens_revoked = count(bg, "SELECT COUNT(*) FROM api_tokens WHERE email = ?", email);
+    bg.prep_exec("DELETE FROM api_tokens WHERE email = ?", vec![email.into()]);
+    bg.prep_exec("DELETE FROM login_tokens WHERE email = ?", vec![email.into()]);
+    bg.prep_exec(
+        "UPDATE impersonations SET student = 'deleted user' WHERE student = ?",
+        vec![email.into()],
+    );
+    bg.prep_exec(
+        "DELETE FROM pseudonym_history WHERE email = ?",
+        vec![email.into()],
+    );
+    bg.prep_exec("DELETE FROM extensions WHERE email = ?", vec![email.into()]);
+    bg.prep_exec("DELETE FROM drafts WHERE email = ?", vec![email.into()]);
+    // roster entries hold the student's name; they are compared in lowercase, as on import
+    bg.prep_exec(
+        "DELETE FROM enrollment WHERE LOWER(email) = ?",
+        vec![email.to_lowercase().into()],
+    );
+    // uploaded files may identify the student, so they go even if answers are anonymized
+    attachments::delete_where(bg, config, "email = ?", vec![email.into()]);
+    bg.prep_exec("DELETE FROM users WHERE email = ?", vec![email.into()]);
+
+    let now = Local::now().naive_local();
+    let receipt = DeletionReceipt {
+        receipt_id: random_id(16),
+        deleted_at: now.format("%Y-%m-%d %H:%M:%S").to_string(),
+        answers_removed,
+        answers_anonymized,
+        tokens_revoked,
+        model_purged,
+    };
+    // only a keyed hash of the email is kept, so the receipt can be verified on request without
+    // us retaining the address
+    bg.insert(
+        "deletion_receipts",
+        vec![
+            receipt.receipt_id.as_str().into(),
+            hash_token(email, &config.secret).into(),
+            requested_by.into(),
+            now.into(),
+            answers_removed.into(),
+            answers_anonymized.into(),
+        ],
+    );
+
+    Ok(receipt)
+}
+
+/// Emails the receipt to the deleted user. The account is gone either way, so a failure is only
+/// logged.
+fn send_receipt(log: slog::Logger, config: &Config, email: &str, receipt: &DeletionReceipt) {
+    if !config.send_emails {
+        return;
+    }
+    let sent = email::send(
+        log.clone(),
+        config,
+        "no-reply@csci2390-submit.cs.brown.edu".into(),
+        vec![email.to_string()],
+        format!("{} account deleted", config.class),
+        format!(
+            "Your {} account has been deleted.\nReceipt: {}\nAnswers removed: {}\n\
+             Answers kept anonymously: {}\n",
+            config.class, receipt.receipt_id, receipt.answers_removed, receipt.answers_anonymized
+        ),
+    );
+    if let Err(e) = sent {
+        warn!(log, "failed to send deletion receipt to {}: {}", email, e);
+    }
+}
+
+#[get("/delete")]
+pub(crate) fn delete_confirm(
+    apikey: ApiKey,
+    csrf: CsrfToken,
+    config: &State<Config>,
+) -> Template {
+    let mut ctx = HashMap::new();
+    ctx.insert("email", apikey.user.clone());
+    if config.anonymize_deleted_answers {
+        ctx.insert("anonymize", String::from("true"));
+    }
+    ctx.insert("csrf_token", csrf.0);
+    if let Some(student) = impersonate::viewing_as(&apikey) {
+        ctx.insert("impersonating", student);
+    }
+    ctx.insert("parent", String::from("layout"));
+    Template::render("deletion/confirm", &ctx)
+}
+
+#[post("/delete", data = "<_data>")]
+pub(crate) fn delete_self(
+    apikey: ApiKey,
+    _data: CsrfForm<NoFields>,
+    cookies: &CookieJar<'_>,
+    backend: &State<Arc<Mutex<MySqlBackend>>>,
+    config: &State<Config>,
+) -> Result<Template, Status> {
+    let mut bg = backend.lock().unwrap();
+    let receipt = delete_user(&mut bg, config, &apikey.user, "self")?;
+    let log = bg.log.clone();
+    drop(bg);
+    send_receipt(log, config, &apikey.user, &receipt);
+
+    cookies.remove(Cookie::named("apikey"));
+    let ctx = DeletionReceiptContext {
+        receipt,
+        self_service: true,
+        parent: "layout",
+    };
+    Ok(Template::render("deletion/receipt", &ctx))
+}
+
+#[get("/<email>/delete")]
+pub(crate) fn delete_by_admin_confirm(
+    _adm: Admin,
+    email: String,
+    csrf: CsrfToken,
+    backend: &State<Arc<Mutex<MySqlBackend>>>,
+    config: &State<Config>,
+) -> Result<Template, Status> {
+    let mut bg = backend.lock().unwrap();
+    let exists = count(&mut bg, "SELECT COUNT(*) FROM users WHERE email = ?", &email) > 0;
+    drop(bg);
+    if !exists {
+        return Err(Status::NotFound);
+    }
+
+    let mut ctx = HashMap::new();
+    ctx.insert("email", email);
+    ctx.insert("admin", String::from("true"));
+    if config.anonymize_deleted_answers {
+        ctx.insert("anonymize", String::from("true"));
+    }
+    ctx.insert("csrf_token", csrf.0);
+    ctx.insert("parent", String::from("layout"));
+    Ok(Template::render("deletion/confirm", &ctx))
+}
+
+#[post("/delete", data = "<data>")]
+pub(crate) fn delete_by_admin(
+    _adm: Admin,
+    apikey: ApiKey,
+    data: CsrfForm<DeleteUserForm>,
+    backend: &State<Arc<Mutex<MySqlBackend>>>,
+    config: &State<Config>,
+) -> Result<Template, Status> {
+    let mut bg = backend.lock().unwrap();
+    let receipt = delete_user(&mut bg, config, &data.email, &apikey.user)?;
+    let log = bg.log.clone();
+    drop(bg);
+    send_receipt(log, config, &data.email, &receipt);
+
+    let ctx = DeletionReceiptContext {
+        receipt,
+        self_service: false,
+        parent: "layout",
+    };
+    Ok(Template::render("deletion/receipt", &ctx))
+}
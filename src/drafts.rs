@@ -0,0 +1,111 @@
+use crate::apikey::ApiKey;
+use crate::backend::MySqlBackend;
+use crate::csrf::CsrfForm;
+use crate::deadline::{self, Deadline};
+use crate::history;
+use crate::qtype::{QuestionSpec, QuestionType};
+use crate::questions::LectureQuestionSubmission;
+use crate::visibility::LectureState;
+use chrono::naive::NaiveDateTime;
+use mysql::from_value;
+use rocket::http::{ContentType, Status};
+use rocket::State;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+#[derive(Serialize)]
+struct DraftSaved {
+    /// Number of questions with a draft after this save
+    drafts: usize,
+    saved_at: String,
+}
+
+/// A student's unsubmitted drafts for a lecture, with the time each was saved.
+pub(crate) fn load(
+    bg: &mut MySqlBackend,
+    email: &str,
+    lec: u64,
+) -> HashMap<u64, (String, NaiveDateTime)> {
+    bg.prep_exec(
+        "SELECT q, answer, saved_at FROM drafts WHERE email = ? AND lec = ?",
+        vec![email.into(), lec.into()],
+    )
+    .into_iter()
+    .map(|r| {
+        (
+            from_value(r[0].clone()),
+            (from_value(r[1].clone()), from_value(r[2].clone())),
+        )
+    })
+    .collect()
+}
+
+/// Drops a student's drafts for a lecture, once they have submitted it.
+pub(crate) fn clear(bg: &mut MySqlBackend, email: &str, lec: u64) {
+    bg.prep_exec(
+        "DELETE FROM drafts WHERE email = ? AND lec = ?",
+        vec![email.into(), lec.into()],
+    );
+}
+
+/// Saves the questions form as drafts, without validating, grading or emailing anything. The
+/// page calls this periodically; answers identical to the submitted ones need no draft.
+#[post("/<lec>/draft", data = "<data>")]
+pub(crate) fn save_draft(
+    apikey: ApiKey,
+    lec: u64,
+    data: CsrfForm<LectureQuestionSubmission>,
+    backend: &State<Arc<Mutex<MySqlBackend>>>,
+) -> Result<(ContentType, String), Status> {
+    let now = deadline::now();
+    let mut bg = backend.lock().unwrap();
+    match LectureState::load(&mut bg, lec) {
+        Some(s) if s.accepts_answers(now) => (),
+        _ => return Err(Status::Forbidden),
+    }
+    if Deadline::for_student(&mut bg, lec, &apikey.user).is_locked(now) {
+        return Err(Status::Forbidden);
+    }
+    let res = bg.prep_exec("SELECT * FROM questions WHERE lec = ?", vec![lec.into()]);
+    let submitted = history::latest_versions(&mut bg, &apikey.user, lec);
+
+    for r in res {
+        let q: u64 = from_value(r[1].clone());
+        // unchecking every box of a multiple choice question sends no field at all
+        let text = if QuestionSpec::from_row(&r).qtype == QuestionType::MultipleChoice {
+            data.choices.get(&q).map(|s| s.join("\n")).unwrap_or_default()
+        } else {
+            match data.answers.get(&q) {
+                Some(answer) => answer.clone(),
+                None => continue,
+            }
+        };
+        // a draft that matches the submitted answer (or an empty one, if nothing was submitted)
+        // is no draft
+        if submitted.get(&q).map(|s| s.0.as_str()).unwrap_or("") == text {
+            bg.prep_exec(
+                "DELETE FROM drafts WHERE email = ? AND lec = ? AND q = ?",
+                vec![apikey.user.as_str().into(), lec.into(), q.into()],
+            );
+        } else {
+            bg.replace(
+                "drafts",
+                vec![
+                    apikey.user.as_str().into(),
+                    lec.into(),
+                    q.into(),
+                    text.into(),
+                    now.into(),
+                ],
+            );
+        }
+    }
+    let drafts = load(&mut bg, &apikey.user, lec).len();
+    drop(bg);
+
+    let saved = DraftSaved {
+        drafts,
+        saved_at: now.format("%H:%M:%S").to_string(),
+    };
+    Ok((ContentType::JSON, serde_json::to_string(&saved).unwrap()))
+}
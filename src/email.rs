@@ -1,29 +1,54 @@
+use crate::config::Config;
+use lettre::file::FileTransport;
 use lettre::sendmail::SendmailTransport;
 use lettre::Transport;
 use lettre_email::Email;
+use std::fmt;
+
+#[derive(Debug)]
+pub(crate) enum EmailError {
+    Sendmail(lettre::sendmail::error::Error),
+    File(lettre::file::error::Error),
+}
+
+impl fmt::Display for EmailError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EmailError::Sendmail(e) => write!(f, "sendmail failed: {}", e),
+            EmailError::File(e) => write!(f, "writing email file failed: {}", e),
+        }
+    }
+}
 
 pub(crate) fn send(
     log: slog::Logger,
+    config: &Config,
     sender: String,
     recipients: Vec<String>,
     subject: String,
     text: String,
-) -> Result<(), lettre::sendmail::error::Error> {
-    let mut mailer = SendmailTransport::new();
-
+) -> Result<(), EmailError> {
     let mut builder = Email::builder()
         .from(sender.clone())
         .subject(subject.clone())
         .text(text.clone());
+    // the text is not logged, since it may contain API keys or login links
+    debug!(log, "Email to {:?}: Subject {}", recipients, subject);
     for recipient in recipients {
         builder = builder.to(recipient);
     }
 
-    debug!(log, "Email: Subject {}\nText: {}!", subject, text);
-
     let email = builder.build();
     match email {
-        Ok(result) => mailer.send(result.into())?,
+        Ok(result) => match config.email_dir {
+            // write emails to disk instead of sending them (e.g., for testing)
+            Some(ref dir) => FileTransport::new(dir)
+                .send(result.into())
+                .map_err(EmailError::File)?,
+            None => SendmailTransport::new()
+                .send(result.into())
+                .map_err(EmailError::Sendmail)?,
+        },
         Err(e) => {
             println!("couldn't construct email: {}", e);
         }
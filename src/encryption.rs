@@ -0,0 +1,256 @@
+use crate::backend::{MySqlBackend, Value};
+use crypto::aead::{AeadDecryptor, AeadEncryptor};
+use crypto::aes::KeySize;
+use crypto::aes_gcm::AesGcm;
+use crypto::hkdf::{hkdf_expand, hkdf_extract};
+use crypto::sha2::Sha256;
+use mysql::from_value;
+use rand::{thread_rng, RngCore};
+use std::io::{Error, ErrorKind};
+
+/// Marks a stored value as encrypted; followed by `<key id>:<base64(nonce | ciphertext | tag)>`.
+const PREFIX: &str = "enc:v1:";
+const NONCE_LEN: usize = 12;
+const TAG_LEN: usize = 16;
+
+/// Keys for encrypting configured columns at rest (AES-256-GCM).
+///
+/// The key file holds one key per line as `<id> <base64 secret>`; `#` starts a comment. The
+/// first key encrypts new values, and all keys can decrypt, so a key is rotated by adding a new
+/// first line and running `websubmit --reencrypt`, after which the old line can be removed.
+pub struct Keyring {
+    keys: Vec<(String, Vec<u8>)>,
+    columns: Vec<(String, String)>,
+}
+
+impl Keyring {
+    pub fn load(path: &str, columns: &[String]) -> Result<Keyring, Error> {
+        let contents = std::fs::read_to_string(path)?;
+        let mut keys = vec![];
+        for line in contents.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let mut parts = line.split_whitespace();
+            let (id, secret) = match (parts.next(), parts.next()) {
+                (Some(id), Some(secret)) if !id.contains(':') => (id, secret),
+                _ => {
+                    return Err(Error::new(
+                        ErrorKind::InvalidData,
+                        format!("malformed key line in {}", path),
+                    ))
+                }
+            };
+            let secret = base64::decode(secret)
+                .map_err(|e| Error::new(ErrorKind::InvalidData, e.to_string()))?;
+            if secret.len() < 32 {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!("key {} is shorter than 32 bytes", id),
+                ));
+            }
+            keys.push((id.to_string(), secret));
+        }
+        if keys.is_empty() {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("no keys in {}", path),
+            ));
+        }
+
+        let columns = columns
+            .iter()
+            .map(|c| {
+                let mut parts = c.splitn(2, '.');
+                match (parts.next(), parts.next()) {
+                    (Some(t), Some(col)) => Ok((t.to_string(), col.to_string())),
+                    _ => Err(Error::new(
+                        ErrorKind::InvalidData,
+                        format!("encrypted column {} is not of the form table.column", c),
+                    )),
+                }
+            })
+            .collect::<Result<_, _>>()?;
+
+        Ok(Keyring { keys, columns })
+    }
+
+    pub fn is_encrypted(&self, table: &str, column: &str) -> bool {
+        self.columns
+            .iter()
+            .any(|(t, c)| t.eq_ignore_ascii_case(table) && c.eq_ignore_ascii_case(column))
+    }
+
+    pub fn columns(&self) -> &[(String, String)] {
+        &self.columns
+    }
+
+    /// Each column gets its own key, so ciphertexts cannot be moved between columns.
+    fn column_key(&self, secret: &[u8], table: &str, column: &str) -> [u8; 32] {
+        let mut prk = [0u8; 32];
+        hkdf_extract(Sha256::new(), b"websubmit encryption at rest", secret, &mut prk);
+        let mut key = [0u8; 32];
+        let info = format!("{}.{}", table.to_lowercase(), column.to_lowercase());
+        hkdf_expand(Sha256::new(), &prk, info.as_bytes(), &mut key);
+        key
+    }
+
+    pub fn encrypt(&self, table: &str, column: &str, plaintext: &[u8]) -> String {
+        let (id, secret) = &self.keys[0];
+        let key = self.column_key(secret, table, column);
+        let mut nonce = [0u8; NONCE_LEN];
+        thread_rng().fill_bytes(&mut nonce);
+
+        let mut sealed = vec![0u8; NONCE_LEN + plaintext.len() + TAG_LEN];
+        sealed[..NONCE_LEN].copy_from_slice(&nonce);
+        let (ct, tag) = sealed[NONCE_LEN..].split_at_mut(plaintext.len());
+        AesGcm::new(KeySize::KeySize256, &key, &nonce, &[]).encrypt(plaintext, ct, tag);
+        format!("{}{}:{}", PREFIX, id, base64::encode(&sealed))
+    }
+
+    /// Returns `None` for values that are not encrypted (e.g., written before encryption was
+    /// enabled), and an error if a value is encrypted but cannot be decrypted, as that means a
+    /// missing key or tampering.
+    pub fn decrypt(
+        &self,
+        table: &str,
+        column: &str,
+        stored: &[u8],
+    ) -> Result<Option<Vec<u8>>, Error> {
+        let invalid = |msg: String| Err(Error::new(ErrorKind::InvalidData, msg));
+        let rest = match std::str::from_utf8(stored).map(|s| s.strip_prefix(PREFIX)) {
+            Ok(Some(rest)) => rest,
+            _ => return Ok(None),
+        };
+        let (id, data) = match rest.find(':') {
+            Some(i) => rest.split_at(i),
+            None => return invalid(format!("malformed encrypted value in {}.{}", table, column)),
+        };
+        let secret = match self.keys.iter().find(|(k, _)| k == id) {
+            Some((_, s)) => s,
+            None => return invalid(format!("no key {} to decrypt {}.{}", id, table, column)),
+        };
+        let sealed = match base64::decode(&data[1..]) {
+            Ok(sealed) if sealed.len() >= NONCE_LEN + TAG_LEN => sealed,
+            _ => return invalid(format!("malformed encrypted value in {}.{}", table, column)),
+        };
+
+        let key = self.column_key(secret, table, column);
+        let (nonce, rest) = sealed.split_at(NONCE_LEN);
+        let (ct, tag) = rest.split_at(rest.len() - TAG_LEN);
+        let mut plaintext = vec![0u8; ct.len()];
+        if !AesGcm::new(KeySize::KeySize256, &key, nonce, &[]).decrypt(ct, &mut plaintext, tag) {
+            return invalid(format!(
+                "failed to authenticate encrypted value in {}.{}",
+                table, column
+            ));
+        }
+        Ok(Some(plaintext))
+    }
+
+    /// Whether a stored value should be rewritten: plaintext, or encrypted with an old key.
+    fn needs_reencryption(&self, stored: &[u8]) -> bool {
+        let current = format!("{}{}:", PREFIX, self.keys[0].0);
+        !stored.starts_with(current.as_bytes())
+    }
+
+    /// Encrypts a value for storage in `table.column`, if that column is encrypted.
+    pub fn seal(&self, table: &str, column: &str, value: Value) -> Value {
+        match value {
+            Value::Bytes(b) if self.is_encrypted(table, column) => {
+                self.encrypt(table, column, &b).into()
+            }
+            v => v,
+        }
+    }
+}
+
+/// Encrypts all plaintext values in encrypted columns and re-encrypts values under old keys with
+/// the current key. Returns the number of values rewritten.
+pub fn reencrypt(bg: &mut MySqlBackend) -> u64 {
+    let columns = match &bg.keyring {
+        Some(k) => k.columns().to_vec(),
+        None => panic!("no encryption_key_file configured"),
+    };
+
+    let mut rewritten = 0;
+    for (table, column) in columns {
+        // read the stored values themselves, not their decryptions
+        let stored = bg.prep_exec_raw(
+            &format!(
+                "SELECT `{}` FROM `{}` WHERE `{}` IS NOT NULL",
+                column, table, column
+            ),
+            vec![],
+        );
+        for r in stored {
+            let old: Vec<u8> = from_value(r[0].clone());
+            let keyring = bg.keyring.as_ref().unwrap();
+            if !keyring.needs_reencryption(&old) {
+                continue;
+            }
+            let plaintext = match keyring.decrypt(&table, &column, &old) {
+                Ok(p) => p.unwrap_or_else(|| old.clone()),
+                Err(e) => {
+                    // leave it for someone with the right key to look at
+                    warn!(bg.log, "not re-encrypting value: {}", e);
+                    continue;
+                }
+            };
+            let new = keyring.encrypt(&table, &column, &plaintext);
+            // one row at a time, so that equal plaintexts get different nonces
+            bg.prep_exec_raw(
+                &format!(
+                    "UPDATE `{}` SET `{}` = ? WHERE `{}` = ? LIMIT 1",
+                    table, column, column
+                ),
+                vec![new.into(), old.into()],
+            );
+            rewritten += 1;
+        }
+        info!(bg.log, "re-encrypted {}.{}", table, column);
+    }
+    rewritten
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn keyring(keys: &[(&str, u8)]) -> Keyring {
+        Keyring {
+            keys: keys.iter().map(|(id, b)| (id.to_string(), vec![*b; 32])).collect(),
+            columns: vec![("answers".into(), "answer".into())],
+        }
+    }
+
+    #[test]
+    fn round_trips() {
+        let k = keyring(&[("k1", 1)]);
+        let sealed = k.encrypt("answers", "answer", b"42");
+        let opened = k.decrypt("answers", "answer", sealed.as_bytes()).unwrap();
+        assert_eq!(opened, Some(b"42".to_vec()));
+        // plaintext written before encryption was enabled
+        assert_eq!(k.decrypt("answers", "answer", b"42").unwrap(), None);
+    }
+
+    #[test]
+    fn undecryptable_values_are_errors() {
+        let sealed = keyring(&[("old", 1)]).encrypt("answers", "answer", b"42");
+        // the key was rotated away
+        assert!(keyring(&[("new", 2)])
+            .decrypt("answers", "answer", sealed.as_bytes())
+            .is_err());
+        // same id, different secret
+        assert!(keyring(&[("old", 2)])
+            .decrypt("answers", "answer", sealed.as_bytes())
+            .is_err());
+        // moved to another column
+        let k = keyring(&[("old", 1)]);
+        assert!(k.decrypt("drafts", "answer", sealed.as_bytes()).is_err());
+        for bad in &["enc:v1:old", "enc:v1:old:not base64!", "enc:v1:old:AAAA"] {
+            assert!(k.decrypt("answers", "answer", bad.as_bytes()).is_err());
+        }
+    }
+}
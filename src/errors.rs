@@ -0,0 +1,81 @@
+use crate::config::Config;
+use rocket::http::{RawStr, Status};
+use rocket::response::Redirect;
+use rocket::Request;
+use rocket_dyn_templates::Template;
+use std::collections::HashMap;
+
+fn render_error(status: Status, req: &Request<'_>, message: &str) -> Template {
+    let class = req
+        .rocket()
+        .state::<Config>()
+        .map(|c| c.class.clone())
+        .unwrap_or_default();
+
+    let mut ctx = HashMap::new();
+    ctx.insert("CLASS_ID", class);
+    ctx.insert("code", format!("{}", status.code));
+    ctx.insert("reason", String::from(status.reason().unwrap_or("Error")));
+    ctx.insert("message", String::from(message));
+    ctx.insert("parent", String::from("layout"));
+    Template::render("error", &ctx)
+}
+
+/// Browsers are sent to the login page (and back afterwards); programmatic clients that
+/// presented a bearer token get a plain 401.
+#[catch(401)]
+pub(crate) fn unauthorized(req: &Request<'_>) -> Result<Redirect, Template> {
+    if req.headers().contains("Authorization") {
+        return Err(render_error(
+            Status::Unauthorized,
+            req,
+            "Your token is invalid or has been revoked.",
+        ));
+    }
+    let next = req.uri().to_string();
+    Ok(Redirect::to(format!(
+        "/login?next={}",
+        RawStr::new(&next).percent_encode()
+    )))
+}
+
+#[catch(403)]
+pub(crate) fn forbidden(req: &Request<'_>) -> Template {
+    render_error(
+        Status::Forbidden,
+        req,
+        "You do not have permission to access this page.",
+    )
+}
+
+#[catch(404)]
+pub(crate) fn not_found(req: &Request<'_>) -> Template {
+    render_error(Status::NotFound, req, "There is nothing here.")
+}
+
+#[catch(422)]
+pub(crate) fn unprocessable(req: &Request<'_>) -> Template {
+    render_error(
+        Status::UnprocessableEntity,
+        req,
+        "The submitted form was incomplete or invalid.",
+    )
+}
+
+#[catch(429)]
+pub(crate) fn too_many_requests(req: &Request<'_>) -> Template {
+    render_error(
+        Status::TooManyRequests,
+        req,
+        "Too many requests. Please wait a few minutes and try again.",
+    )
+}
+
+#[catch(500)]
+pub(crate) fn internal_error(req: &Request<'_>) -> Template {
+    render_error(
+        Status::InternalServerError,
+        req,
+        "Something went wrong on our end. Please try again later.",
+    )
+}
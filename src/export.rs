@@ -0,0 +1,266 @@
+use crate::apikey::ApiKey;
+use crate::attachments;
+use crate::backend::{MySqlBackend, Value};
+use crate::config::Config;
+use mysql::from_value;
+use rocket::http::{ContentType, Header};
+use rocket::State;
+use serde_json::{Map, Value as JsonValue};
+use std::io::Write;
+use std::sync::{Arc, Mutex};
+use zip::write::FileOptions;
+use zip::ZipWriter;
+
+#[derive(Responder)]
+pub(crate) struct Download {
+    inner: (ContentType, Vec<u8>),
+    disposition: Header<'static>,
+}
+
+impl Download {
+    pub(crate) fn new(content_type: ContentType, filename: &str, data: Vec<u8>) -> Download {
+        Download {
+            inner: (content_type, data),
+            disposition: Header::new("Content-Disposition", content_disposition(filename)),
+        }
+    }
+}
+
+/// Always a download. The name is given twice (RFC 6266): `filename` has a plain ASCII fallback
+/// for old clients, and `filename*` the exact name, percent-encoded as UTF-8 (RFC 5987), so
+/// uploaded names cannot break out of the header.
+fn content_disposition(filename: &str) -> String {
+    let fallback: String = filename
+        .chars()
+        .map(|c| match c {
+            ' '..='~' if c != '"' && c != '\\' => c,
+            _ => '_',
+        })
+        .collect();
+    let mut encoded = String::new();
+    for b in filename.bytes() {
+        match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' => encoded.push(b as char),
+            b'!' | b'#' | b'$' | b'&' | b'+' | b'-' | b'.' | b'^' | b'_' | b'`' | b'|' | b'~' => {
+                encoded.push(b as char)
+            }
+            _ => encoded.push_str(&format!("%{:02X}", b)),
+        }
+    }
+    format!("attachment; filename=\"{}\"; filename*=UTF-8''{}", fallback, encoded)
+}
+
+/// Columns about a user that only staff may see, left out of exports.
+const STAFF_ONLY_COLUMNS: &[(&str, &str)] = &[("extensions", "reason")];
+
+/// One table's rows for the exported user.
+pub(crate) struct ExportTable {
+    pub name: String,
+    pub columns: Vec<String>,
+    pub rows: Vec<Vec<Value>>,
+}
+
+/// Collects every row keyed by `email` from every table in the database. Tables and columns
+/// come from the live schema, so anything added to `schema.sql` is exported automatically.
+pub(crate) fn collect_user_data(bg: &mut MySqlBackend, email: &str) -> Vec<ExportTable> {
+    let tables = bg.prep_exec(
+        "SELECT c.table_name FROM information_schema.columns c \
+         JOIN information_schema.tables t \
+         ON (c.table_schema = t.table_schema AND c.table_name = t.table_name) \
+         WHERE c.table_schema = DATABASE() AND c.column_name = 'email' \
+         AND t.table_type = 'BASE TABLE' ORDER BY c.table_name",
+        vec![],
+    );
+
+    let mut res = vec![];
+    for t in tables {
+        let name: String = from_value(t[0].clone());
+        let columns: Vec<String> = bg
+            .prep_exec(
+                "SELECT column_name FROM information_schema.columns \
+                 WHERE table_schema = DATABASE() AND table_name = ? \
+                 ORDER BY ordinal_position",
+                vec![name.as_str().into()],
+            )
+            .into_iter()
+            .map(|r| from_value::<String>(r[0].clone()))
+            .filter(|c| !STAFF_ONLY_COLUMNS.contains(&(name.as_str(), c.as_str())))
+            .collect();
+        // table names come from information_schema, not from the user
+        let rows = bg.prep_exec(
+            &format!(
+                "SELECT {} FROM {} WHERE email = ?",
+                columns
+                    .iter()
+                    .map(|c| format!("`{}`", c))
+                    .collect::<Vec<_>>()
+                    .join(", "),
+                name
+            ),
+            vec![email.into()],
+        );
+        res.push(ExportTable {
+            name,
+            columns,
+            rows,
+        });
+    }
+    res
+}
+
+fn value_to_string(v: &Value) -> Option<String> {
+    match v {
+        Value::NULL => None,
+        Value::Bytes(b) => Some(String::from_utf8_lossy(b).into_owned()),
+        Value::Int(i) => Some(i.to_string()),
+        Value::UInt(u) => Some(u.to_string()),
+        // dates, times and floats
+        v => Some(v.as_sql(false).trim_matches('\'').to_string()),
+    }
+}
+
+fn value_to_json(v: &Value) -> JsonValue {
+    match v {
+        Value::Int(i) => JsonValue::from(*i),
+        Value::UInt(u) => JsonValue::from(*u),
+        v => match value_to_string(v) {
+            Some(s) => JsonValue::String(s),
+            None => JsonValue::Null,
+        },
+    }
+}
+
+fn csv_field(s: &str) -> String {
+    if s.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", s.replace('"', "\"\""))
+    } else {
+        s.to_string()
+    }
+}
+
+pub(crate) fn to_json(email: &str, tables: &[ExportTable]) -> String {
+    let mut doc = Map::new();
+    doc.insert("email".into(), JsonValue::String(email.to_string()));
+    for t in tables {
+        let rows: Vec<JsonValue> = t
+            .rows
+            .iter()
+            .map(|r| {
+                JsonValue::Object(
+                    t.columns
+                        .iter()
+                        .zip(r.iter())
+                        .map(|(c, v)| (c.clone(), value_to_json(v)))
+                        .collect(),
+                )
+            })
+            .collect();
+        doc.insert(t.name.clone(), JsonValue::Array(rows));
+    }
+    serde_json::to_string_pretty(&JsonValue::Object(doc)).unwrap()
+}
+
+fn to_csv(t: &ExportTable) -> String {
+    let mut out = t
+        .columns
+        .iter()
+        .map(|c| csv_field(c))
+        .collect::<Vec<_>>()
+        .join(",");
+    out.push_str("\r\n");
+    for r in &t.rows {
+        out.push_str(
+            &r.iter()
+                .map(|v| csv_field(&value_to_string(v).unwrap_or_default()))
+                .collect::<Vec<_>>()
+                .join(","),
+        );
+        out.push_str("\r\n");
+    }
+    out
+}
+
+#[get("/export")]
+pub(crate) fn export_json(
+    apikey: ApiKey,
+    backend: &State<Arc<Mutex<MySqlBackend>>>,
+) -> Download {
+    let mut bg = backend.lock().unwrap();
+    let tables = collect_user_data(&mut bg, &apikey.user);
+    drop(bg);
+
+    Download::new(
+        ContentType::JSON,
+        "websubmit-export.json",
+        to_json(&apikey.user, &tables).into_bytes(),
+    )
+}
+
+#[get("/export.zip")]
+pub(crate) fn export_zip(
+    apikey: ApiKey,
+    backend: &State<Arc<Mutex<MySqlBackend>>>,
+    config: &State<Config>,
+) -> Download {
+    let mut bg = backend.lock().unwrap();
+    let tables = collect_user_data(&mut bg, &apikey.user);
+    let files = attachments::for_user(&mut bg, &apikey.user);
+    drop(bg);
+
+    let mut zip = ZipWriter::new(std::io::Cursor::new(Vec::new()));
+    let options = FileOptions::default();
+    zip.start_file("export.json", options).unwrap();
+    zip.write_all(to_json(&apikey.user, &tables).as_bytes())
+        .unwrap();
+    for t in &tables {
+        zip.start_file(format!("{}.csv", t.name), options).unwrap();
+        zip.write_all(to_csv(t).as_bytes()).unwrap();
+    }
+    // the attachments table above only lists the files; their contents go alongside it
+    for (lec, q, a) in &files {
+        match a.read(config) {
+            Ok(data) => {
+                zip.start_file(format!("attachments/{}-{}-{}", lec, q, a.filename), options)
+                    .unwrap();
+                zip.write_all(&data).unwrap();
+            }
+            Err(e) => eprintln!("failed to read attachment {}: {}", a.hash, e),
+        }
+    }
+    let data = zip.finish().unwrap().into_inner();
+
+    Download::new(
+        ContentType::new("application", "zip"),
+        "websubmit-export.zip",
+        data,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn filenames_are_encoded_in_content_disposition() {
+        assert_eq!(
+            content_disposition("websubmit-export.zip"),
+            "attachment; filename=\"websubmit-export.zip\"; \
+             filename*=UTF-8''websubmit-export.zip"
+        );
+        assert_eq!(
+            content_disposition("my essay.pdf"),
+            "attachment; filename=\"my essay.pdf\"; filename*=UTF-8''my%20essay.pdf"
+        );
+        assert_eq!(
+            content_disposition("Résumé.pdf"),
+            "attachment; filename=\"R_sum_.pdf\"; filename*=UTF-8''R%C3%A9sum%C3%A9.pdf"
+        );
+        // quotes, separators and line breaks cannot end the value or the header
+        let header = content_disposition("a\"; filename=evil.html\r\nX-Injected: 1");
+        assert_eq!(
+            header,
+            "attachment; filename=\"a_; filename=evil.html__X-Injected: 1\"; \
+             filename*=UTF-8''a%22%3B%20filename%3Devil.html%0D%0AX-Injected%3A%201"
+        );
+    }
+}
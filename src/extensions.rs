@@ -0,0 +1,254 @@
+use crate::admin::Admin;
+use crate::apikey::ApiKey;
+use crate::backend::MySqlBackend;
+use crate::config::Config;
+use crate::csrf::{CsrfForm, CsrfToken};
+use crate::deadline;
+use chrono::naive::NaiveDateTime;
+use mysql::from_value;
+use rocket::response::Redirect;
+use rocket::State;
+use rocket_dyn_templates::Template;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+#[derive(Debug, FromForm)]
+pub(crate) struct GrantForm {
+    lec: u64,
+    /// New deadline, in the course timezone
+    due_at: String,
+    /// Students to extend, separated by whitespace or commas
+    emails: String,
+    /// Also extend every rostered student in this section
+    section: String,
+    /// Shown to staff only
+    reason: String,
+}
+
+#[derive(Debug, FromForm)]
+pub(crate) struct RevokeForm {
+    email: String,
+    lec: u64,
+}
+
+#[derive(Serialize)]
+struct ExtensionEntry {
+    email: String,
+    lec: u64,
+    label: String,
+    due_at: String,
+    reason: String,
+    granted_by: String,
+    granted_at: String,
+}
+
+#[derive(Serialize)]
+struct LectureOption {
+    id: u64,
+    label: String,
+}
+
+#[derive(Serialize)]
+struct ExtensionsContext {
+    extensions: Vec<ExtensionEntry>,
+    lectures: Vec<LectureOption>,
+    sections: Vec<String>,
+    timezone: &'static str,
+    message: Option<String>,
+    errors: Vec<String>,
+    csrf_token: String,
+    parent: &'static str,
+}
+
+/// The extended deadline of `email` for lecture `lec`, if they have one.
+pub(crate) fn lookup(bg: &mut MySqlBackend, email: &str, lec: u64) -> Option<NaiveDateTime> {
+    let res = bg.prep_exec(
+        "SELECT due_at FROM extensions WHERE email = ? AND lec = ?",
+        vec![email.into(), lec.into()],
+    );
+    res.first().map(|r| from_value(r[0].clone()))
+}
+
+/// Extended deadlines for a lecture, by student.
+pub(crate) fn for_lecture(bg: &mut MySqlBackend, lec: u64) -> HashMap<String, NaiveDateTime> {
+    bg.prep_exec(
+        "SELECT email, due_at FROM extensions WHERE lec = ?",
+        vec![lec.into()],
+    )
+    .into_iter()
+    .map(|r| (from_value(r[0].clone()), from_value(r[1].clone())))
+    .collect()
+}
+
+/// Extended deadlines of a student, by lecture.
+pub(crate) fn for_student(bg: &mut MySqlBackend, email: &str) -> HashMap<u64, NaiveDateTime> {
+    bg.prep_exec(
+        "SELECT lec, due_at FROM extensions WHERE email = ?",
+        vec![email.into()],
+    )
+    .into_iter()
+    .map(|r| (from_value(r[0].clone()), from_value(r[1].clone())))
+    .collect()
+}
+
+fn render_extensions(
+    bg: &mut MySqlBackend,
+    config: &Config,
+    csrf: CsrfToken,
+    message: Option<String>,
+    errors: Vec<String>,
+) -> Template {
+    let extensions = bg
+        .prep_exec(
+            "SELECT e.email, e.lec, l.label, e.due_at, e.reason, e.granted_by, e.granted_at \
+             FROM extensions e JOIN lectures l ON e.lec = l.id ORDER BY e.lec, e.email",
+            vec![],
+        )
+        .into_iter()
+        .map(|r| ExtensionEntry {
+            email: from_value(r[0].clone()),
+            lec: from_value(r[1].clone()),
+            label: from_value(r[2].clone()),
+            due_at: deadline::display(config, from_value(r[3].clone())),
+            reason: from_value(r[4].clone()),
+            granted_by: from_value(r[5].clone()),
+            granted_at: from_value::<NaiveDateTime>(r[6].clone())
+                .format("%Y-%m-%d %H:%M:%S")
+                .to_string(),
+        })
+        .collect();
+    let lectures = bg
+        .prep_exec("SELECT id, label FROM lectures ORDER BY id", vec![])
+        .into_iter()
+        .map(|r| LectureOption {
+            id: from_value(r[0].clone()),
+            label: from_value(r[1].clone()),
+        })
+        .collect();
+    let sections = bg
+        .prep_exec(
+            "SELECT DISTINCT section FROM enrollment WHERE section <> '' ORDER BY section",
+            vec![],
+        )
+        .into_iter()
+        .map(|r| from_value(r[0].clone()))
+        .collect();
+
+    let ctx = ExtensionsContext {
+        extensions,
+        lectures,
+        sections,
+        timezone: deadline::zone_name(config),
+        message,
+        errors,
+        csrf_token: csrf.0,
+        parent: "layout",
+    };
+    Template::render("admin/extensions", &ctx)
+}
+
+#[get("/")]
+pub(crate) fn extensions(
+    _adm: Admin,
+    csrf: CsrfToken,
+    backend: &State<Arc<Mutex<MySqlBackend>>>,
+    config: &State<Config>,
+) -> Template {
+    let mut bg = backend.lock().unwrap();
+    render_extensions(&mut bg, config, csrf, None, vec![])
+}
+
+#[post("/grant", data = "<data>")]
+pub(crate) fn grant(
+    _adm: Admin,
+    apikey: ApiKey,
+    data: CsrfForm<GrantForm>,
+    csrf: CsrfToken,
+    backend: &State<Arc<Mutex<MySqlBackend>>>,
+    config: &State<Config>,
+) -> Template {
+    let mut bg = backend.lock().unwrap();
+    let due_at = match deadline::parse_input(config, &data.due_at) {
+        Ok(Some(d)) => d,
+        _ => {
+            let errors = vec![String::from("Please enter a valid new deadline.")];
+            return render_extensions(&mut bg, config, csrf, None, errors);
+        }
+    };
+    let res = bg.prep_exec(
+        "SELECT COUNT(*) FROM lectures WHERE id = ?",
+        vec![data.lec.into()],
+    );
+    if from_value::<u64>(res[0][0].clone()) == 0 {
+        let errors = vec![format!("There is no lecture {}.", data.lec)];
+        return render_extensions(&mut bg, config, csrf, None, errors);
+    }
+
+    let mut emails: Vec<String> = data
+        .emails
+        .split(|c: char| c.is_whitespace() || c == ',')
+        .filter(|e| !e.is_empty())
+        .map(String::from)
+        .collect();
+    let section = data.section.trim();
+    if !section.is_empty() {
+        let res = bg.prep_exec(
+            "SELECT email FROM enrollment WHERE section = ?",
+            vec![section.into()],
+        );
+        emails.extend(res.into_iter().map(|r| from_value::<String>(r[0].clone())));
+    }
+    emails.sort();
+    emails.dedup();
+
+    let now = deadline::now();
+    let mut errors = vec![];
+    let mut granted = 0;
+    for email in emails {
+        let res = bg.prep_exec(
+            "SELECT (SELECT COUNT(*) FROM users WHERE email = ?) \
+             + (SELECT COUNT(*) FROM enrollment WHERE email = ?)",
+            vec![email.as_str().into(), email.as_str().into()],
+        );
+        if from_value::<u64>(res[0][0].clone()) == 0 {
+            errors.push(format!("{}: not a registered or rostered student", email));
+            continue;
+        }
+        bg.replace(
+            "extensions",
+            vec![
+                email.as_str().into(),
+                data.lec.into(),
+                due_at.into(),
+                data.reason.trim().into(),
+                apikey.user.as_str().into(),
+                now.into(),
+            ],
+        );
+        granted += 1;
+    }
+
+    let message = format!(
+        "Extended lecture {} to {} for {} students.",
+        data.lec,
+        deadline::display(config, due_at),
+        granted
+    );
+    render_extensions(&mut bg, config, csrf, Some(message), errors)
+}
+
+#[post("/revoke", data = "<data>")]
+pub(crate) fn revoke(
+    _adm: Admin,
+    data: CsrfForm<RevokeForm>,
+    backend: &State<Arc<Mutex<MySqlBackend>>>,
+) -> Redirect {
+    let mut bg = backend.lock().unwrap();
+    bg.prep_exec(
+        "DELETE FROM extensions WHERE email = ? AND lec = ?",
+        vec![data.email.as_str().into(), data.lec.into()],
+    );
+    drop(bg);
+
+    Redirect::to("/admin/extensions")
+}
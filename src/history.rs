@@ -0,0 +1,340 @@
+use crate::admin::Admin;
+use crate::autograde::AnswerKey;
+use crate::backend::{MySqlBackend, Value};
+use crate::config::{Config, GradedVersion};
+use crate::deadline::Deadline;
+use crate::pseudonym::Pseudonyms;
+use crate::qtype::QuestionSpec;
+use chrono::naive::NaiveDateTime;
+use mysql::from_value;
+use rocket::http::Status;
+use rocket::State;
+use rocket_dyn_templates::Template;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+/// One submitted answer.
+pub(crate) struct Submission<'a> {
+    pub email: &'a str,
+    pub lec: u64,
+    pub q: u64,
+    pub answer: &'a str,
+    pub submitted_at: NaiveDateTime,
+}
+
+#[derive(Serialize)]
+struct VersionEntry {
+    version: u64,
+    time: String,
+    late: bool,
+    graded: bool,
+}
+
+#[derive(Serialize)]
+struct DiffLine {
+    /// "same", "added" or "removed"
+    kind: &'static str,
+    text: String,
+}
+
+#[derive(Serialize)]
+struct HistoryContext {
+    user: String,
+    lec_id: u64,
+    lec_qnum: u64,
+    versions: Vec<VersionEntry>,
+    from: u64,
+    to: u64,
+    diff: Vec<DiffLine>,
+    /// The versions were too large to compare
+    too_large: bool,
+    parent: &'static str,
+}
+
+/// The graded answer (the row in `answers`) before a resubmission.
+struct GradedAnswer {
+    answer: String,
+    submitted_at: NaiveDateTime,
+    grade: u64,
+    /// Set if staff graded this answer by hand
+    graded_by: Option<String>,
+}
+
+fn graded_answer(bg: &mut MySqlBackend, email: &str, lec: u64, q: u64) -> Option<GradedAnswer> {
+    let res = bg.prep_exec(
+        "SELECT answer, submitted_at, grade, graded_by FROM answers \
+         WHERE email = ? AND lec = ? AND q = ?",
+        vec![email.into(), lec.into(), q.into()],
+    );
+    res.first().map(|r| GradedAnswer {
+        answer: from_value(r[0].clone()),
+        submitted_at: from_value(r[1].clone()),
+        grade: from_value(r[2].clone()),
+        graded_by: from_value(r[3].clone()),
+    })
+}
+
+/// A student's latest submitted version of each answer to a lecture, with its time.
+pub(crate) fn latest_versions(
+    bg: &mut MySqlBackend,
+    email: &str,
+    lec: u64,
+) -> HashMap<u64, (String, NaiveDateTime)> {
+    let res = bg.prep_exec(
+        "SELECT q, answer, submitted_at FROM answer_versions \
+         WHERE lec = ? AND email = ? ORDER BY version",
+        vec![lec.into(), email.into()],
+    );
+    // later versions overwrite earlier ones
+    res.into_iter()
+        .map(|r| {
+            (
+                from_value(r[0].clone()),
+                (from_value(r[1].clone()), from_value(r[2].clone())),
+            )
+        })
+        .collect()
+}
+
+/// Stores a submitted answer as a new version and, if the configured policy grades it, makes it
+/// the answer in `answers`. Answers with a key are graded automatically, unless staff graded the
+/// previous answer by hand: then the replacement keeps that grade and is flagged for regrading.
+pub(crate) fn record_submission(
+    bg: &mut MySqlBackend,
+    config: &Config,
+    s: &Submission,
+    deadline: &Deadline,
+    key: Option<&(QuestionSpec, AnswerKey)>,
+) {
+    let (email, lec, q, answer, now) = (s.email, s.lec, s.q, s.answer, s.submitted_at);
+    let res = bg.prep_exec(
+        "SELECT COALESCE(MAX(version), 0) FROM answer_versions \
+         WHERE email = ? AND lec = ? AND q = ?",
+        vec![email.into(), lec.into(), q.into()],
+    );
+    let version = from_value::<u64>(res[0][0].clone()) + 1;
+    bg.insert(
+        "answer_versions",
+        vec![
+            email.into(),
+            lec.into(),
+            q.into(),
+            version.into(),
+            answer.into(),
+            now.into(),
+        ],
+    );
+
+    let previous = graded_answer(bg, email, lec, q);
+    let graded = match (&previous, config.graded_version) {
+        (None, _) | (_, GradedVersion::Latest) => true,
+        (Some(_), GradedVersion::First) => false,
+        // a late resubmission does not replace an answer that was on time
+        (Some(p), GradedVersion::LastOnTime) => {
+            !deadline.is_late(now) || deadline.is_late(p.submitted_at)
+        }
+    };
+    if !graded {
+        return;
+    }
+
+    let (grade, auto_graded, needs_regrade, graded_by): (u64, bool, bool, Value) =
+        match (key, previous) {
+            // a manual grade overrides the key, and is not silently replaced
+            (_, Some(p)) if p.graded_by.is_some() => {
+                // unchanged answers keep their grade as is
+                let changed = p.answer != answer;
+                (p.grade, false, changed, p.graded_by.into())
+            }
+            (Some((spec, key)), _) => (key.grade(spec, answer), true, false, Value::NULL),
+            (None, _) => (0, false, false, Value::NULL),
+        };
+    bg.replace(
+        "answers",
+        vec![
+            email.into(),
+            lec.into(),
+            q.into(),
+            answer.into(),
+            now.into(),
+            grade.into(),
+            auto_graded.into(),
+            version.into(),
+            needs_regrade.into(),
+            graded_by,
+        ],
+    );
+}
+
+/// Versions are only diffed if each is at most this large...
+const MAX_DIFF_BYTES: usize = 256 * 1024;
+/// ...and each has at most this many lines outside their common prefix and suffix, since the
+/// LCS table grows with the product of the two.
+const MAX_DIFF_LINES: usize = 2000;
+
+/// Line-based diff of two texts (longest common subsequence), or `None` if they are too large
+/// to compare.
+fn diff_lines(old: &str, new: &str) -> Option<Vec<DiffLine>> {
+    if old.len() > MAX_DIFF_BYTES || new.len() > MAX_DIFF_BYTES {
+        return None;
+    }
+    let a: Vec<&str> = old.lines().collect();
+    let b: Vec<&str> = new.lines().collect();
+    // unchanged lines at the start and end need no table
+    let prefix = a.iter().zip(b.iter()).take_while(|(x, y)| x == y).count();
+    let suffix = a[prefix..]
+        .iter()
+        .rev()
+        .zip(b[prefix..].iter().rev())
+        .take_while(|(x, y)| x == y)
+        .count();
+    let (head, tail) = (&a[..prefix], &a[a.len() - suffix..]);
+    let (a, b) = (&a[prefix..a.len() - suffix], &b[prefix..b.len() - suffix]);
+    if a.len() > MAX_DIFF_LINES || b.len() > MAX_DIFF_LINES {
+        return None;
+    }
+
+    // lcs[i][j] is the LCS length of a[i..] and b[j..]
+    let mut lcs = vec![vec![0usize; b.len() + 1]; a.len() + 1];
+    for i in (0..a.len()).rev() {
+        for j in (0..b.len()).rev() {
+            lcs[i][j] = if a[i] == b[j] {
+                lcs[i + 1][j + 1] + 1
+            } else {
+                lcs[i + 1][j].max(lcs[i][j + 1])
+            };
+        }
+    }
+
+    let line = |kind, text: &str| DiffLine {
+        kind,
+        text: text.to_string(),
+    };
+    let mut diff: Vec<DiffLine> = head.iter().map(|l| line("same", l)).collect();
+    let (mut i, mut j) = (0, 0);
+    while i < a.len() && j < b.len() {
+        if a[i] == b[j] {
+            diff.push(line("same", a[i]));
+            i += 1;
+            j += 1;
+        } else if lcs[i + 1][j] >= lcs[i][j + 1] {
+            diff.push(line("removed", a[i]));
+            i += 1;
+        } else {
+            diff.push(line("added", b[j]));
+            j += 1;
+        }
+    }
+    diff.extend(a[i..].iter().map(|l| line("removed", l)));
+    diff.extend(b[j..].iter().map(|l| line("added", l)));
+    diff.extend(tail.iter().map(|l| line("same", l)));
+    Some(diff)
+}
+
+#[get("/<user>/<num>/<qnum>/history?<from>&<to>")]
+pub(crate) fn history(
+    _adm: Admin,
+    user: String,
+    num: u64,
+    qnum: u64,
+    from: Option<u64>,
+    to: Option<u64>,
+    backend: &State<Arc<Mutex<MySqlBackend>>>,
+) -> Result<Template, Status> {
+    let mut bg = backend.lock().unwrap();
+    // in anonymous view, `user` is a pseudonym
+    let email = Pseudonyms::load(&mut bg).resolve(&user);
+    let res = bg.prep_exec(
+        "SELECT version, answer, submitted_at FROM answer_versions \
+         WHERE email = ? AND lec = ? AND q = ? ORDER BY version",
+        vec![email.as_str().into(), num.into(), qnum.into()],
+    );
+    let graded = bg.prep_exec(
+        "SELECT version FROM answers WHERE email = ? AND lec = ? AND q = ?",
+        vec![email.as_str().into(), num.into(), qnum.into()],
+    );
+    let deadline = Deadline::for_student(&mut bg, num, &email);
+    drop(bg);
+
+    if res.is_empty() {
+        return Err(Status::NotFound);
+    }
+    let graded_version: Option<u64> = graded.first().and_then(|r| from_value(r[0].clone()));
+    let versions: Vec<(u64, String, NaiveDateTime)> = res
+        .into_iter()
+        .map(|r| {
+            (
+                from_value(r[0].clone()),
+                from_value(r[1].clone()),
+                from_value(r[2].clone()),
+            )
+        })
+        .collect();
+
+    // by default, compare the latest version with the one before it
+    let latest = versions.last().unwrap().0;
+    let to = to.unwrap_or(latest);
+    let from = from.unwrap_or(if to > 1 { to - 1 } else { to });
+    let text = |v: u64| versions.iter().find(|e| e.0 == v).map(|e| e.1.as_str());
+    let diff = match (text(from), text(to)) {
+        (Some(old), Some(new)) => diff_lines(old, new),
+        _ => return Err(Status::NotFound),
+    };
+
+    let ctx = HistoryContext {
+        user,
+        lec_id: num,
+        lec_qnum: qnum,
+        versions: versions
+            .iter()
+            .map(|(v, _, t)| VersionEntry {
+                version: *v,
+                time: t.format("%Y-%m-%d %H:%M:%S").to_string(),
+                late: deadline.is_late(*t),
+                graded: graded_version == Some(*v),
+            })
+            .collect(),
+        from,
+        to,
+        too_large: diff.is_none(),
+        diff: diff.unwrap_or_default(),
+        parent: "layout",
+    };
+    Ok(Template::render("answerhistory", &ctx))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn kinds(diff: &[DiffLine]) -> Vec<(&str, &str)> {
+        diff.iter().map(|l| (l.kind, l.text.as_str())).collect()
+    }
+
+    #[test]
+    fn diffs_changed_lines() {
+        let diff = diff_lines("a\nb\nc\nd", "a\nx\nc\nd\ne").unwrap();
+        assert_eq!(
+            kinds(&diff),
+            vec![
+                ("same", "a"),
+                ("removed", "b"),
+                ("added", "x"),
+                ("same", "c"),
+                ("same", "d"),
+                ("added", "e"),
+            ]
+        );
+    }
+
+    #[test]
+    fn refuses_large_versions() {
+        let many_lines = "\n".repeat(MAX_DIFF_BYTES);
+        assert!(diff_lines(&many_lines, "x").is_none());
+        let long_middle = format!("start\n{}end", "a\n".repeat(MAX_DIFF_LINES + 1));
+        assert!(diff_lines(&long_middle, "start\nend").is_none());
+        // a long but unchanged prefix does not count against the limit
+        let long_prefix = "a\n".repeat(MAX_DIFF_LINES * 2);
+        assert!(diff_lines(&long_prefix, &format!("{}b", long_prefix)).is_some());
+    }
+}
@@ -0,0 +1,159 @@
+use crate::admin::Admin;
+use crate::apikey::ApiKey;
+use crate::backend::{MySqlBackend, Value};
+use crate::config::Config;
+use crate::csrf::{CsrfForm, NoFields};
+use chrono::naive::NaiveDateTime;
+use chrono::Local;
+use mysql::from_value;
+use rocket::http::{Cookie, CookieJar, Status};
+use rocket::response::Redirect;
+use rocket::State;
+use rocket_dyn_templates::Template;
+use std::sync::{Arc, Mutex};
+
+/// Private (encrypted) cookie holding the email of the student an admin is viewing the site as.
+pub(crate) const COOKIE: &str = "impersonate";
+/// The one write an impersonating admin may make.
+pub(crate) const STOP_PATH: &str = "/admin/impersonate/stop";
+
+#[derive(Debug, FromForm)]
+pub(crate) struct ImpersonateForm {
+    email: String,
+}
+
+#[derive(Serialize)]
+struct ImpersonationEntry {
+    admin: String,
+    student: String,
+    started_at: String,
+    ended_at: Option<String>,
+}
+
+#[derive(Serialize)]
+struct ImpersonationsContext {
+    impersonations: Vec<ImpersonationEntry>,
+    parent: &'static str,
+}
+
+#[post("/", data = "<data>")]
+pub(crate) fn start(
+    _adm: Admin,
+    apikey: ApiKey,
+    data: CsrfForm<ImpersonateForm>,
+    cookies: &CookieJar<'_>,
+    backend: &State<Arc<Mutex<MySqlBackend>>>,
+    config: &State<Config>,
+) -> Result<Redirect, Status> {
+    if is_privileged(config, &data.email) {
+        return Err(Status::Forbidden);
+    }
+
+    let mut bg = backend.lock().unwrap();
+    let res = bg.prep_exec(
+        "SELECT email FROM users WHERE email = ?",
+        vec![data.email.as_str().into()],
+    );
+    if res.is_empty() {
+        return Err(Status::NotFound);
+    }
+
+    // every impersonation session is audited
+    bg.insert(
+        "impersonations",
+        vec![
+            apikey.user.as_str().into(),
+            data.email.as_str().into(),
+            Local::now().naive_local().into(),
+            Value::NULL,
+        ],
+    );
+    drop(bg);
+
+    let cookie = Cookie::build(COOKIE, data.email.clone()).path("/").finish();
+    cookies.add_private(cookie);
+    Ok(Redirect::to("/leclist"))
+}
+
+#[post("/stop", data = "<_data>")]
+pub(crate) fn stop(
+    _adm: Admin,
+    apikey: ApiKey,
+    _data: CsrfForm<NoFields>,
+    cookies: &CookieJar<'_>,
+    backend: &State<Arc<Mutex<MySqlBackend>>>,
+) -> Redirect {
+    let mut bg = backend.lock().unwrap();
+    bg.prep_exec(
+        "UPDATE impersonations SET ended_at = ? WHERE admin = ? AND ended_at IS NULL",
+        vec![
+            Local::now().naive_local().into(),
+            apikey.user.as_str().into(),
+        ],
+    );
+    drop(bg);
+
+    cookies.remove_private(Cookie::named(COOKIE));
+    Redirect::to("/admin/users")
+}
+
+#[get("/")]
+pub(crate) fn audit(_adm: Admin, backend: &State<Arc<Mutex<MySqlBackend>>>) -> Template {
+    let mut bg = backend.lock().unwrap();
+    let res = bg.prep_exec(
+        "SELECT admin, student, started_at, ended_at FROM impersonations \
+         ORDER BY started_at DESC",
+        vec![],
+    );
+    drop(bg);
+
+    let format = |v: &Value| {
+        from_value::<NaiveDateTime>(v.clone())
+            .format("%Y-%m-%d %H:%M:%S")
+            .to_string()
+    };
+    let impersonations: Vec<_> = res
+        .into_iter()
+        .map(|r| ImpersonationEntry {
+            admin: from_value(r[0].clone()),
+            student: from_value(r[1].clone()),
+            started_at: format(&r[2]),
+            ended_at: if r[3] == Value::NULL {
+                None
+            } else {
+                Some(format(&r[3]))
+            },
+        })
+        .collect();
+
+    let ctx = ImpersonationsContext {
+        impersonations,
+        parent: "layout",
+    };
+    Template::render("admin/impersonations", &ctx)
+}
+
+/// Only students may be impersonated, never admins, managers or staff.
+pub(crate) fn is_privileged(config: &Config, email: &str) -> bool {
+    config
+        .admins
+        .iter()
+        .chain(config.managers.iter())
+        .chain(config.staff.iter())
+        .any(|e| e == email)
+}
+
+/// Whether `admin` has an audited, not yet ended impersonation of `student`.
+pub(crate) fn is_open(bg: &mut MySqlBackend, admin: &str, student: &str) -> bool {
+    let res = bg.prep_exec(
+        "SELECT COUNT(*) FROM impersonations \
+         WHERE admin = ? AND student = ? AND ended_at IS NULL",
+        vec![admin.into(), student.into()],
+    );
+    from_value::<u64>(res[0][0].clone()) > 0
+}
+
+/// The student an admin is viewing the site as, for the banner in `nav`.
+pub(crate) fn viewing_as(apikey: &ApiKey) -> Option<String> {
+    apikey.impersonator.as_ref().map(|_| apikey.user.clone())
+}
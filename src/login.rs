@@ -1,12 +1,353 @@
+use crate::backend::MySqlBackend;
 use crate::config::Config;
+use crate::csrf::{CsrfForm, CsrfToken};
+use crate::email;
+use crate::oidc;
+use crate::profile::{self, DemographicsFields, PREFER_NOT_TO_SAY};
+use crate::ratelimit::RateLimiter;
+use chrono::naive::NaiveDateTime;
+use chrono::{Duration, Local};
+use crypto::digest::Digest;
+use crypto::sha2::Sha256;
+use mysql::from_value;
+use rand::distributions::Alphanumeric;
+use rand::{thread_rng, Rng};
+use rocket::http::{Cookie, CookieJar, Status};
+use rocket::response::Redirect;
 use rocket::State;
 use rocket_dyn_templates::Template;
 use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+#[derive(Debug, FromForm)]
+pub(crate) struct LoginLinkRequest {
+    email: String,
+}
+
+/// Only allow redirects to local paths after login, never to other sites.
+pub(crate) fn safe_next(next: &Option<String>) -> String {
+    match next {
+        Some(n) if n.starts_with('/') && !n.starts_with("//") && !n.starts_with("/\\") => {
+            n.clone()
+        }
+        _ => String::from("/leclist"),
+    }
+}
+
+#[derive(Serialize)]
+struct LoginContext {
+    #[serde(rename = "CLASS_ID")]
+    class_id: String,
+    next: String,
+    oidc: bool,
+    email: String,
+    demographics: DemographicsFields,
+    errors: HashMap<&'static str, String>,
+    csrf_token: String,
+    parent: &'static str,
+}
+
+/// Renders the login page; registration fields are pre-filled when re-rendering after a failed
+/// registration.
+pub(crate) fn render_login(
+    config: &Config,
+    csrf: CsrfToken,
+    next: &Option<String>,
+    email: &str,
+    demographics: DemographicsFields,
+    errors: HashMap<&'static str, String>,
+) -> Template {
+    let ctx = LoginContext {
+        class_id: config.class.clone(),
+        next: safe_next(next),
+        oidc: config.oidc.is_some(),
+        email: email.to_string(),
+        demographics,
+        errors,
+        csrf_token: csrf.0,
+        parent: "layout",
+    };
+    Template::render("login", &ctx)
+}
+
+#[get("/?<next>&<oidc_error>")]
+pub(crate) fn login(
+    next: Option<String>,
+    oidc_error: Option<String>,
+    csrf: CsrfToken,
+    config: &State<Config>,
+) -> Template {
+    let demographics = profile::fields(
+        config,
+        PREFER_NOT_TO_SAY,
+        "",
+        PREFER_NOT_TO_SAY,
+        false,
+        PREFER_NOT_TO_SAY,
+    );
+    let mut errors = HashMap::new();
+    // only known codes, so that links cannot put arbitrary text on the page
+    if let Some(message) = oidc_error.as_deref().and_then(oidc::error_message) {
+        errors.insert("oidc", String::from(message));
+    }
+    render_login(config, csrf, &next, "", demographics, errors)
+}
+
+/// Login tokens are only stored hashed (together with the secret), so a database dump does not
+/// reveal usable links.
+pub(crate) fn hash_token(token: &str, secret: &str) -> String {
+    let mut hasher = Sha256::new();
+    hasher.input_str(token);
+    hasher.input_str(secret);
+    hasher.result_str()
+}
+
+/// A new login link token, with its hash (as stored) and expiry time.
+fn new_token(config: &Config, now: NaiveDateTime) -> (String, String, NaiveDateTime) {
+    let token: String = thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(32)
+        .map(char::from)
+        .collect();
+    let hash = hash_token(&token, &config.secret);
+    (token, hash, now + Duration::minutes(config.login_link_ttl as i64))
+}
+
+#[derive(Debug, PartialEq)]
+enum LinkError {
+    /// No token with the link's hash was issued
+    Unknown,
+    Expired,
+    Used,
+}
+
+/// Checks the stored token matching a link (its expiry time and whether it was used), if any.
+fn check_token(
+    stored: Option<(NaiveDateTime, bool)>,
+    now: NaiveDateTime,
+) -> Result<(), LinkError> {
+    match stored {
+        None => Err(LinkError::Unknown),
+        Some((_, true)) => Err(LinkError::Used),
+        Some((expires_at, false)) if expires_at <= now => Err(LinkError::Expired),
+        Some(_) => Ok(()),
+    }
+}
+
+#[post("/link", data = "<data>")]
+pub(crate) fn link_request(
+    data: CsrfForm<LoginLinkRequest>,
+    backend: &State<Arc<Mutex<MySqlBackend>>>,
+    config: &State<Config>,
+    limiter: &State<Arc<RateLimiter>>,
+) -> Result<Template, Status> {
+    if !limiter.take("/login/link", &data.email.to_lowercase()) {
+        return Err(Status::TooManyRequests);
+    }
+
+    let mut bg = backend.lock().unwrap();
+    let res = bg.prep_exec(
+        "SELECT email FROM users WHERE email = ?",
+        vec![data.email.as_str().into()],
+    );
+
+    // only registered users get a link, but we respond identically either way so that the
+    // form cannot be used to discover who is registered
+    if !res.is_empty() {
+        let (token, hash, expires) = new_token(config, Local::now().naive_local());
+        bg.insert(
+            "login_tokens",
+            vec![
+                hash.into(),
+                data.email.as_str().into(),
+                expires.into(),
+                0.into(),
+            ],
+        );
+        // never log the link itself: anyone with it can log in
+        debug!(bg.log, "Sending login link to {}", data.email);
+        let log = bg.log.clone();
+        drop(bg);
+
+        if config.send_emails {
+            let link = format!("{}/login/link/{}", config.base_url, token);
+            email::send(
+                log.clone(),
+                config,
+                "no-reply@csci2390-submit.cs.brown.edu".into(),
+                vec![data.email.clone()],
+                format!("{} login link", config.class),
+                format!(
+                    "Use this link to log in to {} (valid for {} minutes, single use):\n{}\n",
+                    config.class, config.login_link_ttl, link
+                ),
+            )
+            .map_err(|e| {
+                error!(log, "failed to send login link email: {}", e);
+                Status::InternalServerError
+            })?;
+        }
+    } else {
+        drop(bg);
+    }
 
-#[get("/")]
-pub(crate) fn login(config: &State<Config>) -> Template {
     let mut ctx = HashMap::new();
-    ctx.insert("CLASS_ID", config.class.clone());
+    ctx.insert("link_email", data.email.clone());
     ctx.insert("parent", String::from("layout"));
-    Template::render("login", &ctx)
+    Ok(Template::render("login/link", &ctx))
+}
+
+#[get("/link/<token>")]
+pub(crate) fn link_login(
+    token: String,
+    cookies: &CookieJar<'_>,
+    backend: &State<Arc<Mutex<MySqlBackend>>>,
+    config: &State<Config>,
+) -> Result<Redirect, Template> {
+    let hash = hash_token(&token, &config.secret);
+    let now = Local::now().naive_local();
+
+    let mut bg = backend.lock().unwrap();
+    let res = bg.prep_exec(
+        "SELECT email, expires_at, used FROM login_tokens WHERE token_hash = ?",
+        vec![hash.as_str().into()],
+    );
+    let stored = res
+        .first()
+        .map(|r| (from_value(r[1].clone()), from_value(r[2].clone())));
+    if let Err(e) = check_token(stored, now) {
+        debug!(bg.log, "rejected login link: {:?}", e);
+        drop(bg);
+        let mut ctx = HashMap::new();
+        ctx.insert("invalid", String::from("true"));
+        ctx.insert("parent", String::from("layout"));
+        return Err(Template::render("login/link", &ctx));
+    }
+
+    // burn the token before establishing the session
+    let email: String = from_value(res[0][0].clone());
+    bg.prep_exec(
+        "UPDATE login_tokens SET used = 1 WHERE token_hash = ?",
+        vec![hash.as_str().into()],
+    );
+    let res = bg.prep_exec(
+        "SELECT apikey FROM users WHERE email = ?",
+        vec![email.into()],
+    );
+    drop(bg);
+
+    if res.is_empty() {
+        return Ok(Redirect::to("/login"));
+    }
+    let key: String = from_value(res[0][0].clone());
+    let cookie = Cookie::build("apikey", key).path("/").finish();
+    cookies.add(cookie);
+    Ok(Redirect::to("/leclist"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::apikey;
+    use crate::config;
+    use regex::Regex;
+    use rocket::http::ContentType;
+    use rocket::local::blocking::Client;
+    use chrono::NaiveDate;
+    use std::path::Path;
+
+    #[test]
+    fn tokens_are_stored_hashed() {
+        let mut config = config::parse("sample-config.toml").unwrap();
+        config.login_link_ttl = 15;
+        let now = NaiveDate::from_ymd(2021, 9, 1).and_hms(12, 0, 0);
+        let (token, hash, expires) = new_token(&config, now);
+        assert_eq!(token.len(), 32);
+        assert_eq!(hash, hash_token(&token, &config.secret));
+        assert!(!hash.contains(&token));
+        assert_ne!(hash, hash_token(&token, "another secret"));
+        assert_eq!(expires, now + Duration::minutes(15));
+        // every link is different
+        assert_ne!(new_token(&config, now).0, token);
+    }
+
+    #[test]
+    fn tokens_are_single_use_and_expire() {
+        let now = NaiveDate::from_ymd(2021, 9, 1).and_hms(12, 0, 0);
+        let later = now + Duration::minutes(1);
+        assert_eq!(check_token(Some((later, false)), now), Ok(()));
+        assert_eq!(check_token(Some((later, true)), now), Err(LinkError::Used));
+        assert_eq!(check_token(Some((now, false)), now), Err(LinkError::Expired));
+        assert_eq!(check_token(Some((now, false)), later), Err(LinkError::Expired));
+        assert_eq!(check_token(None, now), Err(LinkError::Unknown));
+    }
+
+    /// The login link in the (single) email written to `dir` by the file transport.
+    fn emailed_link(dir: &Path) -> String {
+        let mut mails = std::fs::read_dir(dir).unwrap();
+        let mail = mails.next().expect("no email was sent").unwrap();
+        assert!(mails.next().is_none());
+        let mail: serde_json::Value =
+            serde_json::from_slice(&std::fs::read(mail.path()).unwrap()).unwrap();
+        let message: Vec<u8> = serde_json::from_value(mail["message"].clone()).unwrap();
+        let message = String::from_utf8(message).unwrap();
+        let re = Regex::new(r"/login/link/[A-Za-z0-9]+").unwrap();
+        re.find(&message).expect("no login link in email").as_str().to_string()
+    }
+
+    #[test]
+    #[ignore = "needs a MySQL server"]
+    fn login_link_is_single_use() {
+        let email_dir = std::env::temp_dir().join(format!("websubmit-login-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&email_dir);
+        std::fs::create_dir_all(&email_dir).unwrap();
+        let mut config = config::parse("sample-config.toml").unwrap();
+        config.send_emails = true;
+        config.email_dir = Some(email_dir.to_str().unwrap().into());
+
+        let mut bg = MySqlBackend::new(
+            &config.db_user,
+            &config.db_password,
+            "websubmit_test_login",
+            None,
+            true,
+            None,
+        )
+        .unwrap();
+        let key = apikey::create_user(
+            &mut bg,
+            &config,
+            "student@brown.edu",
+            &profile::Demographics::undisclosed(),
+        );
+
+        let rocket = rocket::build()
+            .attach(Template::fairing())
+            .manage(Arc::new(Mutex::new(bg)))
+            .manage(Arc::new(RateLimiter::new(&config)))
+            .manage(config)
+            .mount("/login", routes![login, link_request, link_login]);
+        let client = Client::tracked(rocket).unwrap();
+
+        let response = client
+            .post("/login/link")
+            .header(ContentType::Form)
+            .cookie(Cookie::new("csrf_token", "test"))
+            .body("email=student%40brown.edu&csrf_token=test")
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        let link = emailed_link(&email_dir);
+
+        let response = client.get(link.clone()).dispatch();
+        assert_eq!(response.status(), Status::SeeOther);
+        assert_eq!(response.headers().get_one("Location"), Some("/leclist"));
+        assert_eq!(response.cookies().get("apikey").map(|c| c.value()), Some(key.as_str()));
+
+        let response = client.get(link).dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        assert!(response.cookies().get("apikey").is_none());
+        assert!(response.into_string().unwrap().contains("already been used"));
+
+        std::fs::remove_dir_all(&email_dir).unwrap();
+    }
 }
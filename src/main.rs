@@ -13,15 +13,40 @@ extern crate serde_derive;
 mod admin;
 mod apikey;
 mod args;
+mod attachments;
 mod backend;
 mod config;
+mod csrf;
+mod deadline;
+mod deletion;
+mod drafts;
 mod email;
+mod encryption;
+mod errors;
+mod export;
+mod extensions;
+mod history;
+mod impersonate;
 mod login;
 mod questions;
+mod ratelimit;
+mod roster;
+mod tokens;
 mod manage;
+mod markdown;
 mod bbox;
+mod oidc;
+mod profile;
+mod progress;
+mod pseudonym;
+mod qtype;
+mod autograde;
+mod visibility;
 
 use backend::MySqlBackend;
+use ratelimit::{RateLimitFairing, RateLimiter};
+use rocket::data::ToByteUnit;
+use rocket::figment::Figment;
 use rocket::fs::FileServer;
 use rocket::http::CookieJar;
 use rocket::response::Redirect;
@@ -50,21 +75,58 @@ fn index(cookies: &CookieJar<'_>, backend: &State<Arc<Mutex<MySqlBackend>>>) ->
     }
 }
 
+/// Multipart forms carrying an attachment may be as large as attachments are allowed to be. Only
+/// these two limits are set, the others still come from Rocket.toml.
+fn upload_limits(figment: Figment, config: &config::Config) -> Figment {
+    let upload_limit = (config.attachment_max_kb + 64).kibibytes();
+    figment
+        .merge(("limits.file", upload_limit))
+        .merge(("limits.data-form", std::cmp::max(upload_limit, 2.mebibytes())))
+}
+
+/// Private cookies are encrypted with a key derived from the configured secret, so that they stay
+/// valid across restarts and no separate `secret_key` needs to be set for release builds.
+fn cookie_key(figment: Figment, config: &config::Config) -> Figment {
+    use crypto::digest::Digest;
+    use crypto::sha2::Sha256;
+    let mut hasher = Sha256::new();
+    hasher.input_str("cookies:");
+    hasher.input_str(&config.secret);
+    figment.merge(("secret_key", hasher.result_str()))
+}
+
 #[rocket::main]
 async fn main() {
     let args = args::parse_args();
     let config = args.config;
 
-    let backend = Arc::new(Mutex::new(
-        MySqlBackend::new(
-            &config.db_user,
-            &config.db_password,
-            &format!("{}", args.class),
-            Some(new_logger()),
-            config.prime,
-        )
-        .unwrap(),
-    ));
+    let keyring = config.encryption_key_file.as_ref().map(|path| {
+        encryption::Keyring::load(path, &config.encrypted_columns)
+            .expect("failed to load encryption keys")
+    });
+
+    let mut bg = MySqlBackend::new(
+        &config.db_user,
+        &config.db_password,
+        &args.class,
+        Some(new_logger()),
+        // re-encrypting must keep the data it is run on
+        config.prime && !args.reencrypt,
+        keyring,
+    )
+    .unwrap();
+
+    if args.reencrypt {
+        let n = encryption::reencrypt(&mut bg);
+        println!("Re-encrypted {} values.", n);
+        return;
+    }
+
+    let backend = Arc::new(Mutex::new(bg));
+
+    let limiter = Arc::new(RateLimiter::new(&config));
+
+    let figment = cookie_key(upload_limits(rocket::Config::figment(), &config), &config);
 
     let template_dir = config.template_dir.clone();
     let resource_dir = config.resource_dir.clone();
@@ -76,38 +138,127 @@ async fn main() {
             .expect("failed to set template path!");
     });
 
-    if let Err(e) = rocket::build()
+    if let Err(e) = rocket::custom(figment)
         .attach(template)
+        .attach(RateLimitFairing(limiter.clone()))
         .manage(backend)
+        .manage(limiter)
         .manage(config)
         .mount("/css", FileServer::from(format!("{}/css", resource_dir)))
         .mount("/js", FileServer::from(format!("{}/js", resource_dir)))
+        .register(
+            "/",
+            catchers![
+                errors::unauthorized,
+                errors::forbidden,
+                errors::not_found,
+                errors::unprocessable,
+                errors::too_many_requests,
+                errors::internal_error
+            ],
+        )
         .mount("/", routes![index])
+        .mount(ratelimit::THROTTLED_PATH, routes![ratelimit::throttled])
         .mount(
             "/questions",
-            routes![questions::questions, questions::questions_submit],
+            routes![
+                questions::questions,
+                questions::questions_submit,
+                drafts::save_draft
+            ],
         )
+        .mount("/oidc", routes![oidc::login, oidc::callback])
         .mount("/apikey/check", routes![apikey::check])
         .mount("/apikey/generate", routes![apikey::generate])
         .mount(
             "/grades", 
             routes![questions::grades, questions::editg, questions::editg_submit]
         )
-        .mount("/answers", routes![questions::answers])
+        .mount("/answers", routes![questions::answers, history::history])
+        .mount(
+            "/attachments",
+            routes![
+                attachments::upload,
+                attachments::remove,
+                attachments::download_own,
+                attachments::download
+            ],
+        )
         .mount("/leclist", routes![questions::leclist])
         .mount(
             "/predict", 
             routes![questions::predict, questions::predict_grade]
         )
-        .mount("/login", routes![login::login])
+        .mount(
+            "/login",
+            routes![login::login, login::link_request, login::link_login],
+        )
         .mount(
             "/admin/lec/add",
             routes![admin::lec_add, admin::lec_add_submit],
         )
-        .mount("/admin/users", routes![admin::get_registered_users])
+        .mount(
+            "/admin/users",
+            routes![
+                admin::get_registered_users,
+                admin::user_detail,
+                deletion::delete_by_admin_confirm,
+                deletion::delete_by_admin
+            ],
+        )
+        .mount(
+            "/me",
+            routes![
+                deletion::delete_confirm,
+                deletion::delete_self,
+                export::export_json,
+                export::export_zip,
+                profile::profile,
+                profile::profile_submit
+            ],
+        )
+        .mount("/admin/throttled", routes![admin::throttled])
+        .mount(
+            "/admin/roster",
+            routes![roster::roster, roster::upload, roster::invite],
+        )
+        .mount(
+            "/admin/extensions",
+            routes![extensions::extensions, extensions::grant, extensions::revoke],
+        )
+        .mount(
+            "/admin/pseudonyms",
+            routes![
+                pseudonym::pseudonyms,
+                pseudonym::regenerate,
+                pseudonym::set_view
+            ],
+        )
+        .mount(
+            "/admin/impersonate",
+            routes![impersonate::start, impersonate::stop],
+        )
+        .mount("/admin/impersonations", routes![impersonate::audit])
+        .mount(
+            "/admin/tokens",
+            routes![tokens::tokens, tokens::mint, tokens::revoke],
+        )
         .mount(
             "/admin/lec",
-            routes![admin::lec, admin::addq, admin::editq, admin::editq_submit],
+            routes![
+                admin::lec,
+                admin::addq,
+                admin::editq,
+                admin::editq_submit,
+                admin::set_deadline,
+                admin::set_visibility,
+                admin::set_release,
+                admin::reorder,
+                admin::delete_question_confirm,
+                admin::delete_question,
+                admin::delete_lecture_confirm,
+                admin::delete_lecture
+            ],
         )
         .mount("/manage/users", routes![manage::get_aggregate_grades])
         .launch()
@@ -117,3 +268,35 @@ async fn main() {
         drop(e);
     };
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rocket::data::Limits;
+
+    #[test]
+    fn upload_limits_follow_config() {
+        let mut config = config::parse("sample-config.toml").unwrap();
+        config.attachment_max_kb = 10 * 1024;
+        let figment = cookie_key(upload_limits(rocket::Config::figment(), &config), &config);
+        let limits: Limits = figment.extract_inner("limits").unwrap();
+        assert_eq!(limits.get("file"), Some((10 * 1024 + 64).kibibytes()));
+        assert_eq!(limits.get("data-form"), Some((10 * 1024 + 64).kibibytes()));
+        // limits from Rocket.toml are kept
+        assert_eq!(limits.get("string"), Some(1.mebibytes()));
+    }
+
+    #[test]
+    fn cookie_key_comes_from_config_secret() {
+        let config = config::parse("sample-config.toml").unwrap();
+        let key = |config: &config::Config| {
+            let figment = cookie_key(rocket::Config::figment(), config);
+            figment.extract::<rocket::Config>().unwrap().secret_key
+        };
+        assert!(key(&config).is_provided());
+        assert!(key(&config) == key(&config));
+        let mut other = config.clone();
+        other.secret.push('x');
+        assert!(key(&config) != key(&other));
+    }
+}
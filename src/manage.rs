@@ -6,20 +6,26 @@ use mysql::from_value;
 use mysql::prelude::FromValue;
 pub use mysql::Value;
 use rocket::http::Status;
-use rocket::outcome::IntoOutcome;
+use rocket::outcome::{IntoOutcome, Outcome};
 use rocket::request::{self, FromRequest, Request};
 use rocket::State;
 use rocket_dyn_templates::Template;
 
-use crate::apikey::ApiKey;
+use crate::apikey::{ApiKey, ApiKeyError};
 use crate::backend::MySqlBackend;
 use crate::config::Config;
+use crate::deadline::{self, Deadline};
+use crate::profile::normalize;
 
 pub(crate) struct Manager;
 
 #[derive(Debug)]
 pub(crate) enum ManagerError {
-    Unauthorized,
+    /// The `ApiKey` guard failed (not logged in, or not permitted to make this request at all);
+    /// the failure keeps that guard's status. Only read through `Debug`, when Rocket logs it.
+    ApiKey(#[allow(dead_code)] ApiKeyError),
+    /// Logged in, but not a manager
+    Forbidden,
 }
 
 #[rocket::async_trait]
@@ -27,16 +33,24 @@ impl<'r> FromRequest<'r> for Manager {
     type Error = ManagerError;
 
     async fn from_request(request: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
-        let apikey = request.guard::<ApiKey>().await.unwrap();
+        let apikey = match request.guard::<ApiKey>().await {
+            Outcome::Success(apikey) => apikey,
+            Outcome::Failure((status, e)) => {
+                return Outcome::Failure((status, ManagerError::ApiKey(e)))
+            }
+            Outcome::Forward(()) => return Outcome::Forward(()),
+        };
         let cfg = request.guard::<&State<Config>>().await.unwrap();
 
-        let res = if cfg.managers.contains(&apikey.user) {
+        // an admin viewing the site as a student keeps (read-only) access to staff pages
+        let user = apikey.impersonator.as_ref().unwrap_or(&apikey.user);
+        let res = if cfg.managers.contains(user) {
             Some(Manager)
         } else {
             None
         };
 
-        res.into_outcome((Status::Unauthorized, ManagerError::Unauthorized))
+        res.into_outcome((Status::Forbidden, ManagerError::Forbidden))
     }
 }
 
@@ -74,11 +88,31 @@ fn get_aggregate<T: Eq + Hash + FromValue>(by_idx: usize, grade_idx: usize, data
 pub(crate) fn get_aggregate_grades(
     _adm: Manager,
     backend: &State<Arc<Mutex<MySqlBackend>>>,
+    config: &State<Config>,
 ) -> Template {
     let mut bg = backend.lock().unwrap();
-    let grades = bg.prep_exec("SELECT pseudonym, gender, is_remote, grade FROM users JOIN answers ON users.email = answers.email;", vec![]);
+    let mut grades = bg.prep_exec(
+        "SELECT pseudonym, gender, is_remote, grade, submitted_at, \
+         COALESCE(extensions.due_at, lectures.due_at) FROM users \
+         JOIN answers ON users.email = answers.email LEFT JOIN lectures ON answers.lec = lectures.id \
+         LEFT JOIN extensions ON (extensions.email = answers.email AND extensions.lec = answers.lec);",
+        vec![],
+    );
     drop(bg);
 
+    for r in grades.iter_mut() {
+        // group legacy free-text genders with the configured choices they match
+        let gender = normalize(&config.demographics.genders, &from_value::<String>(r[1].clone()));
+        r[1] = gender.into();
+        // aggregate grades after late penalties, against each student's effective deadline
+        let d = Deadline {
+            due_at: from_value(r[5].clone()),
+            ..Deadline::default()
+        };
+        let penalty = d.late_penalty(config, from_value(r[4].clone()));
+        r[3] = deadline::apply_penalty(from_value(r[3].clone()), penalty).into();
+    }
+
     let aggregates_per_user = get_aggregate(0, 3, &grades);
     let aggregates_per_gender = get_aggregate(1, 3, &grades);
     let aggregates_per_remote = get_aggregate(2, 3, &grades);
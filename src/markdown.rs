@@ -0,0 +1,38 @@
+use crate::backend::{MySqlBackend, Value};
+use mysql::from_value;
+use pulldown_cmark::{html, Options, Parser};
+use std::collections::HashSet;
+
+/// Renders Markdown (with tables, strikethrough and `$...$`/`$$...$$` math) to HTML, then
+/// sanitizes it so that it is safe to embed unescaped: scripts, event handlers, styles,
+/// `javascript:` links and images are removed. Fenced code keeps its `language-*` class and math
+/// its `math-*` class, which the `markdown` partial uses for highlighting and typesetting.
+pub(crate) fn render(text: &str) -> String {
+    let options = Options::ENABLE_TABLES | Options::ENABLE_STRIKETHROUGH | Options::ENABLE_MATH;
+    let mut unsafe_html = String::new();
+    html::push_html(&mut unsafe_html, Parser::new_ext(text, options));
+
+    ammonia::Builder::default()
+        // images could load from anywhere and track who views an answer
+        .rm_tags(&["img"])
+        .add_tag_attributes("code", &["class"])
+        .add_tag_attributes("span", &["class"])
+        .clean(&unsafe_html)
+        .to_string()
+}
+
+/// Whether a question (a full `questions` row) renders its prompt and answers as Markdown.
+pub(crate) fn enabled(r: &[Value]) -> bool {
+    from_value::<Option<bool>>(r[12].clone()).unwrap_or(false)
+}
+
+/// The questions of a lecture that use Markdown.
+pub(crate) fn questions(bg: &mut MySqlBackend, lec: u64) -> HashSet<u64> {
+    bg.prep_exec(
+        "SELECT q FROM questions WHERE lec = ? AND markdown = 1",
+        vec![lec.into()],
+    )
+    .into_iter()
+    .map(|r| from_value(r[0].clone()))
+    .collect()
+}
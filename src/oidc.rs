@@ -0,0 +1,550 @@
+use crate::apikey;
+use crate::backend::MySqlBackend;
+use crate::config::{Config, OidcConfig};
+use crate::profile::Demographics;
+use crate::roster;
+use chrono::Utc;
+use mysql::from_value;
+use rand::distributions::Alphanumeric;
+use rand::{thread_rng, Rng};
+use reqwest::Url;
+use rocket::http::{Cookie, CookieJar, SameSite};
+use rocket::response::Redirect;
+use rocket::State;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+
+#[derive(Debug)]
+pub(crate) enum OidcError {
+    Disabled,
+    Provider(reqwest::Error),
+    /// The provider redirected back with an error (e.g., "access_denied") instead of a code
+    Refused(String),
+    BadState,
+    BadToken,
+    Unverified,
+    DomainNotAllowed,
+    NotEnrolled,
+}
+
+impl From<reqwest::Error> for OidcError {
+    fn from(e: reqwest::Error) -> Self {
+        OidcError::Provider(e)
+    }
+}
+
+impl fmt::Display for OidcError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            OidcError::Disabled => write!(f, "OIDC is not configured"),
+            OidcError::Provider(e) => write!(f, "request to provider failed: {}", e),
+            OidcError::Refused(e) => write!(f, "provider returned error {}", e),
+            OidcError::BadState => write!(f, "missing or mismatched state"),
+            OidcError::BadToken => write!(f, "invalid ID token"),
+            OidcError::Unverified => write!(f, "no verified email"),
+            OidcError::DomainNotAllowed => write!(f, "email domain not allowed"),
+            OidcError::NotEnrolled => write!(f, "not enrolled"),
+        }
+    }
+}
+
+impl OidcError {
+    /// What to tell the user on the login page (see `error_message`).
+    fn code(&self) -> &'static str {
+        match self {
+            OidcError::Refused(_) => "refused",
+            OidcError::DomainNotAllowed | OidcError::NotEnrolled => "not_enrolled",
+            _ => "failed",
+        }
+    }
+}
+
+/// The message for a login page reached with `?oidc_error=<code>`, if the code is known.
+pub(crate) fn error_message(code: &str) -> Option<&'static str> {
+    match code {
+        "refused" => Some("Logging in with your university account was cancelled or refused."),
+        "not_enrolled" => Some("Your university account is not enrolled in this class."),
+        "failed" => Some("Logging in with your university account failed. Please try again."),
+        _ => None,
+    }
+}
+
+fn login_failed(log: &slog::Logger, e: OidcError) -> Redirect {
+    warn!(log, "OIDC login failed: {}", e);
+    Redirect::to(format!("/login?oidc_error={}", e.code()))
+}
+
+#[derive(Debug, Deserialize)]
+struct ProviderMetadata {
+    issuer: String,
+    authorization_endpoint: String,
+    token_endpoint: String,
+    userinfo_endpoint: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct TokenResponse {
+    id_token: String,
+    access_token: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct IdTokenClaims {
+    iss: String,
+    sub: String,
+    aud: serde_json::Value,
+    exp: i64,
+    nonce: Option<String>,
+    email: Option<String>,
+    email_verified: Option<bool>,
+}
+
+#[derive(Debug, Deserialize)]
+struct UserInfo {
+    sub: String,
+    email: Option<String>,
+    email_verified: Option<bool>,
+}
+
+fn random_string() -> String {
+    thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(32)
+        .map(char::from)
+        .collect()
+}
+
+fn redirect_uri(config: &Config) -> String {
+    format!("{}/oidc/callback", config.base_url)
+}
+
+async fn discover(oidc: &OidcConfig) -> Result<ProviderMetadata, OidcError> {
+    let url = format!(
+        "{}/.well-known/openid-configuration",
+        oidc.issuer.trim_end_matches('/')
+    );
+    Ok(reqwest::get(&url).await?.json::<ProviderMetadata>().await?)
+}
+
+/// Decodes the ID token's claims. The token comes straight from the token endpoint over the
+/// back channel, so we rely on the transport rather than the signature (OIDC Core 3.1.3.7) and
+/// only validate issuer, audience, expiry and nonce. The email claims are checked by the caller.
+fn validate_id_token(
+    id_token: &str,
+    provider: &ProviderMetadata,
+    oidc: &OidcConfig,
+    nonce: &str,
+) -> Result<IdTokenClaims, OidcError> {
+    let payload = id_token.split('.').nth(1).ok_or(OidcError::BadToken)?;
+    let bytes =
+        base64::decode_config(payload, base64::URL_SAFE_NO_PAD).map_err(|_| OidcError::BadToken)?;
+    let claims: IdTokenClaims = serde_json::from_slice(&bytes).map_err(|_| OidcError::BadToken)?;
+
+    let aud_ok = match claims.aud {
+        serde_json::Value::String(ref a) => *a == oidc.client_id,
+        serde_json::Value::Array(ref auds) => auds.iter().any(|a| *a == *oidc.client_id),
+        _ => false,
+    };
+    if claims.iss != provider.issuer
+        || !aud_ok
+        || claims.exp < Utc::now().timestamp()
+        || claims.nonce.as_deref() != Some(nonce)
+    {
+        return Err(OidcError::BadToken);
+    }
+    Ok(claims)
+}
+
+/// Fetches the user's verified email from the userinfo endpoint, for providers that leave it
+/// out of the ID token.
+async fn userinfo_email(
+    provider: &ProviderMetadata,
+    token: &TokenResponse,
+    sub: &str,
+) -> Result<String, OidcError> {
+    let (endpoint, access_token) = match (&provider.userinfo_endpoint, &token.access_token) {
+        (Some(e), Some(t)) => (e, t),
+        _ => return Err(OidcError::Unverified),
+    };
+    let info: UserInfo = reqwest::Client::new()
+        .get(endpoint)
+        .bearer_auth(access_token)
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await?;
+    // the response must be about the user the ID token was issued for (OIDC Core 5.3.2)
+    if info.sub != sub {
+        return Err(OidcError::BadToken);
+    }
+    match (info.email, info.email_verified) {
+        (Some(email), Some(true)) => Ok(email),
+        _ => Err(OidcError::Unverified),
+    }
+}
+
+fn domain_allowed(oidc: &OidcConfig, email: &str) -> bool {
+    oidc.allowed_domains.is_empty()
+        || email
+            .rsplit('@')
+            .next()
+            .map(|d| oidc.allowed_domains.iter().any(|a| a.eq_ignore_ascii_case(d)))
+            .unwrap_or(false)
+}
+
+async fn start(cookies: &CookieJar<'_>, config: &Config) -> Result<Redirect, OidcError> {
+    let oidc = config.oidc.as_ref().ok_or(OidcError::Disabled)?;
+    let provider = discover(oidc).await?;
+
+    let state = random_string();
+    let nonce = random_string();
+    // the IdP redirects back cross-site, so these cannot be SameSite=Strict
+    cookies.add(
+        Cookie::build("oidc_state", state.clone())
+            .path("/")
+            .same_site(SameSite::Lax)
+            .http_only(true)
+            .finish(),
+    );
+    cookies.add(
+        Cookie::build("oidc_nonce", nonce.clone())
+            .path("/")
+            .same_site(SameSite::Lax)
+            .http_only(true)
+            .finish(),
+    );
+
+    let url = Url::parse_with_params(
+        &provider.authorization_endpoint,
+        &[
+            ("response_type", "code"),
+            ("client_id", oidc.client_id.as_str()),
+            ("redirect_uri", redirect_uri(config).as_str()),
+            ("scope", "openid email"),
+            ("state", state.as_str()),
+            ("nonce", nonce.as_str()),
+        ],
+    )
+    .map_err(|_| OidcError::Disabled)?;
+    Ok(Redirect::to(url.to_string()))
+}
+
+/// Redeems an authorization code with the provider and returns the user's verified email, if it
+/// is in an allowed domain.
+async fn verified_email(code: &str, nonce: &str, config: &Config) -> Result<String, OidcError> {
+    let oidc = config.oidc.as_ref().ok_or(OidcError::Disabled)?;
+    let provider = discover(oidc).await?;
+    let token: TokenResponse = reqwest::Client::new()
+        .post(&provider.token_endpoint)
+        .form(&[
+            ("grant_type", "authorization_code"),
+            ("code", code),
+            ("redirect_uri", redirect_uri(config).as_str()),
+            ("client_id", oidc.client_id.as_str()),
+            ("client_secret", oidc.client_secret.as_str()),
+        ])
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await?;
+
+    let claims = validate_id_token(&token.id_token, &provider, oidc, nonce)?;
+    let email = match (claims.email, claims.email_verified) {
+        (Some(email), Some(true)) => email,
+        _ => userinfo_email(&provider, &token, &claims.sub).await?,
+    };
+    if !domain_allowed(oidc, &email) {
+        return Err(OidcError::DomainNotAllowed);
+    }
+    Ok(email)
+}
+
+async fn finish(
+    code: &str,
+    state: &str,
+    cookies: &CookieJar<'_>,
+    backend: &Arc<Mutex<MySqlBackend>>,
+    config: &Config,
+) -> Result<(), OidcError> {
+    config.oidc.as_ref().ok_or(OidcError::Disabled)?;
+
+    let expected_state = cookies.get("oidc_state").map(|c| c.value().to_string());
+    let nonce = cookies.get("oidc_nonce").map(|c| c.value().to_string());
+    cookies.remove(Cookie::named("oidc_state"));
+    cookies.remove(Cookie::named("oidc_nonce"));
+    if expected_state.as_deref() != Some(state) {
+        return Err(OidcError::BadState);
+    }
+    let nonce = nonce.ok_or(OidcError::BadState)?;
+
+    let email = verified_email(code, &nonce, config).await?;
+
+    // map the verified email to a user, provisioning one on first login
+    let mut bg = backend.lock().unwrap();
+    let res = bg.prep_exec(
+        "SELECT apikey FROM users WHERE email = ?",
+        vec![email.as_str().into()],
+    );
+    let key: String = if !res.is_empty() {
+        from_value(res[0][0].clone())
+    } else if !roster::valid_email(&email) || !roster::may_register(&mut bg, config, &email) {
+        return Err(OidcError::NotEnrolled);
+    } else {
+        apikey::create_user(&mut bg, config, &email, &Demographics::undisclosed())
+    };
+    drop(bg);
+
+    let cookie = Cookie::build("apikey", key).path("/").finish();
+    cookies.add(cookie);
+    Ok(())
+}
+
+#[get("/login")]
+pub(crate) async fn login(
+    cookies: &CookieJar<'_>,
+    backend: &State<Arc<Mutex<MySqlBackend>>>,
+    config: &State<Config>,
+) -> Redirect {
+    match start(cookies, config).await {
+        Ok(r) => r,
+        Err(e) => login_failed(&backend.lock().unwrap().log.clone(), e),
+    }
+}
+
+/// The code the provider redirected back with, unless it sent an error instead.
+fn authorization_code(code: Option<String>, error: Option<String>) -> Result<String, OidcError> {
+    match (code, error) {
+        (_, Some(error)) => Err(OidcError::Refused(error)),
+        (Some(code), None) => Ok(code),
+        (None, None) => Err(OidcError::BadState),
+    }
+}
+
+/// The provider redirects back here with either a code and state, or an error (and state).
+#[get("/callback?<code>&<state>&<error>")]
+pub(crate) async fn callback(
+    code: Option<String>,
+    state: Option<String>,
+    error: Option<String>,
+    cookies: &CookieJar<'_>,
+    backend: &State<Arc<Mutex<MySqlBackend>>>,
+    config: &State<Config>,
+) -> Redirect {
+    let res = match authorization_code(code, error) {
+        Ok(code) => {
+            let state = state.unwrap_or_default();
+            finish(&code, &state, cookies, backend, config).await
+        }
+        Err(e) => {
+            cookies.remove(Cookie::named("oidc_state"));
+            cookies.remove(Cookie::named("oidc_nonce"));
+            Err(e)
+        }
+    };
+    match res {
+        Ok(()) => Redirect::to("/leclist"),
+        Err(e) => login_failed(&backend.lock().unwrap().log.clone(), e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config;
+    use rocket::fairing::AdHoc;
+    use rocket::http::{ContentType, Status};
+    use rocket::outcome::Outcome;
+    use rocket::request::{self, FromRequest, Request};
+    use rocket::tokio::sync::oneshot;
+
+    const CODE: &str = "good-code";
+    const ACCESS_TOKEN: &str = "access-token";
+    const NONCE: &str = "the-nonce";
+
+    /// What the mock issuer says about the user.
+    struct MockUser {
+        /// Set once the issuer knows its port
+        issuer: Mutex<String>,
+        email: &'static str,
+        /// Only release the email through the userinfo endpoint
+        email_in_userinfo_only: bool,
+    }
+
+    struct Bearer(String);
+
+    #[rocket::async_trait]
+    impl<'r> FromRequest<'r> for Bearer {
+        type Error = ();
+
+        async fn from_request(request: &'r Request<'_>) -> request::Outcome<Self, ()> {
+            match request.headers().get_one("Authorization") {
+                Some(a) => Outcome::Success(Bearer(a.trim_start_matches("Bearer ").into())),
+                None => Outcome::Failure((Status::Unauthorized, ())),
+            }
+        }
+    }
+
+    #[derive(FromForm)]
+    struct TokenRequest {
+        grant_type: String,
+        code: String,
+        client_secret: String,
+    }
+
+    #[get("/.well-known/openid-configuration")]
+    fn discovery(user: &State<MockUser>) -> (ContentType, String) {
+        let issuer = user.issuer.lock().unwrap().clone();
+        let metadata = serde_json::json!({
+            "issuer": issuer,
+            "authorization_endpoint": format!("{}/authorize", issuer),
+            "token_endpoint": format!("{}/token", issuer),
+            "userinfo_endpoint": format!("{}/userinfo", issuer),
+        });
+        (ContentType::JSON, metadata.to_string())
+    }
+
+    #[post("/token", data = "<data>")]
+    fn token(
+        data: rocket::form::Form<TokenRequest>,
+        user: &State<MockUser>,
+    ) -> Result<(ContentType, String), Status> {
+        if data.grant_type != "authorization_code" || data.code != CODE {
+            return Err(Status::BadRequest);
+        }
+        if data.client_secret != "CLIENT_SECRET" {
+            return Err(Status::Unauthorized);
+        }
+        let mut claims = serde_json::json!({
+            "iss": *user.issuer.lock().unwrap(),
+            "sub": "user-1",
+            "aud": "websubmit",
+            "exp": Utc::now().timestamp() + 300,
+            "nonce": NONCE,
+        });
+        if !user.email_in_userinfo_only {
+            claims["email"] = user.email.into();
+            claims["email_verified"] = true.into();
+        }
+        let id_token = format!(
+            "e30.{}.sig",
+            base64::encode_config(claims.to_string(), base64::URL_SAFE_NO_PAD)
+        );
+        let response = serde_json::json!({
+            "id_token": id_token,
+            "access_token": ACCESS_TOKEN,
+            "token_type": "Bearer",
+        });
+        Ok((ContentType::JSON, response.to_string()))
+    }
+
+    #[get("/userinfo")]
+    fn userinfo(bearer: Bearer, user: &State<MockUser>) -> Result<(ContentType, String), Status> {
+        if bearer.0 != ACCESS_TOKEN {
+            return Err(Status::Unauthorized);
+        }
+        let info = serde_json::json!({
+            "sub": "user-1",
+            "email": user.email,
+            "email_verified": true,
+        });
+        Ok((ContentType::JSON, info.to_string()))
+    }
+
+    /// Starts a mock issuer on a free local port and returns a config that uses it. The issuer
+    /// binds port 0 itself and reports the port it got, so that no other process can take the
+    /// port in between.
+    async fn mock_issuer(email: &'static str, email_in_userinfo_only: bool) -> Config {
+        let figment = rocket::Config::figment()
+            .merge(("address", "127.0.0.1"))
+            .merge(("port", 0))
+            .merge(("log_level", "off"));
+        let (ready, started) = oneshot::channel();
+        let rocket = rocket::custom(figment)
+            .manage(MockUser {
+                issuer: Mutex::new(String::new()),
+                email,
+                email_in_userinfo_only,
+            })
+            .mount("/", routes![discovery, token, userinfo])
+            .attach(AdHoc::on_liftoff("Ready", |rocket| {
+                Box::pin(async move {
+                    let issuer = format!("http://127.0.0.1:{}", rocket.config().port);
+                    *rocket.state::<MockUser>().unwrap().issuer.lock().unwrap() = issuer.clone();
+                    ready.send(issuer).unwrap();
+                })
+            }));
+        rocket::tokio::spawn(rocket.launch());
+        let issuer = started.await.unwrap();
+
+        let mut config = config::parse("sample-config.toml").unwrap();
+        config.oidc = Some(OidcConfig {
+            issuer,
+            client_id: String::from("websubmit"),
+            client_secret: String::from("CLIENT_SECRET"),
+            allowed_domains: vec![String::from("brown.edu")],
+        });
+        config
+    }
+
+    #[rocket::async_test]
+    async fn email_from_id_token() {
+        let config = mock_issuer("student@brown.edu", false).await;
+        let email = verified_email(CODE, NONCE, &config).await.unwrap();
+        assert_eq!(email, "student@brown.edu");
+    }
+
+    #[rocket::async_test]
+    async fn email_from_userinfo() {
+        let config = mock_issuer("student@brown.edu", true).await;
+        let email = verified_email(CODE, NONCE, &config).await.unwrap();
+        assert_eq!(email, "student@brown.edu");
+    }
+
+    #[rocket::async_test]
+    async fn rejects_wrong_nonce() {
+        let config = mock_issuer("student@brown.edu", false).await;
+        let res = verified_email(CODE, "another-nonce", &config).await;
+        assert!(matches!(res, Err(OidcError::BadToken)));
+    }
+
+    #[rocket::async_test]
+    async fn rejects_bad_code() {
+        let config = mock_issuer("student@brown.edu", false).await;
+        let res = verified_email("bad-code", NONCE, &config).await;
+        assert!(matches!(res, Err(OidcError::Provider(_))));
+    }
+
+    #[rocket::async_test]
+    async fn rejects_other_domains() {
+        let config = mock_issuer("someone@example.com", false).await;
+        let res = verified_email(CODE, NONCE, &config).await;
+        assert!(matches!(res, Err(OidcError::DomainNotAllowed)));
+    }
+
+    #[test]
+    fn login_page_shows_only_known_errors() {
+        for code in &["refused", "not_enrolled", "failed"] {
+            assert!(error_message(code).is_some());
+        }
+        assert_eq!(error_message("<script>"), None);
+        assert_eq!(OidcError::Refused("access_denied".into()).code(), "refused");
+        assert_eq!(OidcError::NotEnrolled.code(), "not_enrolled");
+        assert_eq!(OidcError::BadState.code(), "failed");
+    }
+
+    #[test]
+    fn provider_errors_are_not_redeemed() {
+        let code = |c: Option<&str>, e: Option<&str>| {
+            authorization_code(c.map(String::from), e.map(String::from))
+        };
+        assert_eq!(code(Some("abc"), None).unwrap(), "abc");
+        assert!(matches!(
+            code(None, Some("access_denied")),
+            Err(OidcError::Refused(e)) if e == "access_denied"
+        ));
+        assert!(matches!(code(Some("abc"), Some("x")), Err(OidcError::Refused(_))));
+        assert!(matches!(code(None, None), Err(OidcError::BadState)));
+    }
+}
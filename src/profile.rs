@@ -0,0 +1,285 @@
+use crate::apikey::ApiKey;
+use crate::backend::MySqlBackend;
+use crate::config::Config;
+use crate::csrf::{CsrfForm, CsrfToken};
+use crate::impersonate;
+use mysql::from_value;
+use rocket::State;
+use rocket_dyn_templates::Template;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+/// Offered for every demographic field, in addition to the configured choices.
+pub(crate) const PREFER_NOT_TO_SAY: &str = "prefer not to say";
+/// What unrecognized legacy free-text values are counted as.
+const OTHER: &str = "other";
+
+/// A user's demographics as stored in `users`, once validated.
+pub(crate) struct Demographics<'a> {
+    pub gender: &'a str,
+    pub age: Option<u32>,
+    pub ethnicity: &'a str,
+    pub is_remote: bool,
+    pub education: &'a str,
+}
+
+impl Demographics<'static> {
+    /// For users who have not (yet) said anything about themselves.
+    pub fn undisclosed() -> Self {
+        Demographics {
+            gender: PREFER_NOT_TO_SAY,
+            age: None,
+            ethnicity: PREFER_NOT_TO_SAY,
+            is_remote: false,
+            education: PREFER_NOT_TO_SAY,
+        }
+    }
+}
+
+#[derive(Debug, FromForm)]
+pub(crate) struct ProfileForm {
+    gender: String,
+    age: String,
+    ethnicity: String,
+    is_remote: bool,
+    education: String,
+}
+
+#[derive(Serialize)]
+pub(crate) struct ChoiceOption {
+    value: String,
+    selected: bool,
+}
+
+/// Demographic form fields with their choices and current values.
+#[derive(Serialize)]
+pub(crate) struct DemographicsFields {
+    genders: Vec<ChoiceOption>,
+    ethnicities: Vec<ChoiceOption>,
+    educations: Vec<ChoiceOption>,
+    age: String,
+    min_age: u32,
+    max_age: u32,
+    is_remote: bool,
+}
+
+#[derive(Serialize)]
+struct ProfileContext {
+    email: String,
+    pseudonym: String,
+    demographics: DemographicsFields,
+    errors: HashMap<&'static str, String>,
+    saved: bool,
+    csrf_token: String,
+    impersonating: Option<String>,
+    parent: &'static str,
+}
+
+fn options(choices: &[String], current: &str) -> Vec<ChoiceOption> {
+    choices
+        .iter()
+        .map(|c| c.as_str())
+        .chain(std::iter::once(PREFER_NOT_TO_SAY))
+        .map(|c| ChoiceOption {
+            value: c.to_string(),
+            selected: c == current,
+        })
+        .collect()
+}
+
+pub(crate) fn fields(
+    config: &Config,
+    gender: &str,
+    age: &str,
+    ethnicity: &str,
+    is_remote: bool,
+    education: &str,
+) -> DemographicsFields {
+    let d = &config.demographics;
+    DemographicsFields {
+        genders: options(&d.genders, gender),
+        ethnicities: options(&d.ethnicities, ethnicity),
+        educations: options(&d.educations, education),
+        age: age.to_string(),
+        min_age: d.min_age,
+        max_age: d.max_age,
+        is_remote,
+    }
+}
+
+fn check_choice(
+    errors: &mut HashMap<&'static str, String>,
+    field: &'static str,
+    choices: &[String],
+    value: &str,
+) {
+    if value != PREFER_NOT_TO_SAY && !choices.iter().any(|c| c == value) {
+        errors.insert(field, format!("Please pick one of the listed options for {}.", field));
+    }
+}
+
+/// Validates submitted demographics against the configured choices. On success, returns the
+/// age (`None` if left blank, i.e., "prefer not to say"); otherwise, error messages by field.
+pub(crate) fn validate(
+    config: &Config,
+    gender: &str,
+    age: &str,
+    ethnicity: &str,
+    education: &str,
+) -> Result<Option<u32>, HashMap<&'static str, String>> {
+    let d = &config.demographics;
+    let mut errors = HashMap::new();
+    check_choice(&mut errors, "gender", &d.genders, gender);
+    check_choice(&mut errors, "ethnicity", &d.ethnicities, ethnicity);
+    check_choice(&mut errors, "education", &d.educations, education);
+
+    let age = age.trim();
+    let parsed = if age.is_empty() {
+        None
+    } else {
+        match age.parse::<u32>() {
+            Ok(a) if a >= d.min_age && a <= d.max_age => Some(a),
+            _ => {
+                errors.insert(
+                    "age",
+                    format!(
+                        "Age must be a whole number between {} and {}, or left blank.",
+                        d.min_age, d.max_age
+                    ),
+                );
+                None
+            }
+        }
+    };
+
+    if errors.is_empty() {
+        Ok(parsed)
+    } else {
+        Err(errors)
+    }
+}
+
+/// Maps a stored value onto the configured choices, so that legacy free-text entries
+/// ("Female", " female ") aggregate together.
+pub(crate) fn normalize(choices: &[String], value: &str) -> String {
+    let value = value.trim();
+    if value.is_empty() || value.eq_ignore_ascii_case(PREFER_NOT_TO_SAY) {
+        return String::from(PREFER_NOT_TO_SAY);
+    }
+    choices
+        .iter()
+        .find(|c| c.eq_ignore_ascii_case(value))
+        .cloned()
+        .unwrap_or(String::from(OTHER))
+}
+
+fn render_profile(
+    apikey: &ApiKey,
+    pseudonym: String,
+    demographics: DemographicsFields,
+    errors: HashMap<&'static str, String>,
+    saved: bool,
+    csrf: CsrfToken,
+) -> Template {
+    let ctx = ProfileContext {
+        email: apikey.user.clone(),
+        pseudonym,
+        demographics,
+        errors,
+        saved,
+        csrf_token: csrf.0,
+        impersonating: impersonate::viewing_as(apikey),
+        parent: "layout",
+    };
+    Template::render("profile", &ctx)
+}
+
+#[get("/profile")]
+pub(crate) fn profile(
+    apikey: ApiKey,
+    csrf: CsrfToken,
+    backend: &State<Arc<Mutex<MySqlBackend>>>,
+    config: &State<Config>,
+) -> Template {
+    let mut bg = backend.lock().unwrap();
+    let res = bg.prep_exec(
+        "SELECT pseudonym, gender, age, ethnicity, is_remote, education FROM users \
+         WHERE email = ?",
+        vec![apikey.user.as_str().into()],
+    );
+    drop(bg);
+
+    let r = &res[0];
+    let demographics = fields(
+        config,
+        &normalize(&config.demographics.genders, &from_value::<String>(r[1].clone())),
+        &from_value::<Option<u32>>(r[2].clone())
+            .map(|a| a.to_string())
+            .unwrap_or_default(),
+        &normalize(&config.demographics.ethnicities, &from_value::<String>(r[3].clone())),
+        from_value::<bool>(r[4].clone()),
+        &normalize(&config.demographics.educations, &from_value::<String>(r[5].clone())),
+    );
+    render_profile(
+        &apikey,
+        from_value(r[0].clone()),
+        demographics,
+        HashMap::new(),
+        false,
+        csrf,
+    )
+}
+
+#[post("/profile", data = "<data>")]
+pub(crate) fn profile_submit(
+    apikey: ApiKey,
+    data: CsrfForm<ProfileForm>,
+    csrf: CsrfToken,
+    backend: &State<Arc<Mutex<MySqlBackend>>>,
+    config: &State<Config>,
+) -> Template {
+    let result = validate(config, &data.gender, &data.age, &data.ethnicity, &data.education);
+
+    let mut bg = backend.lock().unwrap();
+    if let Ok(age) = result {
+        let gender = bg.seal("users", "gender", data.gender.as_str().into());
+        let ethnicity = bg.seal("users", "ethnicity", data.ethnicity.as_str().into());
+        let education = bg.seal("users", "education", data.education.as_str().into());
+        bg.prep_exec(
+            "UPDATE users SET gender = ?, age = ?, ethnicity = ?, is_remote = ?, education = ? \
+             WHERE email = ?",
+            vec![
+                gender,
+                age.into(),
+                ethnicity,
+                data.is_remote.into(),
+                education,
+                apikey.user.as_str().into(),
+            ],
+        );
+    }
+    let res = bg.prep_exec(
+        "SELECT pseudonym FROM users WHERE email = ?",
+        vec![apikey.user.as_str().into()],
+    );
+    drop(bg);
+
+    // re-render with what was submitted, so that errors can be fixed in place
+    let demographics = fields(
+        config,
+        &data.gender,
+        &data.age,
+        &data.ethnicity,
+        data.is_remote,
+        &data.education,
+    );
+    let saved = result.is_ok();
+    render_profile(
+        &apikey,
+        from_value(res[0][0].clone()),
+        demographics,
+        result.err().unwrap_or_default(),
+        saved,
+        csrf,
+    )
+}
@@ -0,0 +1,214 @@
+use crate::backend::MySqlBackend;
+use crate::config::Config;
+use crate::deadline::{self, Deadline};
+use chrono::naive::NaiveDateTime;
+use mysql::from_value;
+use std::collections::{HashMap, HashSet};
+
+/// What a student has submitted for one lecture. Only questions that still exist count.
+#[derive(Debug, Default)]
+pub(crate) struct Progress {
+    /// Questions with a submitted answer
+    pub answered: u64,
+    /// Latest submission of any version of any answer
+    pub last_submission: Option<NaiveDateTime>,
+    /// The graded answers' submission times and grades
+    graded: Vec<(NaiveDateTime, u64)>,
+}
+
+impl Progress {
+    /// Adds a graded answer. Questions left blank are submitted as empty answers, which do not
+    /// count as answered.
+    fn record(&mut self, submitted_at: NaiveDateTime, grade: u64, answer: &str) {
+        if !is_blank(answer) {
+            self.answered += 1;
+        }
+        self.last_submission = self.last_submission.max(Some(submitted_at));
+        self.graded.push((submitted_at, grade));
+    }
+
+    /// Whether any graded answer was submitted after the (student's) deadline.
+    pub(crate) fn is_late(&self, d: &Deadline) -> bool {
+        self.graded.iter().any(|(t, _)| d.is_late(*t))
+    }
+
+    /// Sum of the lecture's grades, after late penalties.
+    pub(crate) fn grade(&self, config: &Config, d: &Deadline) -> u64 {
+        self.graded
+            .iter()
+            .map(|(t, g)| deadline::apply_penalty(*g, d.late_penalty(config, *t)))
+            .sum()
+    }
+}
+
+fn is_blank(answer: &str) -> bool {
+    answer.trim().is_empty()
+}
+
+/// A student's progress on every lecture they have answered, by lecture.
+pub(crate) fn for_student(bg: &mut MySqlBackend, email: &str) -> HashMap<u64, Progress> {
+    let mut progress: HashMap<u64, Progress> = HashMap::new();
+    let res = bg.prep_exec(
+        "SELECT a.lec, a.submitted_at, a.grade, a.answer FROM answers a \
+         JOIN questions qs ON (a.lec = qs.lec AND a.q = qs.q) WHERE a.email = ?",
+        vec![email.into()],
+    );
+    for r in res {
+        // answers may be encrypted, so blank ones are skipped here rather than in the query
+        let p = progress.entry(from_value(r[0].clone())).or_default();
+        let answer: String = from_value(r[3].clone());
+        p.record(from_value(r[1].clone()), from_value(r[2].clone()), &answer);
+    }
+    // the graded version is not necessarily the latest one
+    let res = bg.prep_exec(
+        "SELECT v.lec, MAX(v.submitted_at) FROM answer_versions v \
+         JOIN questions qs ON (v.lec = qs.lec AND v.q = qs.q) WHERE v.email = ? GROUP BY v.lec",
+        vec![email.into()],
+    );
+    for r in res {
+        if let Some(p) = progress.get_mut(&from_value(r[0].clone())) {
+            p.last_submission = p.last_submission.max(from_value(r[1].clone()));
+        }
+    }
+    progress
+}
+
+/// How far the class is with one lecture.
+#[derive(Debug, Serialize)]
+pub(crate) struct Completion {
+    /// Registered students (not admins or staff)
+    pub students: u64,
+    /// Students who answered at least one question
+    pub started: u64,
+    /// Students who answered every question
+    pub completed: u64,
+    /// `completed` as a percentage of `students`
+    pub percent: u64,
+}
+
+/// Class-wide completion of every lecture, given each lecture's number of questions.
+pub(crate) fn class_completion(
+    bg: &mut MySqlBackend,
+    config: &Config,
+    num_qs: &HashMap<u64, u64>,
+) -> HashMap<u64, Completion> {
+    let students: HashSet<String> = bg
+        .prep_exec("SELECT email FROM users", vec![])
+        .into_iter()
+        .map(|r| from_value::<String>(r[0].clone()))
+        .filter(|e| {
+            !config.admins.contains(e) && !config.managers.contains(e) && !config.staff.contains(e)
+        })
+        .collect();
+    // answers may be encrypted, so they are counted here rather than grouped in the query
+    let res = bg.prep_exec(
+        "SELECT a.lec, a.email, a.answer FROM answers a \
+         JOIN questions qs ON (a.lec = qs.lec AND a.q = qs.q)",
+        vec![],
+    );
+    let answers = res.into_iter().map(|r| {
+        (
+            from_value(r[0].clone()),
+            from_value(r[1].clone()),
+            from_value(r[2].clone()),
+        )
+    });
+    tally(&students, num_qs, answers)
+}
+
+/// Completion from (lecture, email, answer) rows, counting only students' non-blank answers.
+fn tally(
+    students: &HashSet<String>,
+    num_qs: &HashMap<u64, u64>,
+    answers: impl Iterator<Item = (u64, String, String)>,
+) -> HashMap<u64, Completion> {
+    let mut answered: HashMap<(u64, String), u64> = HashMap::new();
+    for (lec, email, answer) in answers {
+        if students.contains(&email) && !is_blank(&answer) {
+            *answered.entry((lec, email)).or_default() += 1;
+        }
+    }
+
+    let mut completion: HashMap<u64, Completion> = num_qs
+        .keys()
+        .map(|lec| {
+            let c = Completion {
+                students: students.len() as u64,
+                started: 0,
+                completed: 0,
+                percent: 0,
+            };
+            (*lec, c)
+        })
+        .collect();
+    for ((lec, _), n) in answered {
+        if let Some(c) = completion.get_mut(&lec) {
+            c.started += 1;
+            if n >= num_qs[&lec] {
+                c.completed += 1;
+            }
+        }
+    }
+    for c in completion.values_mut() {
+        c.percent = (c.completed * 100).checked_div(c.students).unwrap_or(0);
+    }
+    completion
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::NaiveDate;
+
+    fn at(hour: u32) -> NaiveDateTime {
+        NaiveDate::from_ymd(2021, 9, 1).and_hms(hour, 0, 0)
+    }
+
+    #[test]
+    fn blank_answers_are_not_answered() {
+        let mut p = Progress::default();
+        p.record(at(10), 1, "42");
+        p.record(at(12), 0, "");
+        p.record(at(11), 0, "  \n");
+        assert_eq!(p.answered, 1);
+        // but they were still submitted
+        assert_eq!(p.last_submission, Some(at(12)));
+        assert_eq!(p.graded.len(), 3);
+    }
+
+    #[test]
+    fn completion_counts_students_non_blank_answers() {
+        let students: HashSet<String> = vec!["a@x.edu", "b@x.edu", "c@x.edu"]
+            .into_iter()
+            .map(String::from)
+            .collect();
+        let num_qs: HashMap<u64, u64> = vec![(1, 2), (2, 1)].into_iter().collect();
+        let rows = vec![
+            (1, "a@x.edu", "yes"),
+            (1, "a@x.edu", "no"),
+            // submitting an untouched lecture neither starts nor completes it
+            (1, "b@x.edu", ""),
+            (1, "b@x.edu", ""),
+            (1, "c@x.edu", "maybe"),
+            (1, "c@x.edu", ""),
+            // not a student
+            (2, "ta@x.edu", "yes"),
+        ];
+        let c = tally(
+            &students,
+            &num_qs,
+            rows.into_iter()
+                .map(|(l, e, a)| (l, e.to_string(), a.to_string())),
+        );
+        assert_eq!((c[&1].students, c[&1].started, c[&1].completed), (3, 2, 1));
+        assert_eq!(c[&1].percent, 33);
+        assert_eq!((c[&2].started, c[&2].completed, c[&2].percent), (0, 0, 0));
+    }
+
+    #[test]
+    fn completion_without_students() {
+        let num_qs: HashMap<u64, u64> = vec![(1, 1)].into_iter().collect();
+        let c = tally(&HashSet::new(), &num_qs, std::iter::empty());
+        assert_eq!(c[&1].percent, 0);
+    }
+}
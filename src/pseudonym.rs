@@ -0,0 +1,284 @@
+use crate::admin::Admin;
+use crate::backend::MySqlBackend;
+use crate::config::{Config, PseudonymStyle};
+use crate::csrf::{CsrfForm, CsrfToken};
+use crate::login::safe_next;
+use chrono::Local;
+use mysql::from_value;
+use rand::distributions::Alphanumeric;
+use rand::seq::SliceRandom;
+use rand::{thread_rng, Rng};
+use rocket::http::{Cookie, CookieJar, Status};
+use rocket::request::{self, FromRequest, Request};
+use rocket::response::Redirect;
+use rocket::State;
+use rocket_dyn_templates::Template;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+/// Cookie set while staff view grades and answers under pseudonyms.
+const COOKIE: &str = "anonymous_view";
+/// Name of the term before any regeneration.
+const INITIAL_TERM: &str = "initial";
+
+const ADJECTIVES: &[&str] = &[
+    "agile", "amber", "bold", "brave", "bright", "brisk", "calm", "clever", "cosmic", "curious",
+    "daring", "eager", "fancy", "fierce", "gentle", "golden", "happy", "humble", "jolly", "keen",
+    "kind", "lively", "lucky", "merry", "mighty", "misty", "nimble", "noble", "patient", "plucky",
+    "polite", "proud", "quick", "quiet", "rapid", "rustic", "shy", "silent", "silver", "sleepy",
+    "smart", "snowy", "spry", "steady", "sunny", "swift", "tidy", "vivid", "witty", "zesty",
+];
+
+const ANIMALS: &[&str] = &[
+    "alpaca", "badger", "beaver", "bison", "camel", "cheetah", "cobra", "crane", "dingo",
+    "dolphin", "eagle", "falcon", "ferret", "gecko", "gibbon", "heron", "hyena", "ibis", "iguana",
+    "jackal", "koala", "lemur", "llama", "lynx", "magpie", "marmot", "moose", "newt", "ocelot",
+    "orca", "otter", "owl", "panda", "pelican", "puffin", "quail", "rabbit", "raven", "salmon",
+    "seal", "sloth", "stork", "tapir", "tiger", "toucan", "turtle", "walrus", "wombat", "yak",
+    "zebra",
+];
+
+#[derive(Debug, FromForm)]
+pub(crate) struct RegenerateForm {
+    term: String,
+}
+
+#[derive(Debug, FromForm)]
+pub(crate) struct ViewForm {
+    anonymous: bool,
+    next: Option<String>,
+}
+
+#[derive(Serialize)]
+struct TermEntry {
+    term: String,
+    started_at: String,
+}
+
+#[derive(Serialize)]
+struct PseudonymsContext {
+    current_term: String,
+    style: &'static str,
+    num_users: u64,
+    terms: Vec<TermEntry>,
+    anonymous: bool,
+    csrf_token: String,
+    parent: &'static str,
+}
+
+fn candidate(style: PseudonymStyle) -> String {
+    let mut rng = thread_rng();
+    match style {
+        PseudonymStyle::Random => (&mut rng)
+            .sample_iter(&Alphanumeric)
+            .take(16)
+            .map(char::from)
+            .collect(),
+        PseudonymStyle::AdjectiveAnimal => format!(
+            "{}-{}-{}",
+            ADJECTIVES.choose(&mut rng).unwrap(),
+            ANIMALS.choose(&mut rng).unwrap(),
+            rng.gen_range(10..100)
+        ),
+    }
+}
+
+fn is_taken(bg: &mut MySqlBackend, pseudonym: &str) -> bool {
+    let res = bg.prep_exec(
+        "SELECT (SELECT COUNT(*) FROM users WHERE pseudonym = ?) \
+         + (SELECT COUNT(*) FROM pseudonym_history WHERE pseudonym = ?)",
+        vec![pseudonym.into(), pseudonym.into()],
+    );
+    from_value::<u64>(res[0][0].clone()) > 0
+}
+
+/// Returns a pseudonym that no user has, or had in an earlier term, so pseudonyms in old
+/// anonymized records never become ambiguous. The caller must hold the backend lock until the
+/// pseudonym is stored.
+pub(crate) fn generate(bg: &mut MySqlBackend, config: &Config) -> String {
+    loop {
+        let p = candidate(config.pseudonym_style);
+        if !is_taken(bg, &p) {
+            return p;
+        }
+    }
+}
+
+pub(crate) fn current_term(bg: &mut MySqlBackend) -> String {
+    let res = bg.prep_exec(
+        "SELECT term FROM pseudonym_terms ORDER BY started_at DESC LIMIT 1",
+        vec![],
+    );
+    res.first()
+        .map(|r| from_value(r[0].clone()))
+        .unwrap_or(String::from(INITIAL_TERM))
+}
+
+/// Starts a new term: archives every user's pseudonym under the ending term and assigns a fresh
+/// one. Returns the number of users given new pseudonyms.
+pub(crate) fn regenerate_all(bg: &mut MySqlBackend, config: &Config, new_term: &str) -> u64 {
+    let old_term = current_term(bg);
+    let now = Local::now().naive_local();
+    let users = bg.prep_exec("SELECT email, pseudonym FROM users", vec![]);
+
+    // archive all first, so that no new pseudonym repeats any old one
+    for r in &users {
+        bg.insert(
+            "pseudonym_history",
+            vec![
+                r[0].clone(),
+                r[1].clone(),
+                old_term.as_str().into(),
+                now.into(),
+            ],
+        );
+    }
+    for r in &users {
+        let p = generate(bg, config);
+        bg.prep_exec(
+            "UPDATE users SET pseudonym = ? WHERE email = ?",
+            vec![p.into(), r[0].clone()],
+        );
+    }
+    bg.insert("pseudonym_terms", vec![new_term.into(), now.into()]);
+
+    users.len() as u64
+}
+
+/// Maps between emails and current pseudonyms, for pages that can show either.
+pub(crate) struct Pseudonyms {
+    by_email: HashMap<String, String>,
+    by_pseudonym: HashMap<String, String>,
+}
+
+impl Pseudonyms {
+    pub(crate) fn load(bg: &mut MySqlBackend) -> Pseudonyms {
+        let res = bg.prep_exec("SELECT email, pseudonym FROM users", vec![]);
+        let mut by_email = HashMap::new();
+        let mut by_pseudonym = HashMap::new();
+        for r in res {
+            let email: String = from_value(r[0].clone());
+            let pseudonym: String = from_value(r[1].clone());
+            by_email.insert(email.clone(), pseudonym.clone());
+            by_pseudonym.insert(pseudonym, email);
+        }
+        Pseudonyms {
+            by_email,
+            by_pseudonym,
+        }
+    }
+
+    /// How to show `email` on the page; users without a pseudonym (e.g., deleted users whose
+    /// answers were kept) are shown as "anonymous".
+    pub(crate) fn display(&self, view: &AnonymousView, email: &str) -> String {
+        if !view.0 {
+            return email.to_string();
+        }
+        self.by_email
+            .get(email)
+            .cloned()
+            .unwrap_or(String::from("anonymous"))
+    }
+
+    /// Resolves a user identifier from a URL, which is a pseudonym in anonymous view. Anything
+    /// that is not a current pseudonym (an email, or the ID of a deleted user's kept answers) is
+    /// passed through; these never look like pseudonyms.
+    pub(crate) fn resolve(&self, user: &str) -> String {
+        self.by_pseudonym
+            .get(user)
+            .cloned()
+            .unwrap_or(user.to_string())
+    }
+}
+
+/// Whether staff have switched to pseudonymous display (for anonymous grading).
+pub(crate) struct AnonymousView(pub bool);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for AnonymousView {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
+        let on = request
+            .cookies()
+            .get(COOKIE)
+            .map(|c| c.value() == "1")
+            .unwrap_or(false);
+        request::Outcome::Success(AnonymousView(on))
+    }
+}
+
+#[get("/")]
+pub(crate) fn pseudonyms(
+    _adm: Admin,
+    view: AnonymousView,
+    csrf: CsrfToken,
+    backend: &State<Arc<Mutex<MySqlBackend>>>,
+    config: &State<Config>,
+) -> Template {
+    let mut bg = backend.lock().unwrap();
+    let current_term = current_term(&mut bg);
+    let res = bg.prep_exec("SELECT COUNT(*) FROM users", vec![]);
+    let num_users = from_value(res[0][0].clone());
+    let terms = bg
+        .prep_exec(
+            "SELECT term, started_at FROM pseudonym_terms ORDER BY started_at DESC",
+            vec![],
+        )
+        .into_iter()
+        .map(|r| TermEntry {
+            term: from_value(r[0].clone()),
+            started_at: from_value::<chrono::NaiveDateTime>(r[1].clone())
+                .format("%Y-%m-%d %H:%M:%S")
+                .to_string(),
+        })
+        .collect();
+    drop(bg);
+
+    let ctx = PseudonymsContext {
+        current_term,
+        style: match config.pseudonym_style {
+            PseudonymStyle::Random => "random",
+            PseudonymStyle::AdjectiveAnimal => "adjective-animal",
+        },
+        num_users,
+        terms,
+        anonymous: view.0,
+        csrf_token: csrf.0,
+        parent: "layout",
+    };
+    Template::render("admin/pseudonyms", &ctx)
+}
+
+#[post("/regenerate", data = "<data>")]
+pub(crate) fn regenerate(
+    _adm: Admin,
+    data: CsrfForm<RegenerateForm>,
+    backend: &State<Arc<Mutex<MySqlBackend>>>,
+    config: &State<Config>,
+) -> Result<Redirect, Status> {
+    let term = data.term.trim();
+    let mut bg = backend.lock().unwrap();
+    let res = bg.prep_exec(
+        "SELECT COUNT(*) FROM pseudonym_terms WHERE term = ?",
+        vec![term.into()],
+    );
+    if term.is_empty() || term == INITIAL_TERM || from_value::<u64>(res[0][0].clone()) > 0 {
+        return Err(Status::UnprocessableEntity);
+    }
+    let n = regenerate_all(&mut bg, config, term);
+    info!(bg.log, "assigned {} new pseudonyms for term {}", n, term);
+    drop(bg);
+
+    Ok(Redirect::to("/admin/pseudonyms"))
+}
+
+#[post("/view", data = "<data>")]
+pub(crate) fn set_view(_adm: Admin, data: CsrfForm<ViewForm>, cookies: &CookieJar<'_>) -> Redirect {
+    if data.anonymous {
+        cookies.add(Cookie::build(COOKIE, "1").path("/").finish());
+    } else {
+        cookies.remove(Cookie::named(COOKIE));
+    }
+    Redirect::to(safe_next(&data.next))
+}
@@ -0,0 +1,189 @@
+use crate::backend::Value;
+use mysql::from_value;
+
+/// Longest accepted answer to a short text question.
+const SHORT_TEXT_MAX: usize = 255;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum QuestionType {
+    /// Free text (textarea); the type of all questions created before types existed
+    Text,
+    /// A single line of text
+    ShortText,
+    /// Exactly one of the options
+    SingleChoice,
+    /// Any subset of the options
+    MultipleChoice,
+    /// A number, graded with a tolerance
+    Numeric,
+    /// Source code in a given language
+    Code,
+}
+
+pub(crate) const ALL_TYPES: &[QuestionType] = &[
+    QuestionType::Text,
+    QuestionType::ShortText,
+    QuestionType::SingleChoice,
+    QuestionType::MultipleChoice,
+    QuestionType::Numeric,
+    QuestionType::Code,
+];
+
+impl QuestionType {
+    pub(crate) fn as_str(&self) -> &'static str {
+        match self {
+            QuestionType::Text => "text",
+            QuestionType::ShortText => "short-text",
+            QuestionType::SingleChoice => "single-choice",
+            QuestionType::MultipleChoice => "multiple-choice",
+            QuestionType::Numeric => "numeric",
+            QuestionType::Code => "code",
+        }
+    }
+
+    pub(crate) fn label(&self) -> &'static str {
+        match self {
+            QuestionType::Text => "free text",
+            QuestionType::ShortText => "short text",
+            QuestionType::SingleChoice => "single choice",
+            QuestionType::MultipleChoice => "multiple choice",
+            QuestionType::Numeric => "numeric",
+            QuestionType::Code => "code",
+        }
+    }
+
+    pub(crate) fn parse(s: &str) -> Option<QuestionType> {
+        ALL_TYPES.iter().find(|t| t.as_str() == s).copied()
+    }
+
+    pub(crate) fn has_options(&self) -> bool {
+        *self == QuestionType::SingleChoice || *self == QuestionType::MultipleChoice
+    }
+}
+
+/// A question's type and type-specific settings.
+#[derive(Debug, Clone)]
+pub(crate) struct QuestionSpec {
+    pub qtype: QuestionType,
+    /// Choices, for single and multiple choice questions
+    pub options: Vec<String>,
+    /// Accepted absolute difference from the key, for numeric questions
+    pub tolerance: Option<f64>,
+    /// Language tag (e.g., "rust"), for code questions
+    pub language: Option<String>,
+}
+
+/// Options are stored one per line, so they cannot contain newlines themselves.
+pub(crate) fn split_options(options: &str) -> Vec<String> {
+    options
+        .lines()
+        .map(|o| o.trim())
+        .filter(|o| !o.is_empty())
+        .map(String::from)
+        .collect()
+}
+
+impl QuestionSpec {
+    /// Reads the spec from a `questions` row (`SELECT *`).
+    pub(crate) fn from_row(r: &[Value]) -> QuestionSpec {
+        let qtype = from_value::<Option<String>>(r[3].clone())
+            .and_then(|t| QuestionType::parse(&t))
+            .unwrap_or(QuestionType::Text);
+        QuestionSpec {
+            qtype,
+            options: split_options(
+                &from_value::<Option<String>>(r[4].clone()).unwrap_or_default(),
+            ),
+            tolerance: from_value(r[5].clone()),
+            language: from_value::<Option<String>>(r[6].clone()).filter(|l| !l.is_empty()),
+        }
+    }
+
+    /// Checks the admin's settings for this question.
+    pub(crate) fn check(&self) -> Result<(), String> {
+        if self.qtype.has_options() && self.options.len() < 2 {
+            return Err(String::from("Choice questions need at least two options."));
+        }
+        if let Some(t) = self.tolerance {
+            if t.is_nan() || t < 0.0 {
+                return Err(String::from("The tolerance must be a non-negative number."));
+            }
+        }
+        Ok(())
+    }
+
+    /// The values to store in the `qtype`, `options`, `tolerance` and `language` columns.
+    pub(crate) fn to_values(&self) -> Vec<Value> {
+        vec![
+            self.qtype.as_str().into(),
+            if self.qtype.has_options() {
+                self.options.join("\n").into()
+            } else {
+                Value::NULL
+            },
+            if self.qtype == QuestionType::Numeric {
+                self.tolerance.into()
+            } else {
+                Value::NULL
+            },
+            if self.qtype == QuestionType::Code {
+                self.language.clone().into()
+            } else {
+                Value::NULL
+            },
+        ]
+    }
+
+    /// Validates and normalizes a student's answer. Multiple choice answers arrive as the list
+    /// of selected options and are stored one per line, in the order the options are listed.
+    pub(crate) fn validate(
+        &self,
+        text: Option<&str>,
+        selected: &[String],
+    ) -> Result<String, String> {
+        let text = text.unwrap_or("");
+        match self.qtype {
+            QuestionType::Text | QuestionType::Code => Ok(text.to_string()),
+            QuestionType::ShortText => {
+                let text = text.trim();
+                if text.contains('\n') || text.chars().count() > SHORT_TEXT_MAX {
+                    Err(format!(
+                        "Please answer in a single line of at most {} characters.",
+                        SHORT_TEXT_MAX
+                    ))
+                } else {
+                    Ok(text.to_string())
+                }
+            }
+            QuestionType::SingleChoice => {
+                if text.is_empty() || self.options.iter().any(|o| o == text) {
+                    Ok(text.to_string())
+                } else {
+                    Err(String::from("Please pick one of the listed options."))
+                }
+            }
+            QuestionType::MultipleChoice => {
+                if selected.iter().all(|s| self.options.contains(s)) {
+                    Ok(self
+                        .options
+                        .iter()
+                        .filter(|o| selected.contains(o))
+                        .cloned()
+                        .collect::<Vec<_>>()
+                        .join("\n"))
+                } else {
+                    Err(String::from("Please pick only from the listed options."))
+                }
+            }
+            QuestionType::Numeric => {
+                let text = text.trim();
+                let is_number = text.parse::<f64>().map(|n| n.is_finite()).unwrap_or(false);
+                if text.is_empty() || is_number {
+                    Ok(text.to_string())
+                } else {
+                    Err(String::from("Please enter a number (e.g., 42 or 3.14)."))
+                }
+            }
+        }
+    }
+}
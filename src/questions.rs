@@ -1,12 +1,25 @@
 use crate::admin::Admin;
 use crate::apikey::{ApiKey, BBoxApiKey};
+use crate::attachments::{self, Attachment};
+use crate::autograde::AnswerKey;
 use crate::backend::{MySqlBackend, Value};
 use crate::config::Config;
+use crate::csrf::{CsrfForm, CsrfToken};
+use crate::deadline::{self, Deadline, DeadlineStatus};
+use crate::drafts;
+use crate::extensions;
+use crate::history::{self, Submission};
 use crate::email;
+use crate::impersonate;
+use crate::markdown;
+use crate::progress::{self, Completion};
+use crate::pseudonym::{AnonymousView, Pseudonyms};
+use crate::qtype::QuestionSpec;
+use crate::visibility::{LectureState, Visibility};
 use chrono::naive::NaiveDateTime;
-use chrono::Local;
 use mysql::from_value;
-use rocket::form::{DataField, Form, FromForm, Options, ValueField};
+use rocket::form::FromForm;
+use rocket::http::Status;
 use rocket::response::Redirect;
 use rocket::State;
 use rocket_dyn_templates::Template;
@@ -25,9 +38,14 @@ use crate::bbox::BBox;
 //   Invalid,
 //}
 
+/// Grade prediction model trained on all students' answers.
+pub(crate) const MODEL_PATH: &str = "model.json";
+
 #[derive(Debug, FromForm)]
 pub(crate) struct LectureQuestionSubmission {
-    answers: HashMap<u64, String>,
+    pub answers: HashMap<u64, String>,
+    /// Selected options of multiple choice questions (one checkbox each)
+    pub choices: HashMap<u64, Vec<String>>,
 }
 
 pub(crate) struct BoxedLectureQuestionSubmission {
@@ -54,17 +72,98 @@ pub(crate) struct PredictGradeForm {
     time: String,
 }
 
+#[derive(Serialize)]
+pub(crate) struct QuestionOption {
+    value: String,
+    selected: bool,
+}
+
 #[derive(Serialize)]
 pub(crate) struct LectureQuestion {
     pub id: u64,
     pub prompt: String,
     pub answer: Option<String>,
+    pub qtype: &'static str,
+    pub qtype_label: &'static str,
+    pub options: Vec<QuestionOption>,
+    pub tolerance: Option<f64>,
+    pub language: Option<String>,
+    /// Why the submitted answer was rejected
+    pub error: Option<String>,
+    /// When the student last submitted an answer
+    pub submitted_at: Option<String>,
+    /// When the unsubmitted draft shown as the answer was saved
+    pub draft_saved_at: Option<String>,
+    /// Whether students can attach a file to their answer
+    pub attachments: bool,
+    /// The student's attached file
+    pub attachment: Option<Attachment>,
+    /// Whether the prompt and answers are Markdown
+    pub markdown: bool,
+    /// The rendered (and sanitized) prompt, for Markdown questions
+    pub prompt_html: Option<String>,
+}
+
+impl LectureQuestion {
+    pub(crate) fn new(
+        id: u64,
+        prompt: String,
+        spec: &QuestionSpec,
+        answer: Option<String>,
+    ) -> Self {
+        // multiple choice answers are stored one option per line
+        let selected: Vec<&str> = answer
+            .as_deref()
+            .map(|a| a.lines().collect())
+            .unwrap_or_default();
+        LectureQuestion {
+            id,
+            prompt,
+            qtype: spec.qtype.as_str(),
+            qtype_label: spec.qtype.label(),
+            options: spec
+                .options
+                .iter()
+                .map(|o| QuestionOption {
+                    value: o.clone(),
+                    selected: selected.contains(&o.as_str()),
+                })
+                .collect(),
+            tolerance: spec.tolerance,
+            language: spec.language.clone(),
+            answer,
+            error: None,
+            submitted_at: None,
+            draft_saved_at: None,
+            attachments: false,
+            attachment: None,
+            markdown: false,
+            prompt_html: None,
+        }
+    }
+
+    /// Reads the per-question settings that are not part of the `QuestionSpec` from a full
+    /// `questions` row.
+    pub(crate) fn read_settings(&mut self, r: &[Value]) {
+        self.attachments = attachments::accepted(r);
+        self.markdown = markdown::enabled(r);
+        if self.markdown {
+            self.prompt_html = Some(markdown::render(&self.prompt));
+        }
+    }
 }
 
 #[derive(Serialize)]
 pub(crate) struct LectureQuestionsContext {
-    pub lec_id: u8,
+    pub lec_id: u64,
     pub questions: Vec<LectureQuestion>,
+    pub deadline: DeadlineStatus,
+    /// Visibility, when an admin previews a lecture students cannot see (or not as usual)
+    pub preview: Option<&'static str>,
+    /// Whether answers are accepted (open, and not past the lock)
+    pub can_submit: bool,
+    pub csrf_token: String,
+    pub impersonating: Option<String>,
     pub parent: &'static str,
 }
 
@@ -75,12 +174,29 @@ struct LectureAnswer {
     answer: String,
     time: String,
     grade: u64,
+    /// Graded from the answer key rather than by staff
+    auto_graded: bool,
+    /// Hand-graded, but the student has since submitted a different answer
+    needs_regrade: bool,
+    late: bool,
+    /// Percentage taken off for lateness
+    penalty: u64,
+    /// Grade after the late penalty
+    final_grade: u64,
+    attachment: Option<Attachment>,
+    /// The rendered answer, for Markdown questions
+    answer_html: Option<String>,
 }
 
 #[derive(Serialize)]
 struct LectureAnswersContext {
-    lec_id: u8,
+    lec_id: u64,
     answers: Vec<LectureAnswer>,
+    anonymous: bool,
+    /// Where to return to after toggling anonymous view
+    next: String,
+    csrf_token: String,
+    impersonating: Option<String>,
     parent: &'static str,
 }
 
@@ -90,24 +206,42 @@ struct LectureListEntry {
     label: String,
     num_qs: u64,
     num_answered: u64,
+    /// When the student last submitted an answer to this lecture
+    last_submission: Option<String>,
+    /// Whether a graded answer was submitted late
+    late: bool,
+    /// The student's grade (after late penalties), once grades are released
+    grade: Option<u64>,
+    /// Class-wide progress, for admins
+    completion: Option<Completion>,
+    deadline: DeadlineStatus,
+    visibility: &'static str,
+    /// Whether the questions page can be opened
+    viewable: bool,
+    /// When a scheduled lecture opens
+    opens: Option<String>,
 }
 
 #[derive(Serialize)]
 struct LectureListContext {
     admin: bool,
     lectures: Vec<LectureListEntry>,
+    archived: Vec<LectureListEntry>,
+    csrf_token: String,
+    impersonating: Option<String>,
     parent: &'static str,
 }
 
 #[derive(Serialize)]
 struct PredictContext {
-    lec_id: u8,
+    lec_id: u64,
+    csrf_token: String,
     parent: &'static str,
 }
 
 #[derive(Serialize)]
 struct PredictGradeContext {
-    lec_id: u8,
+    lec_id: u64,
     time: String,
     grade: f64,
     parent: &'static str,
@@ -116,38 +250,90 @@ struct PredictGradeContext {
 #[get("/")]
 pub(crate) fn leclist(
     apikey: ApiKey,
+    csrf: CsrfToken,
     backend: &State<Arc<Mutex<MySqlBackend>>>,
     config: &State<Config>,
 ) -> Template {
+    let user = apikey.user.clone();
+    let admin = config.admins.contains(&user);
+    let now = deadline::now();
+
     let mut bg = backend.lock().unwrap();
     let res = bg.prep_exec(
-        "SELECT lectures.id, lectures.label, lec_qcount.qcount \
+        "SELECT lectures.id, lectures.label, lec_qcount.qcount, lectures.due_at, lectures.lock_at, \
+         lectures.visibility, lectures.opens_at, lectures.grades_released \
          FROM lectures \
          LEFT JOIN lec_qcount ON (lectures.id = lec_qcount.lec)",
         vec![],
     );
+    let exts = extensions::for_student(&mut bg, &user);
+    let mut progress = progress::for_student(&mut bg, &user);
+    let num_qs: HashMap<u64, u64> = res
+        .iter()
+        .map(|r| {
+            let n = from_value::<Option<u64>>(r[2].clone()).unwrap_or(0);
+            (from_value(r[0].clone()), n)
+        })
+        .collect();
+    let mut completion = if admin {
+        progress::class_completion(&mut bg, config, &num_qs)
+    } else {
+        HashMap::new()
+    };
     drop(bg);
 
-    let user = apikey.user.clone();
-    let admin = config.admins.contains(&user);
-
-    let lecs: Vec<_> = res
-        .into_iter()
-        .map(|r| LectureListEntry {
-            id: from_value(r[0].clone()),
+    let mut lecs = vec![];
+    let mut archived = vec![];
+    for r in res {
+        let id: u64 = from_value(r[0].clone());
+        let state = LectureState::from_values(&r[5], &r[6]);
+        let visibility = state.effective(now);
+        // students do not see hidden lectures at all, and scheduled ones only as upcoming
+        if !admin && visibility == Visibility::Hidden {
+            continue;
+        }
+        let d = Deadline {
+            due_at: from_value(r[3].clone()),
+            lock_at: from_value(r[4].clone()),
+            extended: false,
+        }
+        .extend(exts.get(&id).cloned());
+        let p = progress.remove(&id).unwrap_or_default();
+        let released = from_value::<Option<bool>>(r[7].clone()).unwrap_or(false);
+        let entry = LectureListEntry {
+            deadline: d.status(config, now),
+            id,
             label: from_value(r[1].clone()),
-            num_qs: if r[2] == Value::NULL {
-                0u64
+            num_qs: num_qs[&id],
+            num_answered: p.answered,
+            last_submission: p.last_submission.map(|t| deadline::display(config, t)),
+            late: p.is_late(&d),
+            grade: if released && p.answered > 0 {
+                Some(p.grade(config, &d))
             } else {
-                from_value(r[2].clone())
+                None
             },
-            num_answered: 0u64,
-        })
-        .collect();
+            completion: completion.remove(&id),
+            visibility: visibility.as_str(),
+            viewable: admin || state.is_visible(now),
+            opens: match visibility {
+                Visibility::Scheduled => state.opens_at.map(|t| deadline::display(config, t)),
+                _ => None,
+            },
+        };
+        if visibility == Visibility::Archived {
+            archived.push(entry);
+        } else {
+            lecs.push(entry);
+        }
+    }
 
     let ctx = LectureListContext {
         admin: admin,
         lectures: lecs,
+        archived,
+        csrf_token: csrf.0,
+        impersonating: impersonate::viewing_as(&apikey),
         parent: "layout",
     };
 
@@ -157,11 +343,13 @@ pub(crate) fn leclist(
 #[get("/<num>")]
 pub(crate) fn predict(
     _admin: Admin,
-    num: u8,
+    num: u64,
+    csrf: CsrfToken,
     _backend: &State<Arc<Mutex<MySqlBackend>>>,
 ) -> Template {
     let ctx = PredictContext {
         lec_id: num,
+        csrf_token: csrf.0,
         parent: "layout",
     };
     Template::render("predict", &ctx)
@@ -170,13 +358,13 @@ pub(crate) fn predict(
 #[post("/predict_grade/<num>", data = "<data>")]
 pub(crate) fn predict_grade(
     _adm: Admin,
-    num: u8,
-    data: Form<PredictGradeForm>,
+    num: u64,
+    data: CsrfForm<PredictGradeForm>,
     backend: &State<Arc<Mutex<MySqlBackend>>>,
 ) -> Template {
     let time = NaiveDateTime::parse_from_str(data.time.as_str(), "%Y-%m-%d %H:%M:%S");
     let mut bg = backend.lock().unwrap();
-    let key: Value = (num as u64).into();
+    let key: Value = num.into();
     let res = bg.prep_exec("SELECT submitted_at, grade FROM answers WHERE lec = ?", vec![key]);
     drop(bg);
     let grades: Vec<[f64;2]> = res
@@ -196,7 +384,7 @@ pub(crate) fn predict_grade(
 
     let dataset = Dataset::new(x, y).with_feature_names(vec!["x", "y"]);
 
-    let model_path = Path::new("model.json");
+    let model_path = Path::new(MODEL_PATH);
 
     let model = if model_path.exists() {
         println!("Loading the model from a file...");
@@ -228,27 +416,57 @@ pub(crate) fn predict_grade(
 #[get("/<num>")]
 pub(crate) fn grades(
     _admin: Admin,
-    num: u8,
+    apikey: ApiKey,
+    num: u64,
+    view: AnonymousView,
+    csrf: CsrfToken,
     backend: &State<Arc<Mutex<MySqlBackend>>>,
+    config: &State<Config>,
 ) -> Template {
     let mut bg = backend.lock().unwrap();
-    let key: Value = (num as u64).into();
+    let key: Value = num.into();
     let res = bg.prep_exec("SELECT * FROM answers WHERE lec = ?", vec![key]);
+    let names = Pseudonyms::load(&mut bg);
+    let d = Deadline::load(&mut bg, num);
+    let exts = extensions::for_lecture(&mut bg, num);
+    let mut files = attachments::for_lecture(&mut bg, num);
+    let md = markdown::questions(&mut bg, num);
     drop(bg);
     let answers: Vec<_> = res
         .into_iter()
-        .map(|r| LectureAnswer {
-            id: from_value(r[2].clone()),
-            user: from_value(r[0].clone()),
-            answer: from_value(r[3].clone()),
-            time: from_value::<NaiveDateTime>(r[4].clone()).format("%Y-%m-%d %H:%M:%S").to_string(),
-            grade: from_value(r[5].clone()),
+        .map(|r| {
+            let email: String = from_value(r[0].clone());
+            let id: u64 = from_value(r[2].clone());
+            let d = d.extend(exts.get(&email).cloned());
+            let submitted_at: NaiveDateTime = from_value(r[4].clone());
+            let grade: u64 = from_value(r[5].clone());
+            let penalty = d.late_penalty(config, submitted_at);
+            let attachment = files.remove(&(email.clone(), id));
+            let answer: String = from_value(r[3].clone());
+            LectureAnswer {
+                id,
+                user: names.display(&view, &email),
+                answer_html: md.contains(&id).then(|| markdown::render(&answer)),
+                answer,
+                time: submitted_at.format("%Y-%m-%d %H:%M:%S").to_string(),
+                grade,
+                auto_graded: from_value::<Option<bool>>(r[6].clone()).unwrap_or(false),
+                needs_regrade: from_value::<Option<bool>>(r[8].clone()).unwrap_or(false),
+                late: d.is_late(submitted_at),
+                penalty,
+                final_grade: deadline::apply_penalty(grade, penalty),
+                attachment,
+            }
         })
         .collect();
 
     let ctx = LectureAnswersContext {
         lec_id: num,
         answers: answers,
+        anonymous: view.0,
+        next: format!("/grades/{}", num),
+        csrf_token: csrf.0,
+        impersonating: impersonate::viewing_as(&apikey),
         parent: "layout",
     };
     Template::render("grades", &ctx)
@@ -257,28 +475,47 @@ pub(crate) fn grades(
 #[get("/<user>/<num>/<qnum>")]
 pub(crate) fn editg(
     _adm: Admin,
+    apikey: ApiKey,
     user: String,
-    num: u8,
-    qnum: u8,
+    num: u64,
+    qnum: u64,
+    csrf: CsrfToken,
     backend: &State<Arc<Mutex<MySqlBackend>>>,
 ) -> Template {
     let mut bg = backend.lock().unwrap();
     let res = bg.prep_exec(
         "SELECT * FROM answers WHERE lec = ?",
-        vec![(num as u64).into()],
+        vec![num.into()],
     );
+    // in anonymous view, `user` is a pseudonym
+    let email = Pseudonyms::load(&mut bg).resolve(&user);
+    let md = markdown::questions(&mut bg, num).contains(&qnum);
     drop(bg);
 
     let mut ctx = HashMap::new();
     for r in res {
-        if from_value::<String>(r[0].clone()) == user && from_value::<u8>(r[2].clone()) == qnum {
-            ctx.insert("answer", format!("{}", from_value::<String>(r[3].clone())));
+        if from_value::<String>(r[0].clone()) == email && from_value::<u64>(r[2].clone()) == qnum {
+            let answer: String = from_value(r[3].clone());
+            if md {
+                ctx.insert("answer_html", markdown::render(&answer));
+            }
+            ctx.insert("answer", answer);
             ctx.insert("grade", format!("{}", from_value::<u64>(r[5].clone())));
+            if from_value::<Option<bool>>(r[6].clone()).unwrap_or(false) {
+                ctx.insert("auto_graded", String::from("true"));
+            }
+            if from_value::<Option<bool>>(r[8].clone()).unwrap_or(false) {
+                ctx.insert("needs_regrade", String::from("true"));
+            }
         }
     }
     ctx.insert("user", format!("{}", user));
     ctx.insert("lec_id", format!("{}", num));
     ctx.insert("lec_qnum", format!("{}", qnum));
+    ctx.insert("csrf_token", csrf.0);
+    if let Some(student) = impersonate::viewing_as(&apikey) {
+        ctx.insert("impersonating", student);
+    }
     ctx.insert("parent", String::from("layout"));
     Template::render("gradeedit", &ctx)
 }
@@ -286,20 +523,25 @@ pub(crate) fn editg(
 #[post("/editg/<user>/<num>/<qnum>", data = "<data>")]
 pub(crate) fn editg_submit(
     _adm: Admin,
+    apikey: ApiKey,
     user: String,
-    num: u8,
-    qnum: u8,
-    data: Form<EditGradeForm>,
+    num: u64,
+    qnum: u64,
+    data: CsrfForm<EditGradeForm>,
     backend: &State<Arc<Mutex<MySqlBackend>>>,
 ) -> Redirect {
     let mut bg = backend.lock().unwrap();
+    let email = Pseudonyms::load(&mut bg).resolve(&user);
+    // a grade set here overrides the automatic one (until the student resubmits)
     bg.prep_exec(
-        "UPDATE answers SET grade = ? WHERE email = ? AND lec = ? AND q = ?",
+        "UPDATE answers SET grade = ?, auto_graded = 0, needs_regrade = 0, graded_by = ? \
+         WHERE email = ? AND lec = ? AND q = ?",
         vec![
             (data.grade as u64).into(),
-            user.into(),
-            (num as u64).into(),
-            (qnum as u64).into(),
+            apikey.user.as_str().into(),
+            email.into(),
+            num.into(),
+            qnum.into(),
         ],
     );
     drop(bg);
@@ -310,27 +552,57 @@ pub(crate) fn editg_submit(
 #[get("/<num>")]
 pub(crate) fn answers(
     _admin: Admin,
-    num: u8,
+    apikey: ApiKey,
+    num: u64,
+    view: AnonymousView,
+    csrf: CsrfToken,
     backend: &State<Arc<Mutex<MySqlBackend>>>,
+    config: &State<Config>,
 ) -> Template {
     let mut bg = backend.lock().unwrap();
-    let key: Value = (num as u64).into();
+    let key: Value = num.into();
     let res = bg.prep_exec("SELECT * FROM answers WHERE lec = ?", vec![key]);
+    let names = Pseudonyms::load(&mut bg);
+    let d = Deadline::load(&mut bg, num);
+    let exts = extensions::for_lecture(&mut bg, num);
+    let mut files = attachments::for_lecture(&mut bg, num);
+    let md = markdown::questions(&mut bg, num);
     drop(bg);
     let answers: Vec<_> = res
         .into_iter()
-        .map(|r| LectureAnswer {
-            id: from_value(r[2].clone()),
-            user: from_value(r[0].clone()),
-            answer: from_value(r[3].clone()),
-            time: from_value::<NaiveDateTime>(r[4].clone()).format("%Y-%m-%d %H:%M:%S").to_string(),
-            grade: from_value(r[5].clone()),
+        .map(|r| {
+            let email: String = from_value(r[0].clone());
+            let id: u64 = from_value(r[2].clone());
+            let d = d.extend(exts.get(&email).cloned());
+            let submitted_at: NaiveDateTime = from_value(r[4].clone());
+            let grade: u64 = from_value(r[5].clone());
+            let penalty = d.late_penalty(config, submitted_at);
+            let attachment = files.remove(&(email.clone(), id));
+            let answer: String = from_value(r[3].clone());
+            LectureAnswer {
+                id,
+                user: names.display(&view, &email),
+                answer_html: md.contains(&id).then(|| markdown::render(&answer)),
+                answer,
+                time: submitted_at.format("%Y-%m-%d %H:%M:%S").to_string(),
+                grade,
+                auto_graded: from_value::<Option<bool>>(r[6].clone()).unwrap_or(false),
+                needs_regrade: from_value::<Option<bool>>(r[8].clone()).unwrap_or(false),
+                late: d.is_late(submitted_at),
+                penalty,
+                final_grade: deadline::apply_penalty(grade, penalty),
+                attachment,
+            }
         })
         .collect();
 
     let ctx = LectureAnswersContext {
         lec_id: num,
         answers: answers,
+        anonymous: view.0,
+        next: format!("/answers/{}", num),
+        csrf_token: csrf.0,
+        impersonating: impersonate::viewing_as(&apikey),
         parent: "layout",
     };
     Template::render("answers", &ctx)
@@ -339,77 +611,180 @@ pub(crate) fn answers(
 #[get("/<num>")]
 pub(crate) fn questions(
     apikey: ApiKey,
-    num: u8,
+    num: u64,
+    csrf: CsrfToken,
     backend: &State<Arc<Mutex<MySqlBackend>>>,
-) -> Template {
+    config: &State<Config>,
+) -> Result<Template, Status> {
     use std::collections::HashMap;
 
     let mut bg = backend.lock().unwrap();
-    let key: Value = (num as u64).into();
+    let key: Value = num.into();
+    let now = deadline::now();
+    let admin = config.admins.contains(&apikey.user);
+    let state = LectureState::load(&mut bg, num).ok_or(Status::NotFound)?;
+    // admins can preview lectures students cannot see
+    if !admin && !state.is_visible(now) {
+        return Err(Status::NotFound);
+    }
 
-    let answers_res = bg.prep_exec(
-        "SELECT answers.* FROM answers WHERE answers.lec = ? AND answers.email = ?",
-        vec![(num as u64).into(), apikey.user.clone().into()],
+    // students see their latest version, even if an earlier one is graded, unless they have
+    // an unsubmitted draft
+    let answers = history::latest_versions(&mut bg, &apikey.user, num);
+    let drafts = drafts::load(&mut bg, &apikey.user, num);
+    let mut files = attachments::for_student(&mut bg, &apikey.user, num);
+    let res = bg.prep_exec(
+        "SELECT * FROM questions WHERE lec = ? ORDER BY position, q",
+        vec![key],
     );
-    let mut answers = HashMap::new();
-
-    for r in answers_res {
-        let id: u64 = from_value(r[2].clone());
-        let atext: String = from_value(r[3].clone());
-        answers.insert(id, atext);
-    }
-    let res = bg.prep_exec("SELECT * FROM questions WHERE lec = ?", vec![key]);
+    let d = Deadline::for_student(&mut bg, num, &apikey.user);
     drop(bg);
-    let mut qs: Vec<_> = res
+    let qs: Vec<_> = res
         .into_iter()
         .map(|r| {
             let id: u64 = from_value(r[1].clone());
-            let answer = answers.get(&id).map(|s| s.to_owned());
-            LectureQuestion {
-                id: id,
-                prompt: from_value(r[2].clone()),
-                answer: answer,
-            }
+            let submitted = answers.get(&id);
+            let draft = drafts.get(&id);
+            let answer = draft.or(submitted).map(|(a, _)| a.to_owned());
+            let spec = QuestionSpec::from_row(&r);
+            let mut q = LectureQuestion::new(id, from_value(r[2].clone()), &spec, answer);
+            q.submitted_at = submitted.map(|(_, t)| deadline::display(config, *t));
+            q.draft_saved_at = draft.map(|(_, t)| deadline::display(config, *t));
+            q.read_settings(&r);
+            q.attachment = files.remove(&id);
+            q
         })
         .collect();
-    qs.sort_by(|a, b| a.id.cmp(&b.id));
 
     let ctx = LectureQuestionsContext {
         lec_id: num,
         questions: qs,
+        deadline: d.status(config, now),
+        preview: preview(admin, &state, now),
+        can_submit: state.accepts_answers(now) && !d.is_locked(now),
+        csrf_token: csrf.0,
+        impersonating: impersonate::viewing_as(&apikey),
         parent: "layout",
     };
-    Template::render("questions", &ctx)
+    Ok(Template::render("questions", &ctx))
+}
+
+/// What admins are told about a lecture that students do not see as an open lecture.
+fn preview(admin: bool, state: &LectureState, now: NaiveDateTime) -> Option<&'static str> {
+    match state.effective(now) {
+        Visibility::Open => None,
+        v if admin => Some(v.as_str()),
+        _ => None,
+    }
+}
+
+/// Why a submission was not stored.
+#[derive(Responder)]
+pub(crate) enum SubmitError {
+    /// Invalid answers, or the lecture does not accept answers; the page is shown again
+    Rejected(Template),
+    /// Students cannot see the lecture
+    Unavailable(Status),
 }
 
 #[post("/<num>", data = "<data>")]
 pub(crate) fn questions_submit(
     apikey: ApiKey,
-    num: u8,
-    data: Form<LectureQuestionSubmission>,
+    num: u64,
+    data: CsrfForm<LectureQuestionSubmission>,
+    csrf: CsrfToken,
     backend: &State<Arc<Mutex<MySqlBackend>>>,
     config: &State<Config>,
-) -> Redirect {
+) -> Result<Redirect, SubmitError> {
+    let mut bg = backend.lock().unwrap();
+    let now = deadline::now();
+    let admin = config.admins.contains(&apikey.user);
+    let state = match LectureState::load(&mut bg, num) {
+        Some(s) if admin || s.is_visible(now) => s,
+        _ => return Err(SubmitError::Unavailable(Status::NotFound)),
+    };
+    let res = bg.prep_exec(
+        "SELECT * FROM questions WHERE lec = ? ORDER BY position, q",
+        vec![num.into()],
+    );
+    let d = Deadline::for_student(&mut bg, num, &apikey.user);
+    let mut files = attachments::for_student(&mut bg, &apikey.user, num);
+
+    // validate every answer against its question's type before storing any of them
+    let mut answers = HashMap::new();
+    let mut keys = HashMap::new();
+    let mut qs = vec![];
+    let mut valid = true;
+    for r in &res {
+        let id: u64 = from_value(r[1].clone());
+        let spec = QuestionSpec::from_row(r);
+        if let Some(key) = AnswerKey::from_row(r) {
+            keys.insert(id, (spec.clone(), key));
+        }
+        let selected = data.choices.get(&id).cloned().unwrap_or_default();
+        let submitted = data.answers.get(&id).map(|a| a.as_str());
+        let result = spec.validate(submitted, &selected);
+        let shown = match &result {
+            Ok(a) => a.clone(),
+            Err(_) if selected.is_empty() => submitted.unwrap_or("").to_string(),
+            Err(_) => selected.join("\n"),
+        };
+        let mut q = LectureQuestion::new(id, from_value(r[2].clone()), &spec, Some(shown));
+        q.read_settings(r);
+        q.attachment = files.remove(&id);
+        match result {
+            // choice questions send no field when nothing is selected, which clears the answer
+            Ok(a) if submitted.is_some() || spec.qtype.has_options() => {
+                answers.insert(id, a);
+            }
+            Ok(_) => (),
+            Err(e) => {
+                q.error = Some(e);
+                valid = false;
+            }
+        }
+        qs.push(q);
+    }
+
+    // after the lock, or once the lecture is closed, the answers are shown back but not stored
+    let can_submit = state.accepts_answers(now) && !d.is_locked(now);
+    if !valid || !can_submit {
+        drop(bg);
+        let ctx = LectureQuestionsContext {
+            lec_id: num,
+            questions: qs,
+            deadline: d.status(config, now),
+            preview: preview(admin, &state, now),
+            can_submit,
+            csrf_token: csrf.0,
+            impersonating: impersonate::viewing_as(&apikey),
+            parent: "layout",
+        };
+        return Err(SubmitError::Rejected(Template::render("questions", &ctx)));
+    }
+
     let apikey = BBoxApiKey::new(&apikey);
     let num = BBox::new(num);
-    let data = BoxedLectureQuestionSubmission::new(&data.answers);
+    let data = BoxedLectureQuestionSubmission::new(&answers);
 
-    let mut bg = backend.lock().unwrap();
-    // let vnum: Value = (num as u64).into();
-    let ts: Value = Local::now().naive_local().into();
-    let grade: Value = 0.into();
+    // let vnum: Value = num.into();
 
     for (id, answer) in &data.answers {
-        let rec: Vec<Value> = vec![
-            apikey.user.internal_unbox().clone().into(),
-            (num.internal_unbox().clone() as u64).into(),
-            (*id).into(),
-            answer.internal_unbox().clone().into(),
-            ts.clone(),
-            grade.clone(),
-        ];
-        bg.replace("answers", rec);
+        // every version is kept; which one is graded depends on `graded_version`
+        let submission = Submission {
+            email: apikey.user.internal_unbox(),
+            lec: *num.internal_unbox(),
+            q: *id,
+            answer: answer.internal_unbox(),
+            submitted_at: now,
+        };
+        history::record_submission(&mut bg, config, &submission, &d, keys.get(id));
     }
+    drafts::clear(
+        &mut bg,
+        apikey.user.internal_unbox(),
+        *num.internal_unbox(),
+    );
 
     // TODO: some context that represents sending an email; unbox given that context
     let answer_log = format!(
@@ -429,6 +804,7 @@ pub(crate) fn questions_submit(
 
         email::send(
             bg.log.clone(),
+            config,
             apikey.user.unbox("email").clone(),
             recipients,
             format!("{} meeting {} questions", config.class, num.unbox("email")),
@@ -438,5 +814,5 @@ pub(crate) fn questions_submit(
     }
     drop(bg);
 
-    Redirect::to("/leclist")
+    Ok(Redirect::to("/leclist"))
 }
@@ -0,0 +1,322 @@
+use crate::config::{Config, RateLimit};
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::http::uri::Origin;
+use rocket::http::{Method, Status};
+use rocket::{Data, Request};
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Buckets (and failure counts) are pruned once their table grows beyond this many entries.
+const MAX_BUCKETS: usize = 10000;
+
+/// Where the fairing sends throttled requests.
+pub(crate) const THROTTLED_PATH: &str = "/throttled";
+
+struct Bucket {
+    tokens: f64,
+    last: Instant,
+}
+
+impl Bucket {
+    fn refill(&mut self, limit: &RateLimit, now: Instant) {
+        let elapsed = now.duration_since(self.last).as_secs_f64();
+        self.tokens =
+            (self.tokens + elapsed * limit.per_minute as f64 / 60.0).min(limit.burst as f64);
+        self.last = now;
+    }
+}
+
+struct Failures {
+    count: u32,
+    /// Time of the latest failure; counts are forgotten a lockout duration later
+    last: Instant,
+    locked_until: Option<Instant>,
+}
+
+impl Failures {
+    fn expired(&self, window: Duration, now: Instant) -> bool {
+        match self.locked_until {
+            Some(until) => until <= now,
+            None => self.last + window <= now,
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub(crate) struct ThrottledClient {
+    pub route: String,
+    pub client: String,
+    pub reason: String,
+    pub seconds_left: u64,
+}
+
+/// Token-bucket rate limiter keyed by (route, client IP) and (route, email), plus a lockout for
+/// clients that repeatedly fail API key checks.
+pub(crate) struct RateLimiter {
+    limits: HashMap<String, RateLimit>,
+    lockout_threshold: u32,
+    lockout_duration: Duration,
+    buckets: Mutex<HashMap<(String, String), Bucket>>,
+    failures: Mutex<HashMap<IpAddr, Failures>>,
+}
+
+impl RateLimiter {
+    pub fn new(config: &Config) -> RateLimiter {
+        RateLimiter {
+            limits: config
+                .rate_limits
+                .iter()
+                .map(|l| (l.route.clone(), l.clone()))
+                .collect(),
+            lockout_threshold: config.lockout_threshold,
+            lockout_duration: Duration::from_secs(config.lockout_minutes * 60),
+            buckets: Mutex::new(HashMap::new()),
+            failures: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Takes a token for `client` on `route`; returns false if the client is out of tokens.
+    /// Routes without a configured limit are never throttled.
+    pub fn take(&self, route: &str, client: &str) -> bool {
+        self.take_at(route, client, Instant::now())
+    }
+
+    fn take_at(&self, route: &str, client: &str, now: Instant) -> bool {
+        let limit = match self.limits.get(route) {
+            None => return true,
+            Some(l) => l,
+        };
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.len() > MAX_BUCKETS {
+            let limits = &self.limits;
+            buckets.retain(|(r, _), b| {
+                b.refill(&limits[r], now);
+                b.tokens < limits[r].burst as f64
+            });
+        }
+        let bucket = buckets
+            .entry((route.to_string(), client.to_string()))
+            .or_insert(Bucket {
+                tokens: limit.burst as f64,
+                last: now,
+            });
+        bucket.refill(limit, now);
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+
+    pub fn is_locked_out(&self, ip: &IpAddr) -> bool {
+        self.is_locked_out_at(ip, Instant::now())
+    }
+
+    fn is_locked_out_at(&self, ip: &IpAddr, now: Instant) -> bool {
+        let failures = self.failures.lock().unwrap();
+        match failures.get(ip).and_then(|f| f.locked_until) {
+            Some(until) => until > now,
+            None => false,
+        }
+    }
+
+    /// Records a failed API key check; locks the IP out once it reaches the threshold within a
+    /// lockout duration.
+    pub fn record_failure(&self, ip: IpAddr) {
+        self.record_failure_at(ip, Instant::now())
+    }
+
+    fn record_failure_at(&self, ip: IpAddr, now: Instant) {
+        let window = self.lockout_duration;
+        let mut failures = self.failures.lock().unwrap();
+        if failures.len() > MAX_BUCKETS {
+            failures.retain(|_, f| !f.expired(window, now));
+        }
+        let f = failures.entry(ip).or_insert(Failures {
+            count: 0,
+            last: now,
+            locked_until: None,
+        });
+        if f.expired(window, now) {
+            // previous lockout (or failures) expired, start counting afresh
+            f.count = 0;
+            f.locked_until = None;
+        }
+        f.last = now;
+        f.count += 1;
+        if f.count >= self.lockout_threshold {
+            f.locked_until = Some(now + self.lockout_duration);
+        }
+    }
+
+    pub fn record_success(&self, ip: &IpAddr) {
+        self.failures.lock().unwrap().remove(ip);
+    }
+
+    /// Clients currently locked out or without tokens left, for the admin view.
+    pub fn throttled(&self) -> Vec<ThrottledClient> {
+        let now = Instant::now();
+        let mut res = vec![];
+
+        for (ip, f) in self.failures.lock().unwrap().iter() {
+            if let Some(until) = f.locked_until.filter(|u| *u > now) {
+                res.push(ThrottledClient {
+                    route: String::from("/apikey/check"),
+                    client: ip.to_string(),
+                    reason: format!("locked out after {} failed key checks", f.count),
+                    seconds_left: until.duration_since(now).as_secs(),
+                });
+            }
+        }
+
+        for ((route, client), b) in self.buckets.lock().unwrap().iter_mut() {
+            let limit = &self.limits[route];
+            b.refill(limit, now);
+            if b.tokens < 1.0 {
+                res.push(ThrottledClient {
+                    route: route.clone(),
+                    client: client.clone(),
+                    reason: format!("rate limited ({}/min)", limit.per_minute),
+                    seconds_left: ((1.0 - b.tokens) * 60.0 / limit.per_minute as f64).ceil()
+                        as u64,
+                });
+            }
+        }
+        res
+    }
+}
+
+/// Applies per-IP limits and lockouts to POSTs on rate-limited routes. Throttled requests are
+/// rerouted to `throttled`, so no handler needs to check for them.
+pub(crate) struct RateLimitFairing(pub Arc<RateLimiter>);
+
+#[rocket::async_trait]
+impl Fairing for RateLimitFairing {
+    fn info(&self) -> Info {
+        Info {
+            name: "Rate limiter",
+            kind: Kind::Request,
+        }
+    }
+
+    async fn on_request(&self, req: &mut Request<'_>, _data: &mut Data<'_>) {
+        if req.method() != Method::Post {
+            return;
+        }
+        let ip = match req.client_ip() {
+            Some(ip) => ip,
+            None => return,
+        };
+        let route = req.uri().path().as_str().trim_end_matches('/').to_string();
+        let throttled = (route == "/apikey/check" && self.0.is_locked_out(&ip))
+            || !self.0.take(&route, &ip.to_string());
+        if throttled {
+            req.set_uri(Origin::parse(THROTTLED_PATH).unwrap());
+        }
+    }
+}
+
+/// Answers every request the fairing throttled.
+#[post("/")]
+pub(crate) fn throttled() -> Status {
+    Status::TooManyRequests
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rocket::local::blocking::Client;
+    use std::net::SocketAddr;
+
+    fn limiter(burst: u32, per_minute: u32) -> RateLimiter {
+        let limit = RateLimit {
+            route: "/limited".into(),
+            burst,
+            per_minute,
+        };
+        RateLimiter {
+            limits: vec![(limit.route.clone(), limit)].into_iter().collect(),
+            lockout_threshold: 3,
+            lockout_duration: Duration::from_secs(60),
+            buckets: Mutex::new(HashMap::new()),
+            failures: Mutex::new(HashMap::new()),
+        }
+    }
+
+    #[test]
+    fn buckets_refill_over_time() {
+        let l = limiter(2, 6);
+        let t0 = Instant::now();
+        assert!(l.take_at("/limited", "a", t0));
+        assert!(l.take_at("/limited", "a", t0));
+        assert!(!l.take_at("/limited", "a", t0));
+        // other clients and unlimited routes are unaffected
+        assert!(l.take_at("/limited", "b", t0));
+        assert!(l.take_at("/other", "a", t0));
+        // one token every 10 seconds, up to the burst
+        assert!(!l.take_at("/limited", "a", t0 + Duration::from_secs(9)));
+        assert!(l.take_at("/limited", "a", t0 + Duration::from_secs(10)));
+        let later = t0 + Duration::from_secs(3600);
+        assert!(l.take_at("/limited", "a", later));
+        assert!(l.take_at("/limited", "a", later));
+        assert!(!l.take_at("/limited", "a", later));
+    }
+
+    #[test]
+    fn repeated_failures_lock_out() {
+        let l = limiter(1, 1);
+        let ip: IpAddr = "10.0.0.1".parse().unwrap();
+        let t0 = Instant::now();
+        l.record_failure_at(ip, t0);
+        l.record_failure_at(ip, t0);
+        assert!(!l.is_locked_out_at(&ip, t0));
+        l.record_failure_at(ip, t0);
+        assert!(l.is_locked_out_at(&ip, t0 + Duration::from_secs(59)));
+        assert!(!l.is_locked_out_at(&ip, t0 + Duration::from_secs(60)));
+
+        // a success forgets earlier failures
+        l.record_failure_at(ip, t0 + Duration::from_secs(61));
+        l.record_success(&ip);
+        l.record_failure_at(ip, t0 + Duration::from_secs(62));
+        l.record_failure_at(ip, t0 + Duration::from_secs(62));
+        assert!(!l.is_locked_out_at(&ip, t0 + Duration::from_secs(62)));
+        // and so do failures a lockout duration apart
+        l.record_failure_at(ip, t0 + Duration::from_secs(200));
+        assert!(!l.is_locked_out_at(&ip, t0 + Duration::from_secs(200)));
+    }
+
+    #[test]
+    fn expired_failures_are_pruned() {
+        let l = limiter(1, 1);
+        let t0 = Instant::now();
+        for i in 0..=MAX_BUCKETS as u32 {
+            l.record_failure_at(IpAddr::from(i.to_be_bytes()), t0);
+        }
+        let ip: IpAddr = "10.0.0.1".parse().unwrap();
+        l.record_failure_at(ip, t0 + Duration::from_secs(60));
+        assert_eq!(l.failures.lock().unwrap().len(), 1);
+    }
+
+    #[post("/limited")]
+    fn limited() -> &'static str {
+        "ok"
+    }
+
+    #[test]
+    fn fairing_rejects_throttled_requests() {
+        let l = Arc::new(limiter(1, 1));
+        let rocket = rocket::build()
+            .attach(RateLimitFairing(l))
+            .mount("/", routes![limited])
+            .mount(THROTTLED_PATH, routes![throttled]);
+        let client = Client::tracked(rocket).unwrap();
+        let from = |ip: &str| ip.parse::<SocketAddr>().unwrap();
+        let post = |ip| client.post("/limited").remote(from(ip)).dispatch().status();
+        assert_eq!(post("10.0.0.1:1000"), Status::Ok);
+        assert_eq!(post("10.0.0.1:1000"), Status::TooManyRequests);
+        assert_eq!(post("10.0.0.2:1000"), Status::Ok);
+    }
+}
@@ -0,0 +1,377 @@
+use crate::admin::Admin;
+use crate::backend::{MySqlBackend, Value};
+use crate::config::Config;
+use crate::csrf::{CsrfForm, CsrfToken, NoFields};
+use crate::email;
+use chrono::naive::NaiveDateTime;
+use chrono::Local;
+use mysql::from_value;
+use regex::Regex;
+use rocket::State;
+use rocket_dyn_templates::Template;
+use std::collections::HashSet;
+use std::sync::{Arc, Mutex};
+
+#[derive(Debug, FromForm)]
+pub(crate) struct RosterUpload {
+    /// CSV file contents: `email,name,section`, optionally with a header row
+    roster: String,
+    /// Remove rostered students missing from this upload
+    replace: bool,
+}
+
+#[derive(Serialize)]
+struct RosterEntry {
+    email: String,
+    name: String,
+    section: String,
+    invited_at: Option<String>,
+}
+
+#[derive(Serialize)]
+struct RosterContext {
+    num_rostered: u64,
+    num_registered: u64,
+    not_registered: Vec<RosterEntry>,
+    not_rostered: Vec<String>,
+    message: Option<String>,
+    errors: Vec<String>,
+    csrf_token: String,
+    parent: &'static str,
+}
+
+/// Splits CSV text into records. Handles quoted fields (with `""` escapes and embedded
+/// newlines), both `\n` and `\r\n` line endings, and the byte order mark spreadsheets tend to
+/// write.
+fn parse_csv(text: &str) -> Vec<Vec<String>> {
+    let mut records = vec![];
+    let mut record = vec![];
+    let mut field = String::new();
+    let mut quoted = false;
+    let mut chars = text.trim_start_matches('\u{feff}').chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if quoted && chars.peek() == Some(&'"') => {
+                chars.next();
+                field.push('"');
+            }
+            '"' => quoted = !quoted,
+            ',' if !quoted => record.push(std::mem::take(&mut field)),
+            '\r' if !quoted => (),
+            '\n' if !quoted => {
+                record.push(std::mem::take(&mut field));
+                records.push(std::mem::take(&mut record));
+            }
+            c => field.push(c),
+        }
+    }
+    if !field.is_empty() || !record.is_empty() {
+        record.push(field);
+        records.push(record);
+    }
+    records
+}
+
+fn normalize_email(email: &str) -> String {
+    email.trim().to_lowercase()
+}
+
+/// Whether `email` is a plain `local@domain` address. Deliberately stricter than RFC 5322:
+/// quotes, spaces, brackets and the like are rejected, since addresses end up in pages and
+/// emails.
+pub(crate) fn valid_email(email: &str) -> bool {
+    let re = Regex::new(r"^[A-Za-z0-9._%+-]+@[A-Za-z0-9-]+(\.[A-Za-z0-9-]+)+$").unwrap();
+    email.len() <= 254 && re.is_match(email)
+}
+
+/// Whether `email` may register: anyone if registration is open, otherwise rostered students,
+/// configured domains, and configured admins, managers and staff.
+pub(crate) fn may_register(bg: &mut MySqlBackend, config: &Config, email: &str) -> bool {
+    let email = normalize_email(email);
+    if config.open_registration
+        || config
+            .admins
+            .iter()
+            .chain(config.managers.iter())
+            .chain(config.staff.iter())
+            .any(|a| a.to_lowercase() == email)
+    {
+        return true;
+    }
+    if let Some((_, domain)) = email.rsplit_once('@') {
+        if config
+            .enrollment_domains
+            .iter()
+            .any(|d| d.to_lowercase() == domain)
+        {
+            return true;
+        }
+    }
+    let res = bg.prep_exec(
+        "SELECT COUNT(*) FROM enrollment WHERE LOWER(email) = ?",
+        vec![email.into()],
+    );
+    from_value::<u64>(res[0][0].clone()) > 0
+}
+
+/// Adds or updates the roster from CSV. Returns the number of students imported and one error
+/// message per rejected row.
+fn import(bg: &mut MySqlBackend, csv: &str, replace: bool) -> (u64, Vec<String>) {
+    let mut imported = HashSet::new();
+    let mut errors = vec![];
+    for (i, record) in parse_csv(csv).into_iter().enumerate() {
+        let row = i + 1;
+        if record.iter().all(|f| f.trim().is_empty()) {
+            continue;
+        }
+        if i == 0 && record[0].trim().eq_ignore_ascii_case("email") {
+            continue;
+        }
+        if record.len() != 3 {
+            errors.push(format!(
+                "row {}: expected 3 fields (email, name, section), got {}",
+                row,
+                record.len()
+            ));
+            continue;
+        }
+        let email = normalize_email(&record[0]);
+        if !valid_email(&email) {
+            errors.push(format!("row {}: \"{}\" is not an email address", row, email));
+            continue;
+        }
+
+        // keep the invitation time of students already on the roster
+        let res = bg.prep_exec(
+            "SELECT invited_at FROM enrollment WHERE LOWER(email) = ?",
+            vec![email.as_str().into()],
+        );
+        let invited_at = res.first().map(|r| r[0].clone()).unwrap_or(Value::NULL);
+        bg.replace(
+            "enrollment",
+            vec![
+                email.as_str().into(),
+                record[1].trim().into(),
+                record[2].trim().into(),
+                invited_at,
+            ],
+        );
+        imported.insert(email);
+    }
+
+    // only prune if the whole upload was valid, so a typo cannot drop students
+    if replace && errors.is_empty() {
+        let rostered = bg.prep_exec("SELECT email FROM enrollment", vec![]);
+        for r in rostered {
+            let email: String = from_value(r[0].clone());
+            if !imported.contains(&normalize_email(&email)) {
+                bg.prep_exec(
+                    "DELETE FROM enrollment WHERE email = ?",
+                    vec![email.into()],
+                );
+            }
+        }
+    }
+
+    (imported.len() as u64, errors)
+}
+
+fn render_roster(
+    bg: &mut MySqlBackend,
+    csrf: CsrfToken,
+    message: Option<String>,
+    errors: Vec<String>,
+) -> Template {
+    let res = bg.prep_exec("SELECT COUNT(*) FROM enrollment", vec![]);
+    let num_rostered = from_value(res[0][0].clone());
+    let res = bg.prep_exec("SELECT COUNT(*) FROM users", vec![]);
+    let num_registered = from_value(res[0][0].clone());
+    let not_registered = bg
+        .prep_exec(
+            "SELECT e.email, e.name, e.section, e.invited_at FROM enrollment e \
+             LEFT JOIN users u ON LOWER(u.email) = LOWER(e.email) \
+             WHERE u.email IS NULL ORDER BY e.section, e.email",
+            vec![],
+        )
+        .into_iter()
+        .map(|r| RosterEntry {
+            email: from_value(r[0].clone()),
+            name: from_value(r[1].clone()),
+            section: from_value(r[2].clone()),
+            invited_at: if r[3] == Value::NULL {
+                None
+            } else {
+                Some(
+                    from_value::<NaiveDateTime>(r[3].clone())
+                        .format("%Y-%m-%d %H:%M:%S")
+                        .to_string(),
+                )
+            },
+        })
+        .collect();
+    let not_rostered = bg
+        .prep_exec(
+            "SELECT u.email FROM users u \
+             LEFT JOIN enrollment e ON LOWER(e.email) = LOWER(u.email) \
+             WHERE e.email IS NULL ORDER BY u.email",
+            vec![],
+        )
+        .into_iter()
+        .map(|r| from_value(r[0].clone()))
+        .collect();
+
+    let ctx = RosterContext {
+        num_rostered,
+        num_registered,
+        not_registered,
+        not_rostered,
+        message,
+        errors,
+        csrf_token: csrf.0,
+        parent: "layout",
+    };
+    Template::render("admin/roster", &ctx)
+}
+
+#[get("/")]
+pub(crate) fn roster(
+    _adm: Admin,
+    csrf: CsrfToken,
+    backend: &State<Arc<Mutex<MySqlBackend>>>,
+) -> Template {
+    let mut bg = backend.lock().unwrap();
+    render_roster(&mut bg, csrf, None, vec![])
+}
+
+#[post("/upload", data = "<data>")]
+pub(crate) fn upload(
+    _adm: Admin,
+    data: CsrfForm<RosterUpload>,
+    csrf: CsrfToken,
+    backend: &State<Arc<Mutex<MySqlBackend>>>,
+) -> Template {
+    let mut bg = backend.lock().unwrap();
+    let (n, errors) = import(&mut bg, &data.roster, data.replace);
+    let mut message = format!("Imported {} students.", n);
+    if data.replace && !errors.is_empty() {
+        message.push_str(" The roster was not pruned because some rows were rejected.");
+    }
+    render_roster(&mut bg, csrf, Some(message), errors)
+}
+
+#[post("/invite", data = "<_data>")]
+pub(crate) fn invite(
+    _adm: Admin,
+    _data: CsrfForm<NoFields>,
+    csrf: CsrfToken,
+    backend: &State<Arc<Mutex<MySqlBackend>>>,
+    config: &State<Config>,
+) -> Template {
+    let mut bg = backend.lock().unwrap();
+    // students who have not registered yet and have not been invited before
+    let res = bg.prep_exec(
+        "SELECT e.email, e.name FROM enrollment e \
+         LEFT JOIN users u ON LOWER(u.email) = e.email \
+         WHERE u.email IS NULL AND e.invited_at IS NULL",
+        vec![],
+    );
+    let log = bg.log.clone();
+    // sending may be slow, so do it without holding the backend
+    drop(bg);
+
+    let mut invited = vec![];
+    let mut failed = 0;
+    for r in res {
+        let to: String = from_value(r[0].clone());
+        let name: String = from_value(r[1].clone());
+        if config.send_emails {
+            let sent = email::send(
+                log.clone(),
+                config,
+                "no-reply@csci2390-submit.cs.brown.edu".into(),
+                vec![to.clone()],
+                format!("Welcome to {}", config.class),
+                format!(
+                    "Hi {},\n\nYou are enrolled in {}. Register for the submission system at:\n\
+                     {}/login\n\nUse this email address ({}) to register.\n",
+                    name, config.class, config.base_url, to
+                ),
+            );
+            if let Err(e) = sent {
+                warn!(log, "failed to send invitation to {}: {}", to, e);
+                failed += 1;
+                continue;
+            }
+        }
+        invited.push(to);
+    }
+
+    // only students who were sent an invitation are marked, so the others get one next time
+    let now = Local::now().naive_local();
+    let mut bg = backend.lock().unwrap();
+    for to in &invited {
+        bg.prep_exec(
+            "UPDATE enrollment SET invited_at = ? WHERE email = ?",
+            vec![now.into(), to.as_str().into()],
+        );
+    }
+
+    let mut message = format!("Sent {} invitations.", invited.len());
+    if failed > 0 {
+        message.push_str(&format!(" {} could not be sent.", failed));
+    }
+    render_roster(&mut bg, csrf, Some(message), vec![])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_plain_rows() {
+        assert_eq!(
+            parse_csv("email,name,section\na@x.edu,Ann,1\nb@x.edu,,2"),
+            vec![
+                vec!["email", "name", "section"],
+                vec!["a@x.edu", "Ann", "1"],
+                vec!["b@x.edu", "", "2"],
+            ]
+        );
+        // a trailing newline does not add a record
+        assert_eq!(
+            parse_csv("a@x.edu,Ann,1\n"),
+            vec![vec!["a@x.edu", "Ann", "1"]]
+        );
+        assert!(parse_csv("").is_empty());
+    }
+
+    #[test]
+    fn parses_quoted_fields() {
+        assert_eq!(
+            parse_csv("a@x.edu,\"Doe, Ann\",\"say \"\"hi\"\"\"\nb@x.edu,\"two\nlines\",3"),
+            vec![
+                vec!["a@x.edu", "Doe, Ann", "say \"hi\""],
+                vec!["b@x.edu", "two\nlines", "3"],
+            ]
+        );
+    }
+
+    #[test]
+    fn parses_crlf_and_bom() {
+        assert_eq!(
+            parse_csv("\u{feff}email,name,section\r\na@x.edu,Ann,1\r\n"),
+            vec![vec!["email", "name", "section"], vec!["a@x.edu", "Ann", "1"]]
+        );
+        // line breaks inside quotes are kept as they are
+        assert_eq!(parse_csv("\"a\r\nb\",c"), vec![vec!["a\r\nb", "c"]]);
+    }
+
+    #[test]
+    fn validates_emails() {
+        assert!(valid_email("ann.doe+cs@mail.x.edu"));
+        for bad in &["ann", "ann@x", "@x.edu", "ann doe@x.edu", "\"ann\"@x.edu", "a@x.edu<"] {
+            assert!(!valid_email(bad), "{}", bad);
+        }
+        assert_eq!(normalize_email(" Ann@X.edu "), "ann@x.edu");
+    }
+}
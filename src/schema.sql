@@ -1,6 +1,17 @@
-CREATE TABLE users (email varchar(255), apikey varchar(255), is_admin tinyint, is_manager tinyint, pseudonym varchar(255), gender varchar(255), age int, ethnicity varchar(255), is_remote tinyint, education varchar(255), PRIMARY KEY (apikey));
-CREATE TABLE lectures (id int, label varchar(255), PRIMARY KEY (id));
-CREATE TABLE questions (lec int, q int, question text, PRIMARY KEY (lec, q));
-CREATE TABLE answers (email varchar(255), lec int, q int, answer text, submitted_at datetime, grade int, PRIMARY KEY (email, lec, q));
+CREATE TABLE users (email varchar(255), apikey varchar(255), is_admin tinyint, is_manager tinyint, pseudonym varchar(255), gender varchar(255), age int, ethnicity varchar(255), is_remote tinyint, education varchar(255), PRIMARY KEY (apikey), UNIQUE KEY (pseudonym));
+CREATE TABLE lectures (id int, label varchar(255), due_at datetime, lock_at datetime, visibility varchar(16), opens_at datetime, grades_released tinyint, PRIMARY KEY (id));
+CREATE TABLE questions (lec int, q int, question text, qtype varchar(16), options text, tolerance double, language varchar(64), answer_key text, key_match varchar(16), points int, position int, attachments tinyint, markdown tinyint, PRIMARY KEY (lec, q));
+CREATE TABLE answers (email varchar(255), lec int, q int, answer mediumtext, submitted_at datetime, grade int, auto_graded tinyint, version int, needs_regrade tinyint, graded_by varchar(255), PRIMARY KEY (email, lec, q));
+CREATE TABLE answer_versions (email varchar(255), lec int, q int, version int, answer mediumtext, submitted_at datetime, PRIMARY KEY (email, lec, q, version));
+CREATE TABLE drafts (email varchar(255), lec int, q int, answer mediumtext, saved_at datetime, PRIMARY KEY (email, lec, q));
+CREATE TABLE attachments (email varchar(255), lec int, q int, filename varchar(255), mime varchar(255), size int, hash char(64), uploaded_at datetime, PRIMARY KEY (email, lec, q));
+CREATE TABLE api_tokens (token_hash varchar(255), email varchar(255), scope varchar(16), label varchar(255), created_at datetime, revoked tinyint, PRIMARY KEY (token_hash));
+CREATE TABLE deletion_receipts (receipt_id varchar(255), email_hash varchar(255), requested_by varchar(255), deleted_at datetime, answers_removed int, answers_anonymized int, PRIMARY KEY (receipt_id));
+CREATE TABLE impersonations (admin varchar(255), student varchar(255), started_at datetime, ended_at datetime, PRIMARY KEY (admin, started_at));
+CREATE TABLE login_tokens (token_hash varchar(255), email varchar(255), expires_at datetime, used tinyint, PRIMARY KEY (token_hash));
+CREATE TABLE pseudonym_terms (term varchar(255), started_at datetime, PRIMARY KEY (term));
+CREATE TABLE pseudonym_history (email varchar(255), pseudonym varchar(255), term varchar(255), retired_at datetime, PRIMARY KEY (pseudonym));
+CREATE TABLE enrollment (email varchar(255), name varchar(255), section varchar(255), invited_at datetime, PRIMARY KEY (email));
+CREATE TABLE extensions (email varchar(255), lec int, due_at datetime, reason text, granted_by varchar(255), granted_at datetime, PRIMARY KEY (email, lec));
 
 CREATE VIEW lec_qcount as SELECT questions.lec, COUNT(questions.q) AS qcount FROM questions GROUP BY questions.lec;
@@ -0,0 +1,227 @@
+use crate::admin::Admin;
+use crate::apikey::ApiKeyError;
+use crate::backend::MySqlBackend;
+use crate::config::Config;
+use crate::csrf::{CsrfForm, CsrfToken};
+use crate::login::hash_token;
+use chrono::naive::NaiveDateTime;
+use chrono::Local;
+use mysql::from_value;
+use rand::distributions::Alphanumeric;
+use rand::{thread_rng, Rng};
+use rocket::http::Method;
+use rocket::request::Request;
+use rocket::response::Redirect;
+use rocket::State;
+use rocket_dyn_templates::Template;
+use std::sync::{Arc, Mutex};
+
+/// What a bearer token may be used for. Browser sessions (API key cookie) are unrestricted.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum TokenScope {
+    /// Any GET request the owner could make
+    ReadOnly,
+    /// Read-only, plus submitting answers
+    Submit,
+    /// Read-only, plus editing grades
+    Grade,
+}
+
+impl TokenScope {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TokenScope::ReadOnly => "read-only",
+            TokenScope::Submit => "submit",
+            TokenScope::Grade => "grade",
+        }
+    }
+
+    pub fn parse(s: &str) -> Option<TokenScope> {
+        match s {
+            "read-only" => Some(TokenScope::ReadOnly),
+            "submit" => Some(TokenScope::Submit),
+            "grade" => Some(TokenScope::Grade),
+            _ => None,
+        }
+    }
+
+    /// Whether a request may be made with a token of this scope.
+    pub fn permits(&self, request: &Request<'_>) -> bool {
+        let path = request.uri().path().as_str();
+        match request.method() {
+            Method::Get | Method::Head => true,
+            Method::Post => match self {
+                TokenScope::ReadOnly => false,
+                TokenScope::Submit => path.starts_with("/questions/"),
+                TokenScope::Grade => path.starts_with("/grades/editg/"),
+            },
+            _ => false,
+        }
+    }
+}
+
+#[derive(Debug, FromForm)]
+pub(crate) struct MintTokenForm {
+    email: String,
+    scope: String,
+    label: String,
+}
+
+#[derive(Debug, FromForm)]
+pub(crate) struct RevokeTokenForm {
+    token_hash: String,
+}
+
+#[derive(Serialize)]
+struct TokenEntry {
+    token_hash: String,
+    email: String,
+    scope: String,
+    label: String,
+    created: String,
+    revoked: bool,
+}
+
+#[derive(Serialize)]
+struct TokensContext {
+    tokens: Vec<TokenEntry>,
+    new_token: Option<String>,
+    error: Option<String>,
+    csrf_token: String,
+    parent: &'static str,
+}
+
+/// Looks up a bearer token, returning its owner and scope.
+pub(crate) fn check_bearer_token(
+    backend: &Arc<Mutex<MySqlBackend>>,
+    config: &Config,
+    token: &str,
+) -> Result<(String, TokenScope), ApiKeyError> {
+    let mut bg = backend.lock().unwrap();
+    let rs = bg.prep_exec(
+        "SELECT email, scope FROM api_tokens WHERE token_hash = ? AND revoked = 0",
+        vec![hash_token(token, &config.secret).into()],
+    );
+    drop(bg);
+    if rs.is_empty() {
+        Err(ApiKeyError::Missing)
+    } else if rs.len() > 1 {
+        Err(ApiKeyError::Ambiguous)
+    } else {
+        let scope = TokenScope::parse(&from_value::<String>(rs[0][1].clone()))
+            .ok_or(ApiKeyError::BackendFailure)?;
+        Ok((from_value::<String>(rs[0][0].clone()), scope))
+    }
+}
+
+fn render_tokens(
+    backend: &Arc<Mutex<MySqlBackend>>,
+    csrf: CsrfToken,
+    new_token: Option<String>,
+    error: Option<String>,
+) -> Template {
+    let mut bg = backend.lock().unwrap();
+    let res = bg.prep_exec(
+        "SELECT token_hash, email, scope, label, created_at, revoked FROM api_tokens",
+        vec![],
+    );
+    drop(bg);
+
+    let tokens: Vec<_> = res
+        .into_iter()
+        .map(|r| TokenEntry {
+            token_hash: from_value(r[0].clone()),
+            email: from_value(r[1].clone()),
+            scope: from_value(r[2].clone()),
+            label: from_value(r[3].clone()),
+            created: from_value::<NaiveDateTime>(r[4].clone())
+                .format("%Y-%m-%d %H:%M:%S")
+                .to_string(),
+            revoked: from_value::<u8>(r[5].clone()) != 0,
+        })
+        .collect();
+
+    let ctx = TokensContext {
+        tokens,
+        new_token,
+        error,
+        csrf_token: csrf.0,
+        parent: "layout",
+    };
+    Template::render("admin/tokens", &ctx)
+}
+
+#[get("/")]
+pub(crate) fn tokens(
+    _adm: Admin,
+    csrf: CsrfToken,
+    backend: &State<Arc<Mutex<MySqlBackend>>>,
+) -> Template {
+    render_tokens(backend, csrf, None, None)
+}
+
+#[post("/", data = "<data>")]
+pub(crate) fn mint(
+    _adm: Admin,
+    data: CsrfForm<MintTokenForm>,
+    csrf: CsrfToken,
+    backend: &State<Arc<Mutex<MySqlBackend>>>,
+    config: &State<Config>,
+) -> Template {
+    let scope = match TokenScope::parse(&data.scope) {
+        Some(s) => s,
+        None => {
+            let error = format!("unknown scope {}", data.scope);
+            return render_tokens(backend, csrf, None, Some(error));
+        }
+    };
+
+    let mut bg = backend.lock().unwrap();
+    let res = bg.prep_exec(
+        "SELECT email FROM users WHERE email = ?",
+        vec![data.email.as_str().into()],
+    );
+    if res.is_empty() {
+        drop(bg);
+        let error = format!("no such user {}", data.email);
+        return render_tokens(backend, csrf, None, Some(error));
+    }
+
+    let token: String = thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(40)
+        .map(char::from)
+        .collect();
+    let token = format!("ws_{}", token);
+    bg.insert(
+        "api_tokens",
+        vec![
+            hash_token(&token, &config.secret).into(),
+            data.email.as_str().into(),
+            scope.as_str().into(),
+            data.label.as_str().into(),
+            Local::now().naive_local().into(),
+            0.into(),
+        ],
+    );
+    drop(bg);
+
+    // the token is only ever shown once; we store just its hash
+    render_tokens(backend, csrf, Some(token), None)
+}
+
+#[post("/revoke", data = "<data>")]
+pub(crate) fn revoke(
+    _adm: Admin,
+    data: CsrfForm<RevokeTokenForm>,
+    backend: &State<Arc<Mutex<MySqlBackend>>>,
+) -> Redirect {
+    let mut bg = backend.lock().unwrap();
+    bg.prep_exec(
+        "UPDATE api_tokens SET revoked = 1 WHERE token_hash = ?",
+        vec![data.token_hash.as_str().into()],
+    );
+    drop(bg);
+
+    Redirect::to("/admin/tokens")
+}
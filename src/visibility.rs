@@ -0,0 +1,91 @@
+use crate::backend::{MySqlBackend, Value};
+use chrono::naive::NaiveDateTime;
+use mysql::from_value;
+
+/// Who can see a lecture and whether it accepts answers. Admins can always preview lectures.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum Visibility {
+    /// Invisible to students
+    Hidden,
+    /// Invisible to students until `opens_at`, then open
+    Scheduled,
+    /// Listed and accepting answers (subject to deadlines)
+    Open,
+    /// Listed; students can read their answers but not change them
+    Closed,
+    /// Like closed, but listed apart from current lectures
+    Archived,
+}
+
+pub(crate) const ALL_VISIBILITIES: &[Visibility] = &[
+    Visibility::Hidden,
+    Visibility::Scheduled,
+    Visibility::Open,
+    Visibility::Closed,
+    Visibility::Archived,
+];
+
+impl Visibility {
+    pub(crate) fn as_str(&self) -> &'static str {
+        match self {
+            Visibility::Hidden => "hidden",
+            Visibility::Scheduled => "scheduled",
+            Visibility::Open => "open",
+            Visibility::Closed => "closed",
+            Visibility::Archived => "archived",
+        }
+    }
+
+    pub(crate) fn parse(s: &str) -> Option<Visibility> {
+        ALL_VISIBILITIES.iter().find(|v| v.as_str() == s).copied()
+    }
+}
+
+/// A lecture's visibility settings.
+#[derive(Debug, Clone)]
+pub(crate) struct LectureState {
+    pub visibility: Visibility,
+    /// When a scheduled lecture opens
+    pub opens_at: Option<NaiveDateTime>,
+}
+
+impl LectureState {
+    /// Reads the `visibility` and `opens_at` columns; lectures without a visibility are open.
+    pub(crate) fn from_values(visibility: &Value, opens_at: &Value) -> LectureState {
+        LectureState {
+            visibility: from_value::<Option<String>>(visibility.clone())
+                .and_then(|v| Visibility::parse(&v))
+                .unwrap_or(Visibility::Open),
+            opens_at: from_value(opens_at.clone()),
+        }
+    }
+
+    /// `None` if there is no such lecture.
+    pub(crate) fn load(bg: &mut MySqlBackend, lec: u64) -> Option<LectureState> {
+        let res = bg.prep_exec(
+            "SELECT visibility, opens_at FROM lectures WHERE id = ?",
+            vec![lec.into()],
+        );
+        res.first().map(|r| LectureState::from_values(&r[0], &r[1]))
+    }
+
+    /// The visibility as of `now`: scheduled lectures are open once their time has come.
+    pub(crate) fn effective(&self, now: NaiveDateTime) -> Visibility {
+        match (self.visibility, self.opens_at) {
+            (Visibility::Scheduled, Some(opens)) if now >= opens => Visibility::Open,
+            (v, _) => v,
+        }
+    }
+
+    /// Whether students can see the lecture's questions.
+    pub(crate) fn is_visible(&self, now: NaiveDateTime) -> bool {
+        match self.effective(now) {
+            Visibility::Hidden | Visibility::Scheduled => false,
+            Visibility::Open | Visibility::Closed | Visibility::Archived => true,
+        }
+    }
+
+    pub(crate) fn accepts_answers(&self, now: NaiveDateTime) -> bool {
+        self.effective(now) == Visibility::Open
+    }
+}